@@ -1,80 +1,191 @@
 #!/bin/bash
 set -euo pipefail
+# TRUSTED_DOMAINS may contain wildcard patterns like *.example.com; never glob-expand them
+set -f
+
+# Wait (up to 5s) for an in-container helper to start listening on a local TCP port
+wait_for_port() {
+  for _ in $(seq 1 50); do
+    if (exec 3<>"/dev/tcp/127.0.0.1/$1") 2>/dev/null; then
+      return 0
+    fi
+    sleep 0.1
+  done
+  echo "[RustyYOLO Firewall] WARNING: helper on port $1 did not start"
+}
+
+# Wait (up to 30s) for an in-container helper to signal readiness by creating a file
+wait_for_file() {
+  for _ in $(seq 1 300); do
+    if [ -e "$1" ]; then
+      return 0
+    fi
+    sleep 0.1
+  done
+  echo "[RustyYOLO Firewall] WARNING: $1 was not created; some trusted domains may be blocked"
+}
 
 # --- 1. CONFIGURE FIREWALL (as root) ---
-echo "[RustyYOLO Firewall] Setting up network restrictions..."
-
-# Configure audit logging
-AUDIT_LOG=${AUDIT_LOG:-none}
-if [ "$AUDIT_LOG" = "basic" ] || [ "$AUDIT_LOG" = "verbose" ]; then
-  echo "[RustyYOLO Firewall] Audit logging enabled: $AUDIT_LOG"
-fi
+NETWORK_MODE=${NETWORK_MODE:-firewall}
+if [ "$NETWORK_MODE" = "offline" ]; then
+  # Started with --network none: there is only a loopback interface and nothing to filter
+  echo "[RustyYOLO Firewall] Offline mode: no network, skipping firewall setup"
+else
+  echo "[RustyYOLO Firewall] Setting up network restrictions..."
 
-iptables -P OUTPUT DROP
-iptables -A OUTPUT -o lo -j ACCEPT
-iptables -A OUTPUT -m state --state RELATED,ESTABLISHED -j ACCEPT
+  # Configure audit logging
+  AUDIT_LOG=${AUDIT_LOG:-none}
+  if [ "$AUDIT_LOG" = "basic" ] || [ "$AUDIT_LOG" = "verbose" ]; then
+    echo "[RustyYOLO Firewall] Audit logging enabled: $AUDIT_LOG"
+  fi
 
-# Configure DNS restrictions (defense against DNS exfiltration)
-DNS_SERVERS=${DNS_SERVERS:-"8.8.8.8 8.8.4.4 1.1.1.1 1.0.0.1"}
-if [ "$DNS_SERVERS" = "any" ]; then
-  echo "[RustyYOLO Firewall] WARNING: DNS to any server allowed (exfiltration risk!)"
-  iptables -A OUTPUT -p udp --dport 53 -j ACCEPT
-  iptables -A OUTPUT -p tcp --dport 53 -j ACCEPT
-else
-  echo "[RustyYOLO Firewall] Restricting DNS to allowed servers: $DNS_SERVERS"
-  for dns_server in $DNS_SERVERS; do
-    # Validate IP format to prevent command injection
-    if ! echo "$dns_server" | grep -qE '^[0-9]+\.[0-9]+\.[0-9]+\.[0-9]+$'; then
-      echo "[RustyYOLO Firewall] ERROR: Invalid DNS server IP: $dns_server (skipping)"
-      continue
+  # IPv6 gets the same policy as IPv4 through ip6tables, unless it was disabled with
+  # --disable-ipv6 / [security] disable_ipv6. Refuse to start rather than leave IPv6 open.
+  IPV6_FIREWALL=0
+  if [ "${DISABLE_IPV6:-0}" = "1" ] || [ "$(cat /proc/sys/net/ipv6/conf/all/disable_ipv6 2>/dev/null || echo 1)" = "1" ]; then
+    echo "[RustyYOLO Firewall] IPv6 is disabled"
+  elif ip6tables -L OUTPUT -n >/dev/null 2>&1; then
+    IPV6_FIREWALL=1
+    echo "[RustyYOLO Firewall] IPv6 is enabled; mirroring rules with ip6tables"
+  else
+    echo "[RustyYOLO Firewall] ERROR: IPv6 is enabled but ip6tables is unavailable."
+    echo "[RustyYOLO Firewall] Set 'disable_ipv6 = true' under [security] or pass --disable-ipv6."
+    exit 1
+  fi
+  export IPV6_FIREWALL
+
+  # Apply the ruleset generated by the Rust wrapper (see `rustyolo firewall show`) in a single
+  # iptables-restore transaction, so the sandbox is never left with a half-built firewall.
+  # --noflush keeps the nat rules Docker installs for its embedded DNS server.
+  RULES_DIR=/etc/rustyolo
+  if [ ! -f "$RULES_DIR/firewall.v4.rules" ]; then
+    echo "[RustyYOLO Firewall] ERROR: $RULES_DIR/firewall.v4.rules is missing; refusing to start."
+    exit 1
+  fi
+  iptables-restore --noflush < "$RULES_DIR/firewall.v4.rules"
+  if [ "$IPV6_FIREWALL" = "1" ]; then
+    if [ ! -f "$RULES_DIR/firewall.v6.rules" ]; then
+      echo "[RustyYOLO Firewall] ERROR: $RULES_DIR/firewall.v6.rules is missing; refusing to start."
+      exit 1
     fi
-    echo "[RustyYOLO Firewall] ALLOWING DNS to: $dns_server"
+    ip6tables-restore --noflush < "$RULES_DIR/firewall.v6.rules"
+  fi
+  echo "[RustyYOLO Firewall] Applied firewall ruleset"
+
+  # With live approval, the host mounts the socket the DNS forwarder and the egress proxy
+  # ask it on. Only they may use it: the agent could otherwise put forged requests in
+  # front of the operator and tie up the host with them.
+  if [ -d /run/rustyolo-approval ]; then
+    chown root:rustyolo-proxy /run/rustyolo-approval
+    chmod 750 /run/rustyolo-approval
+  fi
 
-    # Verbose logging: log allowed DNS queries
-    if [ "$AUDIT_LOG" = "verbose" ]; then
-      iptables -A OUTPUT -p udp -d "$dns_server" --dport 53 -j LOG --log-prefix "[AUDIT-DNS-ALLOW] " --log-level 6
-      iptables -A OUTPUT -p tcp -d "$dns_server" --dport 53 -j LOG --log-prefix "[AUDIT-DNS-ALLOW] " --log-level 6
-    fi
+  # In learn mode, the host mounts the directory the DNS forwarder, the egress proxy and
+  # the learn helper record blocked destinations in. Only they may write there: the agent
+  # could otherwise suggest names of its choosing for allow_domains.
+  if [ -d /run/rustyolo-learn ]; then
+    chown root:rustyolo-proxy /run/rustyolo-learn
+    chmod 750 /run/rustyolo-learn
+  fi
 
-    iptables -A OUTPUT -p udp -d "$dns_server" --dport 53 -j ACCEPT
-    iptables -A OUTPUT -p tcp -d "$dns_server" --dport 53 -j ACCEPT
-  done
-fi
+  # With egress quotas, the host mounts a directory for the byte counters. The monitor
+  # writes them there every second; the host stops the session once a quota is used up
+  # and prints per-domain usage at exit. Keep the directory out of the agent's reach.
+  if [ -d /run/rustyolo/egress ]; then
+    chmod 700 /run/rustyolo
+    rustyolo sandbox egress &
+  fi
 
-# Read from TRUSTED_DOMAINS env var passed by the Rust wrapper
-TRUSTED_DOMAINS=${TRUSTED_DOMAINS:-"github.com api.github.com pypi.org files.pythonhosted.org"}
-echo "[RustyYOLO Firewall] Resolving and allowing trusted domains: $TRUSTED_DOMAINS"
-for domain in $TRUSTED_DOMAINS; do
-  # Validate domain format to prevent command injection
-  # Allow: letters, digits, dots, hyphens, underscores (valid domain characters)
-  if ! echo "$domain" | grep -qE '^[a-zA-Z0-9._-]+$'; then
-    echo "[RustyYOLO Firewall] ERROR: Invalid domain format: $domain (skipping)"
-    continue
+  # Open [network] host_ports on the Docker host's gateway IP only (host.docker.internal
+  # is mapped by --add-host, so the address is only known inside the container)
+  if [ -n "${HOST_PORTS:-}" ]; then
+    rustyolo sandbox host-ports
   fi
-  ips=$(dig +short "$domain" | grep -E '^[0-9]+\.[0-9]+\.[0-9]+\.[0-9]+$' || true)
-  if [ -n "$ips" ]; then
-    for ip in $ips; do
-      echo "[RustyYOLO Firewall] ALLOWING IP: $ip (for $domain)"
 
-      # Verbose logging: log allowed connections to whitelisted domains
-      if [ "$AUDIT_LOG" = "verbose" ]; then
-        iptables -A OUTPUT -d "$ip" -j LOG --log-prefix "[AUDIT-ALLOW-$domain] " --log-level 6
-      fi
+  # Read from TRUSTED_DOMAINS env var passed by the Rust wrapper
+  TRUSTED_DOMAINS=${TRUSTED_DOMAINS:-"github.com api.github.com pypi.org files.pythonhosted.org"}
+  REFRESH_INTERVAL=${REFRESH_INTERVAL:-300}
+  export TRUSTED_DOMAINS AUDIT_LOG REFRESH_INTERVAL
+
+  if [ "$NETWORK_MODE" = "proxy" ]; then
+    # Only the egress proxy (running as its own user) may connect out. It checks the
+    # TLS SNI / HTTP Host of every connection against TRUSTED_DOMAINS.
+    PROXY_PORT=3128
+    echo "[RustyYOLO Firewall] Egress proxy mode: allowing trusted domains by name: $TRUSTED_DOMAINS"
+    # Behind a corporate proxy (UPSTREAM_PROXY), allowed connections are tunneled through it
+    if [ -n "${UPSTREAM_PROXY:-}" ]; then
+      echo "[RustyYOLO Firewall] Tunneling allowed connections through the upstream proxy: $UPSTREAM_PROXY"
+    fi
+    # With HTTP policies, the proxy intercepts HTTPS to the hosts they name so it can check
+    # methods and paths. It generates a CA for this session, which is installed below.
+    PROXY_ARGS=""
+    if [ -n "${HTTP_POLICIES:-}" ]; then
+      echo "[RustyYOLO Firewall] Checking HTTP methods and paths for hosts with HTTP policies"
+      install -d -o rustyolo-proxy -m 755 /run/rustyolo-proxy
+      PROXY_ARGS="--intercept-ca /run/rustyolo-proxy/ca.crt"
+    fi
+    # shellcheck disable=SC2086 # PROXY_ARGS is intentionally split
+    gosu rustyolo-proxy rustyolo sandbox proxy --listen "127.0.0.1:$PROXY_PORT" $PROXY_ARGS &
+    wait_for_port "$PROXY_PORT"
+    if [ -n "${HTTP_POLICIES:-}" ]; then
+      cp /run/rustyolo-proxy/ca.crt /usr/local/share/ca-certificates/rustyolo-proxy.crt
+    fi
+    # Only the proxy needs the upstream proxy credentials; keep them away from everything else
+    unset UPSTREAM_PROXY_AUTH
 
-      iptables -A OUTPUT -d "$ip" -j ACCEPT
-    done
+    # Connections are filtered by name, so the DNS forwarder must not open IPs
+    DNS_FORWARDER_ARGS="--no-firewall"
   else
-    echo "[RustyYOLO Firewall] WARNING: Could not resolve $domain"
+    echo "[RustyYOLO Firewall] Resolving and allowing trusted domains: $TRUSTED_DOMAINS"
+    for domain in $TRUSTED_DOMAINS; do
+      # Patterns can't be resolved up front; the DNS forwarder allows them on demand
+      case "$domain" in
+        \*.* | .*)
+          echo "[RustyYOLO Firewall] ALLOWING pattern: $domain (resolved on demand)"
+          ;;
+      esac
+    done
+
+    # Exact names are resolved by the refresher, which owns the RUSTYOLO-TRUSTED chain.
+    # It re-resolves them every REFRESH_INTERVAL seconds so CDN-backed domains keep working
+    # in long sessions, and expires IPs that stop resolving. Addresses the host resolved
+    # and checked (PINNED_ADDRESSES) are opened instead of the first lookup, and checked
+    # again against DNS_SERVERS at every refresh; the refresher writes the new pins to
+    # PINS_FILE, where the DNS forwarder picks them up.
+    if [ -n "${PINNED_ADDRESSES:-}" ]; then
+      echo "[RustyYOLO Firewall] Opening addresses pinned on the host"
+    fi
+    REFRESH_READY=/run/rustyolo-refresh.ready
+    PINS_FILE=/run/rustyolo-pins
+    rm -f "$REFRESH_READY" "$PINS_FILE"
+    rustyolo sandbox refresh --ready-file "$REFRESH_READY" --pins-file "$PINS_FILE" &
+    wait_for_file "$REFRESH_READY"
+
+    # The DNS forwarder adds rules to the RUSTYOLO-DNS chain for trusted names as their
+    # answers come back; this is how wildcard/suffix patterns get allowed
+    DNS_FORWARDER_ARGS="--pins-file $PINS_FILE"
   fi
-done
 
-# Log blocked connections (basic and verbose modes)
-if [ "$AUDIT_LOG" = "basic" ] || [ "$AUDIT_LOG" = "verbose" ]; then
-  echo "[RustyYOLO Firewall] Adding audit logging for blocked connections"
-  iptables -A OUTPUT -j LOG --log-prefix "[AUDIT-BLOCK] " --log-level 4
-fi
+  # Route all name resolution through the sandbox forwarder. It only forwards queries for
+  # trusted names and answers NXDOMAIN for everything else, so lookups cannot be used to
+  # smuggle data out. It queries the configured dns_servers directly rather than Docker's
+  # embedded server, which /etc/resolv.conf names on a user-defined network.
+  UPSTREAM_DNS=${DNS_SERVERS:-$(awk '/^nameserver/ {print $2}' /etc/resolv.conf | tr '\n' ' ')}
+  echo "[RustyYOLO Firewall] Starting DNS forwarder (upstream: $UPSTREAM_DNS)"
+  # shellcheck disable=SC2086 # DNS_FORWARDER_ARGS is intentionally split
+  rustyolo sandbox dns --upstream "$UPSTREAM_DNS" $DNS_FORWARDER_ARGS &
+  wait_for_port 53
+  echo "nameserver 127.0.0.1" > /etc/resolv.conf
+
+  # Learn mode (`rustyolo learn`): the DNS forwarder and proxy record the names they refuse,
+  # and this helper names the addresses of dropped packets. The host reads the records from
+  # the mounted directory when the session ends.
+  if [ -n "${LEARN_DIR:-}" ]; then
+    rustyolo sandbox learn --upstream "$UPSTREAM_DNS" &
+  fi
 
-echo "[RustyYOLO Firewall] Setup complete. All other outbound traffic is blocked."
+  echo "[RustyYOLO Firewall] Setup complete. All other outbound traffic is blocked."
+fi
 
 
 # --- 2. FIX PERMISSIONS (as root) ---
@@ -109,6 +220,23 @@ if [ -n "$PERSISTENT_DIRS" ]; then
   done
 fi
 
+# Install custom CA certificates ([security] ca_certificates), e.g. the root of a
+# TLS-inspecting corporate gateway, and the egress proxy's CA for HTTP policies, before
+# the agent makes any connections
+CA_DIR=/usr/local/share/ca-certificates/rustyolo
+PROXY_CA=/usr/local/share/ca-certificates/rustyolo-proxy.crt
+if [ -d "$CA_DIR" ] || [ -f "$PROXY_CA" ]; then
+  echo "[RustyYOLO Permissions] Installing custom CA certificates"
+  update-ca-certificates >/dev/null
+fi
+if [ -f "$PROXY_CA" ]; then
+  # Tools with their own CA bundle must trust the proxy's CA too; the system bundle
+  # includes it along with any custom certificates
+  export NODE_EXTRA_CA_CERTS=/etc/ssl/certs/ca-certificates.crt
+  export REQUESTS_CA_BUNDLE=/etc/ssl/certs/ca-certificates.crt
+  export SSL_CERT_FILE=/etc/ssl/certs/ca-certificates.crt
+fi
+
 # --- 3. RUN COMMAND (as non-root) ---
 echo "[RustyYOLO Entrypoint] Dropping privileges and running command as 'agent' user: $@"
 # Use gosu to drop privileges and execute the command
@@ -0,0 +1,709 @@
+//! Live approval of blocked destinations (`--live-approval`).
+//!
+//! Without it, a session that needs a domain outside the allowlist has to be restarted with
+//! a new `--allow-domains`. With live approval, the DNS forwarder and the egress proxy ask
+//! the host before refusing a name. The question goes over a unix socket mounted into the
+//! container, to the `rustyolo` process that started it. The operator answers from a second
+//! terminal with `rustyolo approve`:
+//!
+//! * **once** - the name is allowed for [`ONCE_WINDOW`], long enough for the lookup and the
+//!   connection that follows it
+//! * **session** - the name is allowed until the container exits
+//! * **always** - as for the session, and the name is added to `allow_domains` in
+//!   `.rustyolo.toml`
+//! * **deny** - the name stays blocked, and is not asked about again for [`ONCE_WINDOW`]
+//!
+//! The host keeps every decision; the sandbox helpers ask again for each request, and
+//! approved names are opened in the container's firewall like trusted ones. A "once"
+//! reply says how much of the window is left, and the DNS forwarder removes the rules it
+//! opened for it when that time is up.
+
+use crate::domains::{self, AllowEntry, DomainPattern};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the approval socket directory is mounted inside the container; the entrypoint
+/// keeps its parent out of the agent's reach
+pub const APPROVAL_DIR: &str = "/run/rustyolo-approval/socket";
+
+/// File name of the socket the sandbox helpers ask on
+pub const APPROVAL_SOCKET: &str = "approval.sock";
+
+/// File name of the host-only socket `rustyolo approve` connects to
+const OPERATOR_SOCKET: &str = "operator.sock";
+
+/// Prefix of the per-session temporary directories
+const SESSION_PREFIX: &str = "rustyolo-approval-";
+
+/// How long a request waits for the operator before it is refused. A DNS client retries
+/// within this time, so an answer given in time lets the original request through.
+const DECISION_WAIT: Duration = Duration::from_secs(10);
+
+/// How long a "once" approval, or a denial, applies to further requests for the same name
+pub const ONCE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Most requests from the sandbox served at a time; further connections are closed
+const MAX_CONNECTIONS: usize = 32;
+
+/// Most questions waiting for the operator; requests about further names are refused
+const MAX_PENDING: usize = 16;
+
+/// The operator's answer to a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Once,
+    Session,
+    Always,
+    Deny,
+}
+
+impl Decision {
+    /// Parse an answer typed at the prompt (`o`, `s`, `a`, `d` or the full word).
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "o" | "once" => Some(Self::Once),
+            "s" | "session" => Some(Self::Session),
+            "a" | "always" => Some(Self::Always),
+            "d" | "deny" | "n" | "no" => Some(Self::Deny),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Once => "once",
+            Self::Session => "session",
+            Self::Always => "always",
+            Self::Deny => "deny",
+        }
+    }
+}
+
+/// What the operator approved for a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Approval {
+    /// The allow entries to apply (empty if the request stays blocked)
+    pub entries: Vec<AllowEntry>,
+
+    /// How long the entries apply for a "once" approval; `None` for the rest of the session
+    pub lifetime: Option<Duration>,
+}
+
+/// Asks the host about blocked names, from inside the sandbox
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket: PathBuf,
+}
+
+impl Client {
+    pub fn new(socket: &Path) -> Self {
+        Self { socket: socket.to_path_buf() }
+    }
+
+    /// Ask the operator whether `name` may be reached. `source` says which helper asks
+    /// (`dns`, `proxy`).
+    ///
+    /// # Returns
+    ///
+    /// The allow entries to apply to this request, which are empty if it stays blocked
+    /// (including when the host cannot be reached).
+    pub fn ask(&self, source: &str, name: &str) -> Approval {
+        let ask = || -> io::Result<String> {
+            let mut stream = UnixStream::connect(&self.socket)?;
+            stream.set_read_timeout(Some(DECISION_WAIT + Duration::from_secs(2)))?;
+            stream.write_all(format!("ask {source} {name}\n").as_bytes())?;
+            let mut reply = String::new();
+            BufReader::new(stream).read_line(&mut reply)?;
+            Ok(reply)
+        };
+        ask().map(|reply| parse_reply(&reply)).unwrap_or_default()
+    }
+}
+
+/// Parse the host's reply: `allow <entries>`, `once <seconds> <entries>` or `deny`.
+fn parse_reply(reply: &str) -> Approval {
+    let (entries, lifetime) = if let Some(entries) = reply.strip_prefix("allow ") {
+        (entries, None)
+    } else if let Some((seconds, entries)) =
+        reply.strip_prefix("once ").and_then(|rest| rest.split_once(' '))
+    {
+        let Ok(seconds) = seconds.parse() else {
+            return Approval::default();
+        };
+        (entries, Some(Duration::from_secs(seconds)))
+    } else {
+        return Approval::default();
+    };
+    match domains::parse_list(entries) {
+        Ok(entries) => Approval { entries, lifetime },
+        Err(_) => Approval::default(),
+    }
+}
+
+/// A request waiting for the operator
+#[derive(Debug, Clone)]
+struct Question {
+    id: u64,
+    source: String,
+    name: String,
+
+    /// Requests still waiting for the answer
+    waiters: usize,
+}
+
+/// A decision that still applies to new requests
+#[derive(Debug, Clone, Copy)]
+enum Grant {
+    /// Allowed or denied until the given time
+    Until(Instant, bool),
+
+    /// Allowed for the rest of the session
+    Session,
+}
+
+/// Decisions and pending requests, shared by the server threads
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    pending: Vec<Question>,
+
+    /// The latest questions no request waits for any more, which the operator can still
+    /// answer for the next attempt
+    expired: VecDeque<Question>,
+    grants: HashMap<String, Grant>,
+
+    /// The connected `rustyolo approve` client
+    operator: Option<UnixStream>,
+}
+
+impl State {
+    /// Whether an earlier decision covers `name`: `Some(true)` to allow, `Some(false)` to
+    /// refuse, `None` to ask the operator.
+    fn lookup(&self, name: &str, now: Instant) -> Option<bool> {
+        match self.grants.get(name)? {
+            Grant::Session => Some(true),
+            Grant::Until(until, allowed) => (now < *until).then_some(*allowed),
+        }
+    }
+
+    /// How much longer a "once" approval of `name` applies; `None` if it is approved for
+    /// the session (or not at all).
+    fn remaining(&self, name: &str, now: Instant) -> Option<Duration> {
+        match self.grants.get(name)? {
+            Grant::Until(until, true) => Some(until.saturating_duration_since(now)),
+            _ => None,
+        }
+    }
+
+    /// Queue a question for `name`, unless one is already waiting, and count the request
+    /// as one of its waiters.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the question about `name` waiting for the operator; `None` if there are
+    /// already [`MAX_PENDING`] questions
+    fn enqueue(&mut self, source: &str, name: &str) -> Option<u64> {
+        if let Some(question) = self.pending.iter_mut().find(|q| q.name == name) {
+            question.waiters += 1;
+            return Some(question.id);
+        }
+        if self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        self.next_id += 1;
+        let question = Question {
+            id: self.next_id,
+            source: source.to_string(),
+            name: name.into(),
+            waiters: 1,
+        };
+        self.send_question(&question);
+        self.pending.push(question);
+        Some(self.next_id)
+    }
+
+    /// A request waiting for question `id` gave up. Once no request waits for it, the
+    /// question leaves the queue, so that names the operator never answers do not fill
+    /// it, and the operator is told.
+    fn give_up(&mut self, id: u64) {
+        let Some(index) = self.pending.iter().position(|q| q.id == id) else {
+            return;
+        };
+        self.pending[index].waiters -= 1;
+        if self.pending[index].waiters == 0 {
+            let question = self.pending.remove(index);
+            self.send(&format!(
+                "info Request {} for {} timed out and was refused; an answer still applies \
+                 to the next attempt\n",
+                question.id, question.name
+            ));
+            self.expired.push_back(question);
+            if self.expired.len() > MAX_PENDING {
+                self.expired.pop_front();
+            }
+        }
+    }
+
+    /// Apply the operator's answer to question `id`.
+    ///
+    /// # Returns
+    ///
+    /// The name the question was about, if it was still pending or recently expired
+    fn answer(&mut self, id: u64, decision: Decision, now: Instant) -> Option<String> {
+        let question = self.pending.iter().chain(&self.expired).find(|q| q.id == id)?;
+        let name = question.name.clone();
+        // The decision settles every question about the name
+        self.pending.retain(|q| q.name != name);
+        self.expired.retain(|q| q.name != name);
+        let grant = match decision {
+            Decision::Once => Grant::Until(now + ONCE_WINDOW, true),
+            Decision::Session | Decision::Always => Grant::Session,
+            Decision::Deny => Grant::Until(now + ONCE_WINDOW, false),
+        };
+        self.grants.insert(name.clone(), grant);
+        Some(name)
+    }
+
+    fn send_question(&mut self, question: &Question) {
+        let line = format!("ask {} {} {}\n", question.id, question.source, question.name);
+        self.send(&line);
+    }
+
+    fn send(&mut self, line: &str) {
+        if let Some(operator) = &mut self.operator {
+            if operator.write_all(line.as_bytes()).is_err() {
+                self.operator = None;
+            }
+        }
+    }
+}
+
+/// Host-side end of the control channel for one session
+pub struct Server {
+    /// Private temporary directory holding both sockets
+    dir: PathBuf,
+    shared: Arc<(Mutex<State>, Condvar)>,
+    sandbox: Option<UnixListener>,
+    operator: Option<UnixListener>,
+    strict_ports: bool,
+    config_path: PathBuf,
+}
+
+impl Server {
+    /// Create the session directory and bind both sockets, before the container starts.
+    ///
+    /// The sandbox socket is world-writable so that both the container's root user and the
+    /// egress proxy user can connect; it sits inside a private directory so other users on
+    /// the host cannot reach it, and inside the container the entrypoint restricts the
+    /// directory it is mounted in to root and the egress proxy user.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict_ports` - Limit approved names to ports 80 and 443
+    /// * `config_path` - Configuration file that "always" approvals are written to
+    pub fn create(strict_ports: bool, config_path: &Path) -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("{SESSION_PREFIX}{}", std::process::id()));
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        let sandbox_dir = dir.join("sandbox");
+        fs::create_dir(&sandbox_dir)?;
+        fs::set_permissions(&sandbox_dir, fs::Permissions::from_mode(0o777))?;
+
+        let sandbox = UnixListener::bind(sandbox_dir.join(APPROVAL_SOCKET))?;
+        fs::set_permissions(sandbox_dir.join(APPROVAL_SOCKET), fs::Permissions::from_mode(0o777))?;
+        let operator = UnixListener::bind(dir.join(OPERATOR_SOCKET))?;
+
+        Ok(Self {
+            dir,
+            shared: Arc::new((Mutex::new(State::default()), Condvar::new())),
+            sandbox: Some(sandbox),
+            operator: Some(operator),
+            strict_ports,
+            config_path: config_path.to_path_buf(),
+        })
+    }
+
+    /// The directory to mount at [`APPROVAL_DIR`]
+    pub fn sandbox_dir(&self) -> PathBuf {
+        self.dir.join("sandbox")
+    }
+
+    /// Start answering requests from the container and the operator in the background.
+    pub fn serve(&mut self) {
+        if let Some(listener) = self.sandbox.take() {
+            let shared = Arc::clone(&self.shared);
+            let strict_ports = self.strict_ports;
+            thread::spawn(move || {
+                let active = Arc::new(AtomicUsize::new(0));
+                for stream in listener.incoming().flatten() {
+                    // Closing the connection refuses the request
+                    if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    let shared = Arc::clone(&shared);
+                    let active = Arc::clone(&active);
+                    thread::spawn(move || {
+                        let _ = handle_request(&stream, &shared, strict_ports);
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            });
+        }
+        if let Some(listener) = self.operator.take() {
+            let shared = Arc::clone(&self.shared);
+            let config_path = self.config_path.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = handle_operator(&stream, &shared, &config_path);
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Answer one `ask <source> <name>` request from the sandbox.
+fn handle_request(
+    stream: &UnixStream,
+    shared: &(Mutex<State>, Condvar),
+    strict_ports: bool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(DECISION_WAIT))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let (Some("ask"), Some(source), Some(name), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        return Ok(());
+    };
+    let name = name.trim_end_matches('.').to_lowercase();
+
+    // Only exact names can be approved
+    let allowed = DomainPattern::parse(&name).is_ok_and(|p| p.is_exact())
+        && wait_for_decision(shared, source, &name);
+
+    let reply = if allowed {
+        let mut entries = vec![AllowEntry::parse(&name).map_err(io::Error::other)?];
+        if strict_ports {
+            entries = domains::restrict_to_web_ports(entries);
+        }
+        let entries: Vec<String> = entries.iter().map(ToString::to_string).collect();
+        match lock(&shared.0).remaining(&name, Instant::now()) {
+            Some(lifetime) => format!("once {} {}\n", lifetime.as_secs().max(1), entries.join(" ")),
+            None => format!("allow {}\n", entries.join(" ")),
+        }
+    } else {
+        "deny\n".to_string()
+    };
+    (&*stream).write_all(reply.as_bytes())
+}
+
+/// Wait up to [`DECISION_WAIT`] for a decision about `name`, asking the operator if no
+/// earlier decision covers it.
+fn wait_for_decision(shared: &(Mutex<State>, Condvar), source: &str, name: &str) -> bool {
+    let (state, decided) = shared;
+    let deadline = Instant::now() + DECISION_WAIT;
+    let mut state = lock(state);
+    let question = if state.lookup(name, Instant::now()).is_none() {
+        let Some(id) = state.enqueue(source, name) else {
+            return false;
+        };
+        Some(id)
+    } else {
+        None
+    };
+    loop {
+        let now = Instant::now();
+        if let Some(allowed) = state.lookup(name, now) {
+            return allowed;
+        }
+        if now >= deadline {
+            if let Some(id) = question {
+                state.give_up(id);
+            }
+            return false;
+        }
+        state = decided
+            .wait_timeout(state, deadline - now)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+}
+
+/// Serve a connected `rustyolo approve` client until it disconnects: send it the pending
+/// questions and apply its `<id> <decision>` answers.
+fn handle_operator(
+    stream: &UnixStream,
+    shared: &(Mutex<State>, Condvar),
+    config_path: &Path,
+) -> io::Result<()> {
+    let (state, decided) = shared;
+    {
+        let mut state = lock(state);
+        state.operator = Some(stream.try_clone()?);
+        for question in state.pending.clone() {
+            state.send_question(&question);
+        }
+    }
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let (Some(id), Some(decision)) = (
+            words.next().and_then(|id| id.parse::<u64>().ok()),
+            words.next().and_then(Decision::parse),
+        ) else {
+            continue;
+        };
+
+        let mut state = lock(state);
+        let Some(name) = state.answer(id, decision, Instant::now()) else {
+            continue;
+        };
+        decided.notify_all();
+        if decision == Decision::Always {
+            let message =
+                match crate::learn::add_to_config(config_path, std::slice::from_ref(&name)) {
+                    Ok(()) => format!("Added {name} to allow_domains in {}", config_path.display()),
+                    Err(e) => format!("Failed to save {name}: {e}"),
+                };
+            state.send(&format!("info {message}\n"));
+        }
+    }
+    lock(state).operator = None;
+    Ok(())
+}
+
+/// The operator sockets of running sessions, keyed by the `rustyolo` process ID
+fn running_sessions() -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return Vec::new();
+    };
+    let mut sessions: Vec<(String, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let id = name.strip_prefix(SESSION_PREFIX)?.to_string();
+            let socket = entry.path().join(OPERATOR_SOCKET);
+            socket.exists().then_some((id, socket))
+        })
+        .collect();
+    sessions.sort();
+    sessions
+}
+
+/// Connect to a running session and answer its requests at the terminal
+/// (`rustyolo approve`).
+///
+/// # Arguments
+///
+/// * `session` - Process ID of the `rustyolo` session; may be omitted if only one runs
+pub fn run_operator(session: Option<&str>) -> Result<(), String> {
+    let sessions = running_sessions();
+    let socket = match (session, sessions.as_slice()) {
+        (Some(id), _) => sessions
+            .iter()
+            .find(|(session_id, _)| session_id == id)
+            .map(|(_, socket)| socket.clone())
+            .ok_or_else(|| format!("No session {id} with live approval is running"))?,
+        (None, []) => return Err("No session with live approval is running".to_string()),
+        (None, [(_, socket)]) => socket.clone(),
+        (None, _) => {
+            let ids: Vec<&str> = sessions.iter().map(|(id, _)| id.as_str()).collect();
+            return Err(format!(
+                "Several sessions are running ({}); pass one: rustyolo approve <session>",
+                ids.join(", ")
+            ));
+        }
+    };
+
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err("rustyolo approve needs a terminal to prompt on".to_string());
+    }
+    let stream = UnixStream::connect(&socket)
+        .map_err(|e| format!("Failed to connect to {}: {e}", socket.display()))?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    println!("[RustyYOLO] Waiting for requests from the sandbox (Ctrl-C to stop)...");
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if let Some(message) = line.strip_prefix("info ") {
+            println!("[RustyYOLO] {message}");
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let (Some("ask"), Some(id), Some(source), Some(name)) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            continue;
+        };
+
+        let decision = loop {
+            print!(
+                "[RustyYOLO] Agent tried to reach {name} ({source}) - \
+                 allow [o]nce / for [s]ession / [a]lways / [d]eny? "
+            );
+            let _ = io::stdout().flush();
+            let mut input = String::new();
+            if stdin.lock().read_line(&mut input).unwrap_or(0) == 0 {
+                return Ok(());
+            }
+            if let Some(decision) = Decision::parse(&input) {
+                break decision;
+            }
+        };
+        writer
+            .write_all(format!("{id} {}\n", decision.as_str()).as_bytes())
+            .map_err(|e| format!("The session has ended: {e}"))?;
+    }
+    println!("[RustyYOLO] The session has ended.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decision_parse() {
+        assert_eq!(Decision::parse("o\n"), Some(Decision::Once));
+        assert_eq!(Decision::parse("Session"), Some(Decision::Session));
+        assert_eq!(Decision::parse(" a "), Some(Decision::Always));
+        assert_eq!(Decision::parse("d"), Some(Decision::Deny));
+        assert_eq!(Decision::parse("maybe"), None);
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let approval = parse_reply("allow example.com:80 example.com:443\n");
+        assert_eq!(approval.entries.len(), 2);
+        assert!(approval.entries[1].allows("example.com", 443, crate::domains::Protocol::Tcp));
+        assert_eq!(approval.lifetime, None);
+        let approval = parse_reply("once 30 example.com:443\n");
+        assert_eq!(approval.entries.len(), 1);
+        assert_eq!(approval.lifetime, Some(Duration::from_secs(30)));
+        assert_eq!(parse_reply("deny\n"), Approval::default());
+        assert_eq!(parse_reply("allow not a valid:entry:x\n"), Approval::default());
+        assert_eq!(parse_reply("once soon example.com\n"), Approval::default());
+    }
+
+    #[test]
+    fn test_server_round_trip() {
+        let mut server = Server::create(true, Path::new("unused.toml")).unwrap();
+        server.serve();
+        let operator = UnixStream::connect(server.dir.join(OPERATOR_SOCKET)).unwrap();
+
+        let client = Client::new(&server.sandbox_dir().join(APPROVAL_SOCKET));
+        let asking = thread::spawn(move || client.ask("dns", "Example.com."));
+
+        let mut question = String::new();
+        BufReader::new(&operator).read_line(&mut question).unwrap();
+        assert_eq!(question, "ask 1 dns example.com\n");
+        (&operator).write_all(b"1 session\n").unwrap();
+
+        let approval = asking.join().unwrap();
+        let entries: Vec<String> = approval.entries.iter().map(ToString::to_string).collect();
+        assert_eq!(entries, ["example.com:80/tcp", "example.com:443/tcp"]);
+        assert_eq!(approval.lifetime, None);
+
+        // A "once" approval tells the sandbox when it runs out
+        let client = Client::new(&server.sandbox_dir().join(APPROVAL_SOCKET));
+        let asking = thread::spawn(move || client.ask("dns", "other.com"));
+        question.clear();
+        BufReader::new(&operator).read_line(&mut question).unwrap();
+        assert_eq!(question, "ask 2 dns other.com\n");
+        (&operator).write_all(b"2 once\n").unwrap();
+        let lifetime = asking.join().unwrap().lifetime.unwrap();
+        assert!(lifetime > Duration::ZERO && lifetime <= ONCE_WINDOW);
+    }
+
+    #[test]
+    fn test_state_decisions() {
+        let now = Instant::now();
+        let mut state = State::default();
+        assert_eq!(state.lookup("example.com", now), None);
+
+        state.enqueue("dns", "example.com");
+        state.enqueue("proxy", "example.com");
+        state.enqueue("dns", "other.com");
+        assert_eq!(state.pending.len(), 2);
+
+        assert_eq!(state.answer(1, Decision::Once, now).as_deref(), Some("example.com"));
+        assert_eq!(state.answer(1, Decision::Once, now), None);
+        assert_eq!(state.lookup("example.com", now), Some(true));
+        assert_eq!(state.lookup("example.com", now + ONCE_WINDOW), None);
+
+        state.answer(2, Decision::Deny, now);
+        assert_eq!(state.lookup("other.com", now), Some(false));
+
+        state.enqueue("dns", "example.com");
+        state.answer(3, Decision::Session, now);
+        assert_eq!(state.lookup("example.com", now + ONCE_WINDOW * 10), Some(true));
+
+        // A flood of names does not queue up without bound
+        for i in 0..MAX_PENDING {
+            assert!(state.enqueue("proxy", &format!("host{i}.example")).is_some());
+        }
+        assert!(state.enqueue("proxy", "host0.example").is_some());
+        assert!(state.enqueue("proxy", "one-too-many.example").is_none());
+        assert_eq!(state.pending.len(), MAX_PENDING);
+    }
+
+    #[test]
+    fn test_timed_out_question_frees_its_slot() {
+        let mut state = State::default();
+        let ids: Vec<u64> = (0..MAX_PENDING)
+            .map(|i| state.enqueue("dns", &format!("random{i}.example")).unwrap())
+            .collect();
+        assert!(state.enqueue("dns", "wanted.example").is_none());
+
+        // A question stays while another request still waits for it
+        assert_eq!(state.enqueue("proxy", "random0.example"), Some(ids[0]));
+        state.give_up(ids[0]);
+        assert_eq!(state.pending.len(), MAX_PENDING);
+        state.give_up(ids[0]);
+        assert_eq!(state.pending.len(), MAX_PENDING - 1);
+
+        for id in &ids[1..] {
+            state.give_up(*id);
+        }
+        assert!(state.pending.is_empty());
+        let wanted = state.enqueue("dns", "wanted.example").unwrap();
+
+        // A late answer still applies, and settles a newer question about the name
+        let retry = state.enqueue("dns", "random1.example").unwrap();
+        let now = Instant::now();
+        assert_eq!(state.answer(ids[1], Decision::Once, now).as_deref(), Some("random1.example"));
+        assert_eq!(state.lookup("random1.example", now), Some(true));
+        assert_eq!(state.answer(retry, Decision::Deny, now), None);
+        assert_eq!(state.pending.iter().map(|q| q.id).collect::<Vec<_>>(), [wanted]);
+
+        // Only the latest expired questions are kept
+        assert_eq!(state.expired.len(), MAX_PENDING - 1);
+        for i in 0..=MAX_PENDING {
+            let id = state.enqueue("dns", &format!("flood{i}.example")).unwrap();
+            state.give_up(id);
+        }
+        assert_eq!(state.expired.len(), MAX_PENDING);
+        assert_eq!(state.answer(ids[0], Decision::Session, now), None);
+    }
+}
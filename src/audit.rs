@@ -0,0 +1,37 @@
+/// Audit logging level for security events.
+///
+/// Shared by the host-side wrapper and the helpers that run inside the sandbox
+/// so that every component agrees on what "basic" and "verbose" mean.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditLevel {
+    /// No audit logging
+    None,
+    /// Log blocked events only
+    Basic,
+    /// Log allowed and blocked events
+    Verbose,
+}
+
+impl AuditLevel {
+    /// Parse an audit level from its configuration string (case-insensitive).
+    ///
+    /// Returns `None` if the value is not one of "none", "basic" or "verbose".
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "none" => Some(Self::None),
+            "basic" => Some(Self::Basic),
+            "verbose" => Some(Self::Verbose),
+            _ => None,
+        }
+    }
+
+    /// Whether blocked events should be logged
+    pub fn logs_blocked(self) -> bool {
+        self != Self::None
+    }
+
+    /// Whether allowed events should be logged
+    pub fn logs_allowed(self) -> bool {
+        self == Self::Verbose
+    }
+}
@@ -0,0 +1,133 @@
+//! Custom CA certificates for TLS-intercepting networks (`[security] ca_certificates`).
+//!
+//! Behind a TLS-inspecting gateway, every HTTPS connection from the sandbox is signed by
+//! the gateway's own root certificate, which the image does not trust. The configured
+//! certificates are copied into a temporary directory that is mounted read-only where
+//! `update-ca-certificates` looks for local certificates; the entrypoint installs them
+//! into the system trust store before dropping privileges. Copying them (rather than
+//! mounting the originals) gives them world-readable permissions, which the container's
+//! root user needs without `CAP_DAC_OVERRIDE`, and keeps the host paths out of the
+//! container.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Where the certificate directory is mounted inside the container
+pub const CA_DIR: &str = "/usr/local/share/ca-certificates/rustyolo";
+
+/// The system trust store, which includes the custom certificates once they are installed
+pub const SYSTEM_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// File in [`CA_DIR`] holding only the custom certificates (for `NODE_EXTRA_CA_CERTS`,
+/// which adds to Node's built-in roots)
+pub const EXTRA_BUNDLE: &str = "extra.pem";
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Number of PEM certificate blocks in `content`
+fn count_certificates(content: &str) -> usize {
+    content.matches(PEM_BEGIN).count()
+}
+
+/// Host-side copy of the custom certificates, removed when dropped
+#[derive(Debug)]
+pub struct CaBundle {
+    /// Temporary directory to mount at [`CA_DIR`]
+    dir: PathBuf,
+}
+
+impl CaBundle {
+    /// Validate the certificate files and copy them into a temporary directory.
+    ///
+    /// Each file must contain at least one PEM certificate; DER files are rejected.
+    ///
+    /// # Returns
+    ///
+    /// The bundle and the number of certificates it holds
+    pub fn create(paths: &[PathBuf]) -> Result<(Self, usize), String> {
+        let mut certificates = Vec::new();
+        for path in paths {
+            let content = fs::read(path)
+                .map_err(|e| format!("Failed to read CA certificate {}: {e}", path.display()))?;
+            let content = String::from_utf8_lossy(&content).into_owned();
+            if count_certificates(&content) == 0 {
+                return Err(format!(
+                    "{} is not a PEM certificate (expected a '{PEM_BEGIN}' block)",
+                    path.display()
+                ));
+            }
+            certificates.push(content);
+        }
+
+        let dir = std::env::temp_dir().join(format!("rustyolo-ca-{}", std::process::id()));
+        let bundle = Self { dir };
+        bundle.write(&certificates).map_err(|e| {
+            format!("Failed to prepare CA certificates in {}: {e}", bundle.dir.display())
+        })?;
+        let count = certificates.iter().map(|c| count_certificates(c)).sum();
+        Ok((bundle, count))
+    }
+
+    fn write(&self, certificates: &[String]) -> io::Result<()> {
+        fs::create_dir(&self.dir)?;
+        fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o755))?;
+
+        let mut extra = String::new();
+        for (i, content) in certificates.iter().enumerate() {
+            // update-ca-certificates only picks up files ending in .crt
+            self.write_file(&format!("rustyolo-{}.crt", i + 1), content)?;
+            extra.push_str(content.trim_end());
+            extra.push('\n');
+        }
+        self.write_file(EXTRA_BUNDLE, &extra)
+    }
+
+    fn write_file(&self, name: &str, content: &str) -> io::Result<()> {
+        let path = self.dir.join(name);
+        fs::write(&path, content)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))
+    }
+
+    /// The directory to mount at [`CA_DIR`]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for CaBundle {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &str = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+
+    #[test]
+    fn test_create_bundle() {
+        let source = std::env::temp_dir().join(format!("rustyolo-ca-test-{}", std::process::id()));
+        fs::create_dir_all(&source).unwrap();
+        let pem = source.join("corp-root.pem");
+        fs::write(&pem, format!("{CERT}{CERT}")).unwrap();
+        let der = source.join("corp-root.der");
+        fs::write(&der, [0x30, 0x82, 0x01, 0x0a]).unwrap();
+
+        let (bundle, count) = CaBundle::create(std::slice::from_ref(&pem)).unwrap();
+        assert_eq!(count, 2);
+        assert!(bundle.dir().join("rustyolo-1.crt").exists());
+        let extra = fs::read_to_string(bundle.dir().join(EXTRA_BUNDLE)).unwrap();
+        assert_eq!(count_certificates(&extra), 2);
+        let dir = bundle.dir().to_path_buf();
+        drop(bundle);
+        assert!(!dir.exists());
+
+        assert!(CaBundle::create(&[der]).unwrap_err().contains("not a PEM certificate"));
+        assert!(CaBundle::create(&[source.join("missing.pem")]).is_err());
+        fs::remove_dir_all(&source).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Ranges that trusted domains must never resolve into: private (RFC 1918, unique local),
+/// loopback, link-local (including the cloud metadata endpoint `169.254.169.254`),
+/// carrier-grade NAT and IPv4-mapped IPv6 addresses. An answer in one of these ranges
+/// usually means DNS rebinding.
+///
+/// The IPv4 ranges are also blocked inside NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`)
+/// addresses, which embed an IPv4 address that the network may route to: see
+/// [`Blocklist::new`].
+pub const BLOCKED_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::1/128",
+    "::ffff:0:0/96",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// An IPv4 or IPv6 network from `allow_cidrs`, such as `10.20.0.0/16`.
+///
+/// A bare address (`10.20.0.5`) is treated as a single-host network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parse and validate a single `allow_cidrs` entry.
+    ///
+    /// Entries with host bits set (e.g. `10.20.0.5/16`) are rejected rather than silently
+    /// widened, since they usually indicate a typo.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let cidr = Cidr::parse("10.20.0.0/16")?;
+    /// assert_eq!(cidr.to_string(), "10.20.0.0/16");
+    /// ```
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid CIDR '{entry}': '{addr}' is not an IP address"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix {
+            Some(prefix) => {
+                prefix.parse::<u8>().ok().filter(|len| *len <= max_len).ok_or_else(|| {
+                    format!("Invalid CIDR '{entry}': prefix length must be between 0 and {max_len}")
+                })?
+            }
+            None => max_len,
+        };
+
+        let cidr = Self { network, prefix_len };
+        let masked = cidr.masked();
+        if masked != network {
+            return Err(format!(
+                "Invalid CIDR '{entry}': host bits are set (did you mean {masked}/{prefix_len}?)"
+            ));
+        }
+        Ok(cidr)
+    }
+
+    /// Whether this is an IPv6 network
+    pub fn is_ipv6(&self) -> bool {
+        self.network.is_ipv6()
+    }
+
+    /// Whether `ip` lies inside this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv6() == self.is_ipv6()
+            && Self { network: ip, prefix_len: self.prefix_len }.masked() == self.network
+    }
+
+    /// The IPv6 forms of an IPv4 network: inside the NAT64 well-known prefix
+    /// (`64:ff9b::/96`, RFC 6052) and inside 6to4 (`2002::/16`, RFC 3056). IPv6 networks
+    /// have none.
+    pub fn embedded_forms(&self) -> Vec<Cidr> {
+        let IpAddr::V4(ip) = self.network else {
+            return Vec::new();
+        };
+        let ip = u128::from(u32::from(ip));
+        let form = |network: u128, prefix_len: u8| Cidr {
+            network: Ipv6Addr::from(network).into(),
+            prefix_len: prefix_len + self.prefix_len,
+        };
+        vec![form(0x64_ff9b << 96 | ip, 96), form(0x2002 << 112 | ip << 80, 16)]
+    }
+
+    /// The network address with all host bits cleared
+    fn masked(&self) -> IpAddr {
+        match self.network {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                Ipv4Addr::from(u32::from(ip) & mask).into()
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                Ipv6Addr::from(u128::from(ip) & mask).into()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Parse a space-separated `allow_cidrs` list.
+pub fn parse_list(list: &str) -> Result<Vec<Cidr>, String> {
+    list.split_whitespace().map(Cidr::parse).collect()
+}
+
+/// The built-in [`BLOCKED_RANGES`], minus the parts exempted with `allow_private`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blocklist {
+    ranges: Vec<Cidr>,
+    overrides: Vec<Cidr>,
+    exemptions: Vec<Cidr>,
+}
+
+impl Blocklist {
+    /// Build the blocklist, exempting the `overrides` networks.
+    ///
+    /// IPv4 ranges are blocked, and IPv4 overrides exempted, in their NAT64 and 6to4 forms
+    /// too ([`Cidr::embedded_forms`]), so `64:ff9b::a9fe:a9fe` is blocked like
+    /// `169.254.169.254`.
+    pub fn new(overrides: Vec<Cidr>) -> Self {
+        let ranges: Vec<Cidr> = BLOCKED_RANGES
+            .iter()
+            .map(|range| Cidr::parse(range).expect("built-in blocked range is valid"))
+            .collect();
+        let with_forms = |cidrs: &[Cidr]| {
+            let forms = cidrs.iter().flat_map(Cidr::embedded_forms);
+            cidrs.iter().copied().chain(forms).collect()
+        };
+        Self { ranges: with_forms(&ranges), exemptions: with_forms(&overrides), overrides }
+    }
+
+    /// The blocked ranges
+    pub fn ranges(&self) -> &[Cidr] {
+        &self.ranges
+    }
+
+    /// The networks exempted from the blocked ranges with `allow_private`
+    pub fn overrides(&self) -> &[Cidr] {
+        &self.overrides
+    }
+
+    /// The networks exempted from the blocked ranges, with the NAT64 and 6to4 forms of
+    /// the IPv4 overrides
+    pub fn exemptions(&self) -> &[Cidr] {
+        &self.exemptions
+    }
+
+    /// The blocked range `ip` falls into, or `None` if it is not blocked.
+    pub fn blocks(&self, ip: IpAddr) -> Option<Cidr> {
+        if self.exemptions.iter().any(|cidr| cidr.contains(ip)) {
+            return None;
+        }
+        self.ranges.iter().find(|range| range.contains(ip)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_networks_and_addresses() {
+        let list = parse_list("10.20.0.0/16 192.168.1.10 fd00:1234::/32").unwrap();
+        let rendered: Vec<String> = list.iter().map(ToString::to_string).collect();
+        assert_eq!(rendered, ["10.20.0.0/16", "192.168.1.10/32", "fd00:1234::/32"]);
+        assert!(list[2].is_ipv6());
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_entries() {
+        assert!(Cidr::parse("10.20.0.0/33").is_err());
+        assert!(Cidr::parse("10.20.0.0/").is_err());
+        assert!(Cidr::parse("internal.example.com").is_err());
+        assert!(Cidr::parse("10.20.0.0/16;reboot").is_err());
+
+        let err = Cidr::parse("10.20.0.5/16").unwrap_err();
+        assert!(err.contains("did you mean 10.20.0.0/16"));
+    }
+
+    #[test]
+    fn test_blocklist_with_overrides() {
+        let blocklist = Blocklist::new(parse_list("10.20.0.0/16").unwrap());
+        let blocked = |ip: &str| blocklist.blocks(ip.parse().unwrap()).map(|c| c.to_string());
+
+        assert_eq!(blocked("169.254.169.254").as_deref(), Some("169.254.0.0/16"));
+        assert_eq!(blocked("127.0.0.1").as_deref(), Some("127.0.0.0/8"));
+        assert_eq!(blocked("10.1.2.3").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(blocked("::ffff:10.1.2.3").as_deref(), Some("::ffff:0.0.0.0/96"));
+        assert_eq!(blocked("fd00:ec2::254").as_deref(), Some("fc00::/7"));
+        assert_eq!(blocked("10.20.5.6"), None);
+        assert_eq!(blocked("140.82.112.3"), None);
+        assert_eq!(blocked("2606:50c0:8000::154"), None);
+
+        // IPv4 addresses embedded in NAT64 and 6to4 addresses
+        assert_eq!(blocked("64:ff9b::a9fe:a9fe").as_deref(), Some("64:ff9b::a9fe:0/112"));
+        assert_eq!(blocked("64:ff9b::7f00:1").as_deref(), Some("64:ff9b::7f00:0/104"));
+        assert_eq!(blocked("2002:a9fe:a9fe::1").as_deref(), Some("2002:a9fe::/32"));
+        assert_eq!(blocked("2002:c0a8:101:1::5").as_deref(), Some("2002:c0a8::/32"));
+        assert_eq!(blocked("64:ff9b::a14:506"), None);
+        assert_eq!(blocked("2002:a14:506::1"), None);
+        assert_eq!(blocked("64:ff9b::8c52:7003"), None);
+        assert_eq!(blocked("2002:8c52:7003::1"), None);
+        assert_eq!(blocklist.overrides().len(), 1);
+    }
+}
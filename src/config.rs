@@ -1,36 +1,53 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// Name of the project configuration file, in the current directory
+pub const CONFIG_FILE: &str = ".rustyolo.toml";
+
 /// Configuration file structure for .rustyolo.toml
 ///
 /// This allows users to specify default settings at the project level,
 /// avoiding the need to type long command-line arguments repeatedly.
 ///
 /// CLI arguments always take precedence over config file settings.
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     /// Default configuration section
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_default")]
     pub default: DefaultConfig,
 
     /// Resource limits configuration
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_default")]
     pub resources: ResourcesConfig,
 
     /// Security configuration
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_default")]
     pub security: SecurityConfig,
+
+    /// Network configuration
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub network: NetworkConfig,
 }
 
 /// Default runtime configuration
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct DefaultConfig {
     /// Space-separated list of domains to allow outbound traffic to
     pub allow_domains: Option<String>,
 
+    /// Space-separated list of domains to block even if they match `allow_domains`
+    pub deny_domains: Option<String>,
+
+    /// Named allowlist presets to add to `allow_domains` (e.g., `["python", "github"]`)
+    pub allow_presets: Option<Vec<String>>,
+
+    /// Space-separated list of IP addresses or CIDR ranges to allow outbound traffic to
+    pub allow_cidrs: Option<String>,
+
     /// Additional volumes to mount (array of strings)
     pub volumes: Option<Vec<String>>,
 
@@ -40,6 +57,18 @@ pub struct DefaultConfig {
     /// Persistent auth directory path
     pub auth_home: Option<PathBuf>,
 
+    /// How the project is mounted: "bind" or "overlay"
+    pub workspace: Option<String>,
+
+    /// Run sessions in a new git worktree and branch
+    pub worktree: Option<bool>,
+
+    /// Snapshot the project before each session (default: true)
+    pub snapshot: Option<bool>,
+
+    /// Size above which an ignored path is left out of a git snapshot, or "none"
+    pub snapshot_ignored_limit: Option<String>,
+
     /// Docker image to use
     pub image: Option<String>,
 
@@ -48,7 +77,7 @@ pub struct DefaultConfig {
 }
 
 /// Resource limits configuration
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct ResourcesConfig {
     /// Memory limit (e.g., "4g", "512m")
@@ -62,7 +91,7 @@ pub struct ResourcesConfig {
 }
 
 /// Security configuration
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct SecurityConfig {
     /// Path to custom seccomp profile, or "none" to disable
@@ -71,11 +100,107 @@ pub struct SecurityConfig {
     /// Space-separated list of DNS servers to allow
     pub dns_servers: Option<String>,
 
+    /// Disable IPv6 in the container instead of firewalling it with ip6tables
+    pub disable_ipv6: Option<bool>,
+
     /// Audit logging level: "none", "basic", "verbose"
     pub audit_log: Option<String>,
 
     /// Custom message to inject into agent's system prompt
     pub inject_message: Option<String>,
+
+    /// PEM CA certificates to trust inside the sandbox (e.g., a TLS-inspecting gateway's root)
+    pub ca_certificates: Option<Vec<String>>,
+
+    /// Host paths volumes may not mount, in addition to the built-in deny tree
+    pub deny_mounts: Option<Vec<String>>,
+}
+
+/// Network configuration
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// How outbound traffic is filtered: "firewall", "proxy" or "offline"
+    pub mode: Option<String>,
+
+    /// Seconds between re-resolving trusted domains (0 = only at startup)
+    pub refresh_interval: Option<u64>,
+
+    /// Limit trusted domains listed without a port to 80/tcp and 443/tcp
+    pub strict_ports: Option<bool>,
+
+    /// TCP ports on the Docker host the agent may reach via `host.docker.internal`
+    pub host_ports: Option<Vec<u16>>,
+
+    /// Space-separated networks exempted from the blocked private and link-local ranges
+    pub allow_private: Option<String>,
+
+    /// Egress quota on all outbound traffic (e.g., "200MB")
+    pub max_egress: Option<String>,
+
+    /// Per-domain egress quotas: domain or pattern -> size
+    pub egress_quotas: Option<BTreeMap<String, String>>,
+
+    /// Ask the operator before refusing names outside the allowlist
+    pub live_approval: Option<bool>,
+
+    /// Corporate HTTP proxy to send all allowed traffic through (`http://[user:pass@]host:port`)
+    pub upstream_proxy: Option<String>,
+
+    /// HTTP methods and paths allowed on specific hosts
+    pub http_policies: Option<Vec<HttpPolicy>>,
+
+    /// Running containers the agent may reach on the session network
+    pub sidecars: Option<Vec<String>>,
+}
+
+/// HTTP methods and paths allowed on a host, e.g.
+/// `{ host = "api.github.com", methods = ["GET"], paths = ["/repos/our-org/*"] }`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpPolicy {
+    /// Domain or pattern the policy applies to
+    pub host: String,
+
+    /// Allowed request methods (any if omitted)
+    pub methods: Option<Vec<String>>,
+
+    /// Allowed request paths, where `*` matches any characters (any if omitted)
+    pub paths: Option<Vec<String>>,
+}
+
+/// User-wide configuration, shared by all projects
+///
+/// Lives in `rustyolo.toml` in the user's config directory (`~/.config` on Linux). It is
+/// deliberately kept outside the auth home directory, which is mounted writable into
+/// the sandbox, so the agent cannot change it.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalConfig {
+    /// User-defined allowlist presets: preset name -> `allow_domains` entries
+    #[serde(default)]
+    pub presets: BTreeMap<String, Vec<String>>,
+}
+
+impl GlobalConfig {
+    /// Try to load the global configuration file
+    ///
+    /// Returns the default (empty) configuration if the file doesn't exist.
+    /// Returns `Err` if the file exists but cannot be parsed.
+    pub fn try_load() -> Result<Self, String> {
+        let Some(config_path) = dirs::config_dir().map(|dir| dir.join("rustyolo.toml")) else {
+            return Ok(Self::default());
+        };
+
+        if !config_path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(&config_path)
+            .map_err(|e| format!("Failed to read {}: {e}", config_path.display()))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse {}: {e}", config_path.display()))
+    }
 }
 
 impl Config {
@@ -118,7 +243,7 @@ impl Config {
     /// }
     /// ```
     pub fn try_load_from_current_dir() -> Result<Option<Self>, String> {
-        let config_path = PathBuf::from(".rustyolo.toml");
+        let config_path = PathBuf::from(CONFIG_FILE);
 
         if !config_path.exists() {
             return Ok(None);
@@ -126,6 +251,32 @@ impl Config {
 
         Self::load(&config_path).map(Some)
     }
+
+    /// Write the configuration to a TOML file
+    ///
+    /// Unset options and empty sections are left out. Comments in an existing file are not
+    /// preserved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let content =
+            toml::to_string_pretty(self).map_err(|e| format!("Failed to serialize config: {e}"))?;
+        fs::write(path.as_ref(), content)
+            .map_err(|e| format!("Failed to write {}: {e}", path.as_ref().display()))
+    }
+}
+
+/// Expand a leading `~`, `$HOME` or `${HOME}` in a path from the command line or
+/// configuration file to the user's home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    let rest = ["~", "${HOME}", "$HOME"].iter().find_map(|prefix| path.strip_prefix(prefix));
+    match (rest, dirs::home_dir()) {
+        (Some(""), Some(home)) => home,
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
 }
 
 #[cfg(test)]
@@ -137,9 +288,16 @@ mod tests {
         let toml_str = r#"
 [default]
 allow_domains = "github.com pypi.org"
+deny_domains = "gist.github.com uploads.github.com"
+allow_presets = ["python", "github"]
+allow_cidrs = "10.20.0.0/16 fd00::/8"
 volumes = ["~/.ssh:/home/agent/.ssh:ro", "~/.gitconfig:/home/agent/.gitconfig:ro"]
 env = ["MY_VAR=value", "ANOTHER=var"]
 auth_home = "~/.config/rustyolo"
+workspace = "overlay"
+worktree = true
+snapshot = false
+snapshot_ignored_limit = "1GB"
 image = "my-custom-image:latest"
 agent = "claude"
 
@@ -151,17 +309,46 @@ pids_limit = "512"
 [security]
 seccomp_profile = "./seccomp/custom.json"
 dns_servers = "8.8.8.8 1.1.1.1"
+disable_ipv6 = true
 audit_log = "verbose"
 inject_message = "You are in a restricted environment"
+ca_certificates = ["~/corp-root.pem"]
+deny_mounts = ["~/.kube"]
+
+[network]
+mode = "proxy"
+refresh_interval = 120
+strict_ports = true
+host_ports = [11434, 5432]
+allow_private = "10.20.0.0/16"
+max_egress = "200MB"
+egress_quotas = { "github.com" = "50MB" }
+live_approval = true
+upstream_proxy = "http://proxy.corp:3128"
+http_policies = [{ host = "api.github.com", methods = ["GET"], paths = ["/repos/our-org/*"] }]
+sidecars = ["postgres-dev"]
 "#;
 
         let config: Config = toml::from_str(toml_str).unwrap();
 
         // Test default section
         assert_eq!(config.default.allow_domains, Some("github.com pypi.org".to_string()));
+        assert_eq!(
+            config.default.deny_domains,
+            Some("gist.github.com uploads.github.com".to_string())
+        );
+        assert_eq!(
+            config.default.allow_presets,
+            Some(vec!["python".to_string(), "github".to_string()])
+        );
+        assert_eq!(config.default.allow_cidrs, Some("10.20.0.0/16 fd00::/8".to_string()));
         assert_eq!(config.default.volumes.as_ref().unwrap().len(), 2);
         assert_eq!(config.default.env.as_ref().unwrap().len(), 2);
         assert_eq!(config.default.auth_home, Some(PathBuf::from("~/.config/rustyolo")));
+        assert_eq!(config.default.workspace.as_deref(), Some("overlay"));
+        assert_eq!(config.default.worktree, Some(true));
+        assert_eq!(config.default.snapshot, Some(false));
+        assert_eq!(config.default.snapshot_ignored_limit.as_deref(), Some("1GB"));
         assert_eq!(config.default.image, Some("my-custom-image:latest".to_string()));
         assert_eq!(config.default.agent, Some("claude".to_string()));
 
@@ -173,11 +360,34 @@ inject_message = "You are in a restricted environment"
         // Test security section
         assert_eq!(config.security.seccomp_profile, Some("./seccomp/custom.json".to_string()));
         assert_eq!(config.security.dns_servers, Some("8.8.8.8 1.1.1.1".to_string()));
+        assert_eq!(config.security.disable_ipv6, Some(true));
         assert_eq!(config.security.audit_log, Some("verbose".to_string()));
         assert_eq!(
             config.security.inject_message,
             Some("You are in a restricted environment".to_string())
         );
+        assert_eq!(config.security.ca_certificates, Some(vec!["~/corp-root.pem".to_string()]));
+        assert_eq!(config.security.deny_mounts, Some(vec!["~/.kube".to_string()]));
+
+        // Test network section
+        assert_eq!(config.network.mode, Some("proxy".to_string()));
+        assert_eq!(config.network.refresh_interval, Some(120));
+        assert_eq!(config.network.strict_ports, Some(true));
+        assert_eq!(config.network.host_ports, Some(vec![11434, 5432]));
+        assert_eq!(config.network.allow_private, Some("10.20.0.0/16".to_string()));
+        assert_eq!(config.network.max_egress, Some("200MB".to_string()));
+        assert_eq!(config.network.egress_quotas.unwrap()["github.com"], "50MB");
+        assert_eq!(config.network.live_approval, Some(true));
+        assert_eq!(config.network.upstream_proxy, Some("http://proxy.corp:3128".to_string()));
+        assert_eq!(
+            config.network.http_policies,
+            Some(vec![HttpPolicy {
+                host: "api.github.com".to_string(),
+                methods: Some(vec!["GET".to_string()]),
+                paths: Some(vec!["/repos/our-org/*".to_string()]),
+            }])
+        );
+        assert_eq!(config.network.sidecars, Some(vec!["postgres-dev".to_string()]));
     }
 
     #[test]
@@ -200,6 +410,34 @@ allow_domains = "github.com"
         assert!(config.default.allow_domains.is_none());
     }
 
+    #[test]
+    fn test_parse_global_config() {
+        let toml_str = r#"
+[presets]
+corp = ["git.corp.example.com:22", "*.artifacts.corp.example.com"]
+"#;
+
+        let config: GlobalConfig = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.presets["corp"].len(), 2);
+        assert!(toml::from_str::<GlobalConfig>("").unwrap().presets.is_empty());
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let toml_str = r#"
+[default]
+allow_domains = "github.com pypi.org"
+
+[network]
+egress_quotas = { "github.com" = "50MB" }
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let serialized = toml::to_string_pretty(&config).unwrap();
+        assert!(!serialized.contains("[resources]"));
+        assert_eq!(toml::from_str::<Config>(&serialized).unwrap(), config);
+    }
+
     #[test]
     fn test_reject_unknown_fields() {
         let toml_str = r#"
@@ -211,4 +449,16 @@ unknown_field = "value"
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("unknown field `unknown_field`"));
     }
+
+    #[test]
+    fn test_expand_home() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~"), home);
+        assert_eq!(expand_home("~/corp-root.pem"), home.join("corp-root.pem"));
+        assert_eq!(expand_home("~other/ca.pem"), PathBuf::from("~other/ca.pem"));
+        assert_eq!(expand_home("$HOME/ca.pem"), home.join("ca.pem"));
+        assert_eq!(expand_home("${HOME}"), home);
+        assert_eq!(expand_home("$HOMEDIR/ca.pem"), PathBuf::from("$HOMEDIR/ca.pem"));
+        assert_eq!(expand_home("/etc/ca.pem"), PathBuf::from("/etc/ca.pem"));
+    }
 }
@@ -0,0 +1,1008 @@
+//! DNS forwarder that runs inside the sandbox.
+//!
+//! Wildcard and suffix patterns in `allow_domains` (e.g. `*.githubusercontent.com`) cannot be
+//! resolved up front, so the firewall cannot allow them by IP at startup. Instead, the
+//! container's resolver points at this forwarder. It relays every query to the upstream DNS
+//! servers and, when the answer is for a name matching a trusted pattern, adds firewall rules
+//! for the returned addresses before handing the answer back to the client. The connection
+//! that follows the lookup is therefore allowed, while names that were never looked up
+//! through the sandbox resolver stay blocked.
+//!
+//! The forwarder is also the only resolver the agent can reach, so it doubles as a DNS
+//! filter: queries for names that do not match the allowlist are answered with NXDOMAIN
+//! and never leave the sandbox. This stops an agent from exfiltrating data by encoding it
+//! in lookups such as `secret-data.attacker.com`. Names on the denylist are answered with
+//! NXDOMAIN even when they match the allowlist.
+//!
+//! Names resolved on the host before the session (see [`crate::resolve`]) are answered
+//! with their pinned addresses instead of being forwarded, and answers never open rules
+//! for them: the refresher opened the pinned addresses, and the in-sandbox resolver has
+//! no say in them. The refresher checks the pins again as it refreshes and writes the
+//! new ones to a file, which the forwarder reloads when it changes.
+
+use crate::approval::{self, Approval};
+use crate::audit::AuditLevel;
+use crate::cidr::Blocklist;
+use crate::domains::{AllowEntry, DomainPattern, Port};
+use crate::egress::Quotas;
+use crate::firewall;
+use crate::learn::Recorder;
+use crate::wire::Reader;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long to wait for an upstream server before trying the next one
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS record type for IPv4 addresses
+pub const TYPE_A: u16 = 1;
+
+/// DNS record type for IPv6 addresses
+pub const TYPE_AAAA: u16 = 28;
+
+/// DNS record type for EDNS(0) options
+const TYPE_OPT: u16 = 41;
+
+/// Largest UDP response to ask for (the DNS flag day 2020 recommendation)
+const EDNS_UDP_SIZE: u16 = 1232;
+
+/// Header flag: the response was truncated and must be retried over TCP
+const FLAG_TC: u16 = 0x0200;
+
+/// Header flag: the resolver validated the answer with DNSSEC
+const FLAG_AD: u16 = 0x0020;
+
+/// DNS record type for reverse lookups
+const TYPE_PTR: u16 = 12;
+
+/// TTL of the records in answers for pinned names, in seconds
+const PINNED_TTL: u32 = 300;
+
+/// How often rules opened for "once" approvals are checked for expiry
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runtime settings for the DNS forwarder
+pub struct ForwarderConfig {
+    /// Upstream DNS servers, tried in order
+    pub upstream: Vec<SocketAddr>,
+
+    /// Trusted entries; only names matching one of them are forwarded
+    pub allowlist: Vec<AllowEntry>,
+
+    /// Denied patterns; names matching one of them are never forwarded
+    pub denylist: Vec<DomainPattern>,
+
+    /// Addresses pinned on the host, by name; pinned names are answered from here (no
+    /// addresses: NXDOMAIN)
+    pub pinned: BTreeMap<String, Vec<IpAddr>>,
+
+    /// File the refresher writes re-checked pins to; replaces `pinned` once it exists
+    pub pins_file: Option<PathBuf>,
+
+    /// Add firewall rules for the addresses in answers (disabled in proxy mode, where
+    /// connections are filtered by name instead)
+    pub update_firewall: bool,
+
+    /// Also allow IPv6 addresses (AAAA records) through `ip6tables`
+    pub ipv6: bool,
+
+    /// Ranges that answers may not open
+    pub blocklist: Blocklist,
+
+    /// Egress quotas; when set, traffic to answered addresses is counted per name (also
+    /// in proxy mode, where the egress proxy resolves names through this forwarder)
+    pub quotas: Quotas,
+
+    /// Records names outside the allowlist in learn mode
+    pub learn: Option<Recorder>,
+
+    /// Asks the host about names outside the allowlist (live approval)
+    pub approval: Option<approval::Client>,
+
+    /// Audit logging level
+    pub audit: AuditLevel,
+}
+
+/// Address/port pairs that have firewall rules, with the time the rules expire (`None`
+/// for rules that stay for the session)
+#[derive(Debug, Default)]
+struct Opened {
+    rules: HashMap<(IpAddr, Option<Port>), Option<Instant>>,
+}
+
+impl Opened {
+    /// Record a rule for `key` that lasts until `until` (`None`: the session).
+    ///
+    /// # Returns
+    ///
+    /// Whether the rule still has to be added to the firewall; otherwise the existing one
+    /// is kept for as long as the longer of the two
+    fn open(&mut self, key: (IpAddr, Option<Port>), until: Option<Instant>) -> bool {
+        if let Some(current) = self.rules.get_mut(&key) {
+            *current = current.zip(until).map(|(a, b)| a.max(b));
+            false
+        } else {
+            self.rules.insert(key, until);
+            true
+        }
+    }
+
+    /// Forget a rule that could not be added.
+    fn forget(&mut self, key: &(IpAddr, Option<Port>)) {
+        self.rules.remove(key);
+    }
+
+    /// Forget the rules that expired at `now` and return them, to remove from the firewall.
+    fn expire(&mut self, now: Instant) -> Vec<(IpAddr, Option<Port>)> {
+        let mut expired = Vec::new();
+        self.rules.retain(|key, until| {
+            let alive = until.is_none_or(|until| now < until);
+            if !alive {
+                expired.push(*key);
+            }
+            alive
+        });
+        expired
+    }
+}
+
+/// The pinned addresses currently in effect
+#[derive(Debug, Default)]
+struct Pinned {
+    names: BTreeMap<String, Vec<IpAddr>>,
+
+    /// Modification time of the pins file they were read from (`None`: the initial pins)
+    modified: Option<SystemTime>,
+}
+
+struct Forwarder {
+    config: ForwarderConfig,
+
+    /// Pinned addresses, reloaded when the refresher rewrites the pins file
+    pinned: Mutex<Pinned>,
+
+    /// Rules added for answers
+    allowed: Mutex<Opened>,
+
+    /// Address/name pairs that already have accounting rules
+    counted: Mutex<HashSet<(IpAddr, String)>>,
+}
+
+/// Run the forwarder on UDP and TCP until the process is killed.
+///
+/// # Arguments
+///
+/// * `listen` - Address to listen on (e.g., "127.0.0.1:53")
+/// * `config` - Upstream servers, trusted patterns and audit settings
+pub fn run(listen: &str, config: ForwarderConfig) -> Result<(), Box<dyn Error>> {
+    if config.upstream.is_empty() {
+        return Err("No upstream DNS servers configured".into());
+    }
+
+    let udp = UdpSocket::bind(listen).map_err(|e| format!("Failed to listen on {listen}: {e}"))?;
+    let tcp =
+        TcpListener::bind(listen).map_err(|e| format!("Failed to listen on {listen}: {e}"))?;
+    println!("[RustyYOLO DNS] Listening on {listen}");
+
+    let forwarder = Arc::new(Forwarder {
+        pinned: Mutex::new(Pinned { names: config.pinned.clone(), modified: None }),
+        config,
+        allowed: Mutex::new(Opened::default()),
+        counted: Mutex::new(HashSet::new()),
+    });
+
+    // Rules opened for "once" approvals are removed when the approval runs out
+    if forwarder.config.update_firewall && forwarder.config.approval.is_some() {
+        let sweeper = Arc::clone(&forwarder);
+        thread::spawn(move || loop {
+            thread::sleep(EXPIRY_INTERVAL);
+            sweeper.expire(Instant::now());
+        });
+    }
+
+    let tcp_forwarder = Arc::clone(&forwarder);
+    thread::spawn(move || {
+        for client in tcp.incoming().flatten() {
+            let forwarder = Arc::clone(&tcp_forwarder);
+            thread::spawn(move || {
+                let _ = forwarder.serve_tcp(client);
+            });
+        }
+    });
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let Ok((len, client)) = udp.recv_from(&mut buf) else {
+            continue;
+        };
+        let query = buf[..len].to_vec();
+        let socket = udp.try_clone()?;
+        let forwarder = Arc::clone(&forwarder);
+        thread::spawn(move || {
+            if let Some(response) = forwarder.answer_udp(&query) {
+                let _ = socket.send_to(&response, client);
+            }
+        });
+    }
+}
+
+impl Forwarder {
+    /// Decide whether a client query may be forwarded.
+    ///
+    /// Returns what the operator approved for this query (nothing for trusted names),
+    /// or `Err` with the reply to send instead (NXDOMAIN for denied names and names
+    /// outside the allowlist, the pinned addresses for pinned names), or `Err(None)` to
+    /// drop a query that could not be parsed.
+    fn screen(&self, query: &[u8]) -> Result<Approval, Option<Vec<u8>>> {
+        let question = parse_query(query).ok_or(None)?;
+        if self.config.denylist.iter().any(|pattern| pattern.matches(&question.name)) {
+            if self.config.audit.logs_blocked() {
+                println!("[AUDIT-DENY] {} {}", question.name, type_name(question.record_type));
+            }
+            return Err(Some(nxdomain(query, question.end)));
+        }
+
+        let allowed =
+            self.config.allowlist.iter().any(|entry| entry.pattern.matches(&question.name));
+        let approved = match &self.config.approval {
+            Some(client) if !allowed && matches!(question.record_type, TYPE_A | TYPE_AAAA) => {
+                client.ask("dns", &question.name)
+            }
+            _ => Approval::default(),
+        };
+
+        if !approved.entries.is_empty() {
+            if self.config.audit.logs_blocked() {
+                println!("[AUDIT-APPROVED] {} {}", question.name, type_name(question.record_type));
+            }
+            return Ok(approved);
+        }
+        if !allowed {
+            if self.config.audit.logs_blocked() {
+                println!("[AUDIT-DNS-BLOCK] {} {}", question.name, type_name(question.record_type));
+            }
+            if let Some(learn) = &self.config.learn {
+                if matches!(question.record_type, TYPE_A | TYPE_AAAA) {
+                    learn.record("dns", &question.name);
+                }
+            }
+            return Err(Some(nxdomain(query, question.end)));
+        }
+        if self.config.audit.logs_allowed() {
+            println!("[AUDIT-DNS-QUERY] {} {}", question.name, type_name(question.record_type));
+        }
+        match self.pinned(&question.name) {
+            Some(addresses) if addresses.is_empty() => Err(Some(nxdomain(query, question.end))),
+            Some(addresses) if matches!(question.record_type, TYPE_A | TYPE_AAAA) => {
+                Err(Some(pinned_reply(query, &question, &addresses)))
+            }
+            _ => Ok(Approval::default()),
+        }
+    }
+
+    fn answer_udp(&self, query: &[u8]) -> Option<Vec<u8>> {
+        match self.screen(query) {
+            Ok(approved) => self.forward_udp(query, &approved),
+            Err(reply) => reply,
+        }
+    }
+
+    fn answer_tcp(&self, query: &[u8]) -> Option<Vec<u8>> {
+        match self.screen(query) {
+            Ok(approved) => self.forward_tcp(query, &approved),
+            Err(reply) => reply,
+        }
+    }
+
+    fn forward_udp(&self, query: &[u8], approved: &Approval) -> Option<Vec<u8>> {
+        for server in &self.config.upstream {
+            if let Some(response) = exchange_udp(server, query) {
+                self.inspect(&response, approved);
+                return Some(response);
+            }
+        }
+        None
+    }
+
+    fn forward_tcp(&self, query: &[u8], approved: &Approval) -> Option<Vec<u8>> {
+        for server in &self.config.upstream {
+            if let Some(response) = exchange_tcp(server, query) {
+                self.inspect(&response, approved);
+                return Some(response);
+            }
+        }
+        None
+    }
+
+    fn serve_tcp(&self, mut client: TcpStream) -> io::Result<()> {
+        client.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        loop {
+            let query = read_tcp_message(&mut client)?;
+            let Some(response) = self.answer_tcp(&query) else {
+                return Ok(());
+            };
+            write_tcp_message(&mut client, &response)?;
+        }
+    }
+
+    /// The pinned addresses of `name`, if it is pinned, after picking up a rewritten pins
+    /// file.
+    fn pinned(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let mut pinned = self.pinned.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(path) = &self.config.pins_file {
+            let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+            if modified.is_some() && modified != pinned.modified {
+                match fs::read_to_string(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|list| crate::resolve::parse_pins(&list))
+                {
+                    Ok(names) => *pinned = Pinned { names, modified },
+                    Err(e) => {
+                        eprintln!("[RustyYOLO DNS] ERROR: {}: {e}", path.display());
+                        pinned.modified = modified;
+                    }
+                }
+            }
+        }
+        pinned.names.get(name).cloned()
+    }
+
+    /// Add firewall rules for answers to names that match a trusted pattern or an entry
+    /// the operator `approved` for this query. Rules for a "once" approval expire with it.
+    fn inspect(&self, response: &[u8], approved: &Approval) {
+        if !self.config.update_firewall && self.config.quotas.is_empty() {
+            return;
+        }
+        let Some(answer) = parse_response(response) else {
+            return;
+        };
+        if self.pinned(&answer.name).is_some() {
+            return;
+        }
+        let until = approved.lifetime.map(|lifetime| Instant::now() + lifetime);
+        let ports: Vec<(Option<Port>, Option<Instant>)> = self
+            .config
+            .allowlist
+            .iter()
+            .map(|entry| (entry, None))
+            .chain(approved.entries.iter().map(|entry| (entry, until)))
+            .filter(|(entry, _)| entry.pattern.matches(&answer.name))
+            .map(|(entry, until)| (entry.port, until))
+            .collect();
+
+        let mut allowed = self.allowed.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        for ip in firewall::screen_resolved(&answer.name, answer.addresses, &self.config.blocklist)
+        {
+            if ip.is_ipv6() && !self.config.ipv6 {
+                continue;
+            }
+            if !self.config.quotas.is_empty() {
+                let mut counted =
+                    self.counted.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+                if counted.insert((ip, answer.name.clone())) {
+                    if let Err(e) = firewall::account_ip(ip, &answer.name, &self.config.quotas) {
+                        eprintln!("[RustyYOLO Firewall] ERROR: {e}");
+                        counted.remove(&(ip, answer.name.clone()));
+                    }
+                }
+            }
+            if !self.config.update_firewall {
+                continue;
+            }
+            for (port, until) in &ports {
+                if !allowed.open((ip, *port), *until) {
+                    continue;
+                }
+                match firewall::allow_ip(firewall::DNS_CHAIN, ip, *port, self.config.audit) {
+                    Ok(()) => println!(
+                        "[RustyYOLO Firewall] ALLOWING IP: {ip}{} (for {})",
+                        firewall::port_suffix(*port),
+                        answer.name
+                    ),
+                    Err(e) => {
+                        eprintln!("[RustyYOLO Firewall] ERROR: {e}");
+                        allowed.forget(&(ip, *port));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove the rules of "once" approvals that have run out.
+    fn expire(&self, now: Instant) {
+        let expired = self
+            .allowed
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .expire(now);
+        for (ip, port) in expired {
+            println!(
+                "[RustyYOLO Firewall] EXPIRING IP: {ip}{} (approved once)",
+                firewall::port_suffix(port)
+            );
+            if let Err(e) = firewall::remove_ip(firewall::DNS_CHAIN, ip, port, self.config.audit) {
+                eprintln!("[RustyYOLO Firewall] ERROR: {e}");
+            }
+        }
+    }
+}
+
+/// Send `query` to `server` over UDP and wait for its reply.
+fn exchange_udp(server: &SocketAddr, query: &[u8]) -> Option<Vec<u8>> {
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).ok()?;
+    socket.set_read_timeout(Some(UPSTREAM_TIMEOUT)).ok()?;
+    socket.send_to(query, server).ok()?;
+
+    let mut buf = vec![0u8; 65535];
+    while let Ok((len, from)) = socket.recv_from(&mut buf) {
+        // Ignore stray packets that are not the reply to this query
+        if from == *server && len >= 2 && buf[..2] == query[..2] {
+            return Some(buf[..len].to_vec());
+        }
+    }
+    None
+}
+
+/// Send `query` to `server` over TCP and wait for its reply.
+fn exchange_tcp(server: &SocketAddr, query: &[u8]) -> Option<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(server, UPSTREAM_TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT)).ok()?;
+    write_tcp_message(&mut stream, query).ok()?;
+    read_tcp_message(&mut stream).ok()
+}
+
+/// Look up the A or AAAA records of `name` on one server, asking for DNSSEC validation.
+///
+/// The query sets the AD and DO bits, so a validating resolver reports whether the answer
+/// was validated. Truncated responses are retried over TCP.
+///
+/// # Returns
+///
+/// The answer (with no addresses for NXDOMAIN and empty answers), or `None` if the server
+/// did not reply with a response to this query
+pub fn lookup(server: &SocketAddr, name: &str, record_type: u16) -> Option<Answer> {
+    let query = address_query(name, record_type);
+    let mut response = exchange_udp(server, &query)?;
+    if response.len() >= 4 && u16::from_be_bytes([response[2], response[3]]) & FLAG_TC != 0 {
+        response = exchange_tcp(server, &query)?;
+    }
+    if response.len() < 2 || response[..2] != query[..2] {
+        return None;
+    }
+    parse_response(&response).filter(|answer| answer.name == name.to_ascii_lowercase())
+}
+
+/// Build a recursive query for `name` with the AD bit and an EDNS(0) record with the DO bit
+fn address_query(name: &str, record_type: u16) -> Vec<u8> {
+    // Unpredictable IDs make spoofed responses harder to inject
+    let id =
+        std::hash::BuildHasher::hash_one(&std::collections::hash_map::RandomState::new(), name);
+    let mut query = u16::try_from(id & 0xFFFF).unwrap_or_default().to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x20, 0x00, 0x01, 0, 0, 0, 0, 0x00, 0x01]); // RD, AD; one question and one additional record
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(u8::try_from(label.len()).unwrap_or_default());
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x01]); // IN
+
+    // OPT pseudo-record: root name, UDP payload size, DO flag, no options
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&EDNS_UDP_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x00, 0x80, 0x00, 0x00, 0x00]);
+    query
+}
+
+/// Look up the name of `ip` (its PTR record) on the first upstream server that answers.
+///
+/// Used by learn mode to name addresses the firewall blocked.
+pub fn reverse_lookup(ip: IpAddr, upstream: &[SocketAddr]) -> Option<String> {
+    let query = ptr_query(ip);
+    upstream
+        .iter()
+        .find_map(|server| exchange_udp(server, &query).and_then(|r| parse_ptr_response(&r)))
+}
+
+/// The `in-addr.arpa` / `ip6.arpa` name of an address
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets: Vec<String> = ip.octets().iter().rev().map(ToString::to_string).collect();
+            format!("{}.in-addr.arpa", octets.join("."))
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|byte| [byte & 0x0F, byte >> 4])
+                .map(|nibble| format!("{nibble:x}"))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+/// Build a recursive PTR query for `ip`
+fn ptr_query(ip: IpAddr) -> Vec<u8> {
+    let id = u16::try_from(std::process::id() & 0xFFFF).unwrap_or_default();
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]); // RD, one question
+    for label in reverse_name(ip).split('.') {
+        query.push(u8::try_from(label.len()).unwrap_or_default());
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x01]); // IN
+    query
+}
+
+/// The first PTR name in the answer section of a response
+fn parse_ptr_response(message: &[u8]) -> Option<String> {
+    let mut r = Reader::new(message);
+    r.skip(2)?; // id
+    let flags = r.u16()?;
+    let question_count = r.u16()?;
+    let answer_count = r.u16()?;
+    r.skip(4)?; // authority and additional counts
+    if flags & 0x8000 == 0 || question_count != 1 {
+        return None;
+    }
+    read_name(&mut r)?;
+    r.skip(4)?; // qtype, qclass
+
+    for _ in 0..answer_count {
+        read_name(&mut r)?;
+        let record_type = r.u16()?;
+        r.skip(2 + 4)?; // class, ttl
+        let data_len = usize::from(r.u16()?);
+        if record_type == TYPE_PTR {
+            return read_name(&mut r);
+        }
+        r.skip(data_len)?;
+    }
+    None
+}
+
+fn read_tcp_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn write_tcp_message(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "DNS message too large"))?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(message)
+}
+
+/// The question of a client query
+#[derive(Debug, PartialEq)]
+struct Question {
+    /// The queried name (lowercase, without trailing dot)
+    name: String,
+
+    record_type: u16,
+
+    /// Offset just past the question section
+    end: usize,
+}
+
+/// Parse the single question out of a client query.
+fn parse_query(message: &[u8]) -> Option<Question> {
+    let mut r = Reader::new(message);
+    r.skip(2)?; // id
+    let flags = r.u16()?;
+    let question_count = r.u16()?;
+    r.skip(6)?; // answer, authority and additional counts
+
+    // Must be a query (no QR bit) with exactly one question
+    if flags & 0x8000 != 0 || question_count != 1 {
+        return None;
+    }
+
+    let name = read_name(&mut r)?;
+    let record_type = r.u16()?;
+    r.skip(2)?; // qclass
+    Some(Question { name, record_type, end: r.position() })
+}
+
+/// Build an NXDOMAIN reply to `query`, echoing its header and question.
+fn nxdomain(query: &[u8], question_end: usize) -> Vec<u8> {
+    let mut reply = query[..question_end].to_vec();
+    reply[2] = 0x80 | (query[2] & 0x79); // QR, keeping the opcode and RD bits
+    reply[3] = 0x80 | 3; // RA, RCODE 3 (NXDOMAIN)
+    reply[6..12].fill(0); // no answer, authority or additional records
+    reply
+}
+
+/// Build a reply to an A or AAAA `query` answering it with the `addresses` of that type.
+fn pinned_reply(query: &[u8], question: &Question, addresses: &[IpAddr]) -> Vec<u8> {
+    let records: Vec<Vec<u8>> = addresses
+        .iter()
+        .filter_map(|ip| match (ip, question.record_type) {
+            (IpAddr::V4(ip), TYPE_A) => Some(ip.octets().to_vec()),
+            (IpAddr::V6(ip), TYPE_AAAA) => Some(ip.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+    let mut reply = query[..question.end].to_vec();
+    reply[2] = 0x80 | (query[2] & 0x79); // QR, keeping the opcode and RD bits
+    reply[3] = 0x80; // RA, no error
+    reply[6..12].fill(0);
+    reply[6..8].copy_from_slice(&u16::try_from(records.len()).unwrap_or_default().to_be_bytes());
+    for data in records {
+        reply.extend_from_slice(&[0xC0, 0x0C]); // pointer to the question name
+        reply.extend_from_slice(&question.record_type.to_be_bytes());
+        reply.extend_from_slice(&[0x00, 0x01]); // IN
+        reply.extend_from_slice(&PINNED_TTL.to_be_bytes());
+        reply.extend_from_slice(&u16::try_from(data.len()).unwrap_or_default().to_be_bytes());
+        reply.extend_from_slice(&data);
+    }
+    reply
+}
+
+/// Human-readable name of a DNS record type for audit logs
+fn type_name(record_type: u16) -> String {
+    match record_type {
+        TYPE_A => "A".to_string(),
+        TYPE_AAAA => "AAAA".to_string(),
+        5 => "CNAME".to_string(),
+        TYPE_PTR => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        33 => "SRV".to_string(),
+        65 => "HTTPS".to_string(),
+        other => format!("TYPE{other}"),
+    }
+}
+
+/// The interesting parts of a DNS response
+#[derive(Debug, PartialEq)]
+pub struct Answer {
+    /// The queried name (lowercase, without trailing dot)
+    pub name: String,
+
+    /// Addresses from A and AAAA records in the answer section
+    pub addresses: Vec<IpAddr>,
+
+    /// Whether the resolver validated the answer with DNSSEC (the AD bit)
+    pub authenticated: bool,
+}
+
+/// Parse the question name and A/AAAA records out of a DNS response.
+///
+/// CNAME chains are followed implicitly: every A record in the answer section belongs to
+/// the queried name, whatever intermediate names the chain goes through.
+fn parse_response(message: &[u8]) -> Option<Answer> {
+    let mut r = Reader::new(message);
+    r.skip(2)?; // id
+    let flags = r.u16()?;
+    let question_count = r.u16()?;
+    let answer_count = r.u16()?;
+    r.skip(4)?; // authority and additional counts
+
+    // Must be a response (QR bit) with exactly one question
+    if flags & 0x8000 == 0 || question_count != 1 {
+        return None;
+    }
+
+    let name = read_name(&mut r)?;
+    r.skip(4)?; // qtype, qclass
+
+    let mut addresses = Vec::new();
+    for _ in 0..answer_count {
+        read_name(&mut r)?;
+        let record_type = r.u16()?;
+        r.skip(2 + 4)?; // class, ttl
+        let data_len = usize::from(r.u16()?);
+        let data = r.take(data_len)?;
+        if let Ok(octets) = <[u8; 4]>::try_from(data) {
+            if record_type == TYPE_A {
+                addresses.push(IpAddr::from(octets));
+            }
+        } else if let Ok(octets) = <[u8; 16]>::try_from(data) {
+            if record_type == TYPE_AAAA {
+                addresses.push(IpAddr::from(octets));
+            }
+        }
+    }
+
+    Some(Answer { name, addresses, authenticated: flags & FLAG_AD != 0 })
+}
+
+/// Read a (possibly compressed) domain name, leaving the reader just after it.
+fn read_name(r: &mut Reader) -> Option<String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut resume_at = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = r.u8()?;
+        if len == 0 {
+            break;
+        }
+        if len & 0xC0 == 0xC0 {
+            // Compression pointer: continue reading at the given offset
+            let offset = (usize::from(len & 0x3F) << 8) | usize::from(r.u8()?);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            resume_at.get_or_insert(r.position());
+            r.seek(offset)?;
+            continue;
+        }
+        let label = r.take(usize::from(len))?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+    }
+
+    if let Some(pos) = resume_at {
+        r.seek(pos)?;
+    }
+    Some(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response for `raw.githubusercontent.com` with a CNAME and two A records,
+    /// using compression pointers the way real servers do
+    fn sample_response() -> Vec<u8> {
+        let mut msg = vec![
+            0x12, 0x34, // id
+            0x81, 0x80, // flags: response, recursion desired/available
+            0x00, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+        ];
+        // Question: raw.githubusercontent.com A IN (name at offset 12)
+        for label in ["raw", "githubusercontent", "com"] {
+            msg.push(u8::try_from(label.len()).unwrap());
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+
+        // CNAME raw.githubusercontent.com -> cdn.example.net
+        msg.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x11]);
+        let cname_offset = msg.len();
+        msg.extend_from_slice(&[3, b'c', b'd', b'n', 7]);
+        msg.extend_from_slice(b"example");
+        msg.extend_from_slice(&[3, b'n', b'e', b't', 0]);
+
+        // Two A records for cdn.example.net (pointer to the CNAME target)
+        for last in [1u8, 2] {
+            msg.extend_from_slice(&[0xC0, u8::try_from(cname_offset).unwrap()]);
+            msg.extend_from_slice(&[0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x04]);
+            msg.extend_from_slice(&[185, 199, 108, last]);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_parse_response_follows_cname_chain() {
+        let answer = parse_response(&sample_response()).unwrap();
+        assert_eq!(answer.name, "raw.githubusercontent.com");
+        assert_eq!(
+            answer.addresses,
+            vec![IpAddr::from([185, 199, 108, 1]), IpAddr::from([185, 199, 108, 2])]
+        );
+    }
+
+    #[test]
+    fn test_parse_response_reads_aaaa_records() {
+        let mut msg = vec![0x00, 0x01, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        msg.extend_from_slice(&[6, b'g', b'i', b't', b'h', b'u', b'b', 3, b'c', b'o', b'm', 0]);
+        msg.extend_from_slice(&[0x00, 0x1C, 0x00, 0x01]);
+        msg.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x1C, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x10]);
+        let ip: Ipv6Addr = "2606:50c0:8000::154".parse().unwrap();
+        msg.extend_from_slice(&ip.octets());
+
+        let answer = parse_response(&msg).unwrap();
+        assert_eq!(answer.name, "github.com");
+        assert_eq!(answer.addresses, vec![IpAddr::V6(ip)]);
+    }
+
+    #[test]
+    fn test_parse_response_rejects_queries_and_truncation() {
+        let mut query = sample_response();
+        query[2] &= 0x7F; // clear QR bit
+        assert!(parse_response(&query).is_none());
+
+        let response = sample_response();
+        assert!(parse_response(&response[..response.len() - 2]).is_none());
+    }
+
+    #[test]
+    fn test_address_query_asks_for_dnssec() {
+        let query = address_query("GitHub.com.", TYPE_AAAA);
+        let question = parse_query(&query).unwrap();
+        assert_eq!(question.name, "github.com");
+        assert_eq!(question.record_type, TYPE_AAAA);
+        assert_eq!(u16::from_be_bytes([query[2], query[3]]) & FLAG_AD, FLAG_AD);
+        // The OPT record follows the question and has the DO bit set
+        assert_eq!(query[question.end..question.end + 3], [0x00, 0x00, 0x29]);
+        assert_eq!(query[question.end + 7], 0x80);
+
+        let mut response = sample_response();
+        assert!(!parse_response(&response).unwrap().authenticated);
+        response[3] |= 0x20;
+        assert!(parse_response(&response).unwrap().authenticated);
+    }
+
+    /// A client query for `name` with an EDNS OPT record in the additional section
+    fn query(name: &str) -> Vec<u8> {
+        let mut msg = vec![0xAB, 0xCD, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+        for label in name.split('.') {
+            msg.push(u8::try_from(label.len()).unwrap());
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x01]); // TXT IN
+        msg.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0, 0, 0, 0, 0x00, 0x00]);
+        msg
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let question = parse_query(&query("Secret-Data.Attacker.com")).unwrap();
+        assert_eq!(question.name, "secret-data.attacker.com");
+        assert_eq!(type_name(question.record_type), "TXT");
+        assert_eq!(question.end, 12 + 26 + 4);
+
+        assert!(parse_query(&sample_response()).is_none());
+    }
+
+    #[test]
+    fn test_nxdomain_reply() {
+        let query = query("attacker.com");
+        let question = parse_query(&query).unwrap();
+        let reply = nxdomain(&query, question.end);
+
+        assert_eq!(reply[..2], [0xAB, 0xCD]);
+        assert_eq!(reply[2], 0x81); // response, recursion desired
+        assert_eq!(reply[3] & 0x0F, 3); // NXDOMAIN
+        assert_eq!(reply[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reply.len(), question.end);
+    }
+
+    #[test]
+    fn test_screen_denylist_overrides_allowlist() {
+        let pins_file =
+            std::env::temp_dir().join(format!("rustyolo-dns-test-pins-{}", std::process::id()));
+        let pinned = crate::resolve::parse_pins("github.com=140.82.112.3 git.github.com=").unwrap();
+        let forwarder = Forwarder {
+            pinned: Mutex::new(Pinned { names: pinned.clone(), modified: None }),
+            config: ForwarderConfig {
+                upstream: Vec::new(),
+                allowlist: crate::domains::parse_list("github.com .github.com").unwrap(),
+                denylist: crate::domains::parse_patterns("gist.github.com").unwrap(),
+                pinned,
+                pins_file: Some(pins_file.clone()),
+                update_firewall: false,
+                ipv6: false,
+                blocklist: Blocklist::new(Vec::new()),
+                quotas: Quotas::default(),
+                learn: None,
+                approval: None,
+                audit: AuditLevel::None,
+            },
+            allowed: Mutex::new(Opened::default()),
+            counted: Mutex::new(HashSet::new()),
+        };
+
+        assert!(forwarder.screen(&query("api.github.com")).is_ok());
+        assert!(matches!(forwarder.screen(&query("gist.github.com")), Err(Some(_))));
+        assert!(matches!(forwarder.screen(&query("attacker.com")), Err(Some(_))));
+
+        // Pinned names are answered from the pin; other record types are forwarded
+        assert!(forwarder.screen(&query("github.com")).is_ok());
+        let mut a_query = query("github.com");
+        a_query[12 + 12 + 1] = 0x01; // A instead of TXT
+        let Err(Some(reply)) = forwarder.screen(&a_query) else {
+            panic!("github.com was not answered from the pin");
+        };
+        let answer = parse_response(&reply).unwrap();
+        assert_eq!(answer.addresses, vec![IpAddr::from([140, 82, 112, 3])]);
+        assert_eq!(reply[3] & 0x0F, 0);
+
+        // A name the host could not pin stays blocked
+        let Err(Some(reply)) = forwarder.screen(&query("git.github.com")) else {
+            panic!("git.github.com was forwarded");
+        };
+        assert_eq!(reply[3] & 0x0F, 3);
+
+        // Pins the refresher checked again replace the initial ones
+        fs::write(&pins_file, "github.com=140.82.112.4 git.github.com=140.82.112.5").unwrap();
+        let Err(Some(reply)) = forwarder.screen(&a_query) else {
+            panic!("github.com was not answered from the pin");
+        };
+        let answer = parse_response(&reply).unwrap();
+        assert_eq!(answer.addresses, vec![IpAddr::from([140, 82, 112, 4])]);
+        assert_eq!(forwarder.pinned("git.github.com"), Some(vec![IpAddr::from([140, 82, 112, 5])]));
+        fs::remove_file(&pins_file).unwrap();
+    }
+
+    #[test]
+    fn test_once_approvals_expire() {
+        let now = Instant::now();
+        let later = now + approval::ONCE_WINDOW;
+        let ip = IpAddr::from([93, 184, 216, 34]);
+        let mut opened = Opened::default();
+
+        assert!(opened.open((ip, None), Some(later)));
+        assert!(!opened.open((ip, None), Some(now)));
+        assert!(opened.expire(now).is_empty());
+        assert_eq!(opened.expire(later), vec![(ip, None)]);
+        assert!(opened.open((ip, None), Some(later)));
+
+        // A rule the allowlist needs as well stays for the session
+        assert!(!opened.open((ip, None), None));
+        assert!(opened.expire(later + approval::ONCE_WINDOW).is_empty());
+        assert!(!opened.open((ip, None), Some(later)));
+        assert!(opened.expire(later + approval::ONCE_WINDOW).is_empty());
+    }
+
+    #[test]
+    fn test_lookup_ignores_short_tcp_responses() {
+        // A server that truncates over UDP, then answers over TCP with a single byte
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(server).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, client) = udp.recv_from(&mut buf).unwrap();
+            buf[2] |= 0x82; // QR, TC
+            udp.send_to(&buf[..len], client).unwrap();
+            let (mut stream, _) = tcp.accept().unwrap();
+            read_tcp_message(&mut stream).unwrap();
+            write_tcp_message(&mut stream, &[0xAB]).unwrap();
+        });
+        assert_eq!(lookup(&server, "github.com", TYPE_A), None);
+    }
+
+    #[test]
+    fn test_ptr_query_and_response() {
+        let query = ptr_query("140.82.112.3".parse().unwrap());
+        assert!(query.windows(13).any(|w| w == b"\x07in-addr\x04arpa"));
+        assert_eq!(
+            reverse_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+
+        // Response: the query plus one PTR answer pointing back at the question name
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x0C, 0x00, 0x01, 0, 0, 0, 60, 0, 12]);
+        response.extend_from_slice(b"\x06github\x03com\x00");
+        assert_eq!(parse_ptr_response(&response).as_deref(), Some("github.com"));
+        assert_eq!(parse_ptr_response(&query), None);
+    }
+
+    #[test]
+    fn test_read_name_rejects_pointer_loops() {
+        // Header followed by a name that points at itself
+        let mut msg = vec![0u8; 12];
+        msg.extend_from_slice(&[0xC0, 0x0C]);
+        let mut r = Reader::new(&msg);
+        r.seek(12).unwrap();
+        assert!(read_name(&mut r).is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+
+/// A trusted domain entry from `allow_domains`.
+///
+/// Three forms are supported:
+/// - `github.com` - exactly this name
+/// - `*.githubusercontent.com` - any subdomain, but not the domain itself
+/// - `.githubusercontent.com` - the domain itself and any subdomain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainPattern {
+    /// Exact name match
+    Exact(String),
+
+    /// Any subdomain of the base domain
+    Wildcard(String),
+
+    /// The base domain or any of its subdomains
+    Suffix(String),
+}
+
+impl DomainPattern {
+    /// Parse and validate a single `allow_domains` entry.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(DomainPattern)` - The normalized (lowercase) pattern
+    /// * `Err(String)` - Why the entry was rejected
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let pattern = DomainPattern::parse("*.githubusercontent.com")?;
+    /// assert!(pattern.matches("raw.githubusercontent.com"));
+    /// ```
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        let normalized = entry.trim_end_matches('.').to_lowercase();
+
+        let (pattern, base) = if let Some(base) = normalized.strip_prefix("*.") {
+            (Self::Wildcard(base.to_string()), base)
+        } else if let Some(base) = normalized.strip_prefix('.') {
+            (Self::Suffix(base.to_string()), base)
+        } else {
+            (Self::Exact(normalized.clone()), normalized.as_str())
+        };
+
+        validate_name(base).map_err(|reason| format!("Invalid domain '{entry}': {reason}"))?;
+
+        // Refuse patterns that would cover an entire top-level domain (e.g. "*.com")
+        if !matches!(pattern, Self::Exact(_)) && !base.contains('.') {
+            return Err(format!(
+                "Invalid domain '{entry}': wildcard patterns must name at least a second-level domain"
+            ));
+        }
+
+        Ok(pattern)
+    }
+
+    /// Check whether a hostname matches this pattern (case-insensitive, trailing dot ignored).
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        match self {
+            Self::Exact(domain) => name == *domain,
+            Self::Wildcard(base) => is_subdomain(&name, base),
+            Self::Suffix(base) => name == *base || is_subdomain(&name, base),
+        }
+    }
+
+    /// Whether this is a plain domain name rather than a wildcard or suffix pattern
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Self::Exact(_))
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exact(domain) => write!(f, "{domain}"),
+            Self::Wildcard(base) => write!(f, "*.{base}"),
+            Self::Suffix(base) => write!(f, ".{base}"),
+        }
+    }
+}
+
+/// Transport protocol of a port-scoped allow entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// The protocol name as understood by iptables (`-p tcp`)
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+}
+
+/// A single destination port that an allow entry is limited to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Port {
+    pub number: u16,
+    pub protocol: Protocol,
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.number, self.protocol.as_str())
+    }
+}
+
+/// Ports that portless entries are limited to in strict mode
+pub const WEB_PORTS: [Port; 2] = [
+    Port { number: 80, protocol: Protocol::Tcp },
+    Port { number: 443, protocol: Protocol::Tcp },
+];
+
+/// One `allow_domains` entry: a domain pattern, optionally limited to a single port.
+///
+/// Entries take the form `host`, `host:port` or `host:port/protocol`, where the protocol
+/// is `tcp` (the default) or `udp`. An entry without a port allows every port, unless
+/// it is narrowed with [`restrict_to_web_ports`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowEntry {
+    pub pattern: DomainPattern,
+
+    /// The only port this entry allows, or `None` for all ports
+    pub port: Option<Port>,
+}
+
+impl AllowEntry {
+    /// Parse and validate a single `allow_domains` entry.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let entry = AllowEntry::parse("git.internal:22")?;
+    /// assert!(entry.allows("git.internal", 22, Protocol::Tcp));
+    /// assert!(!entry.allows("git.internal", 443, Protocol::Tcp));
+    /// ```
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        let Some((host, port)) = entry.split_once(':') else {
+            return Ok(Self { pattern: DomainPattern::parse(entry)?, port: None });
+        };
+
+        let (number, protocol) = match port.split_once('/') {
+            Some((number, "tcp")) => (number, Protocol::Tcp),
+            Some((number, "udp")) => (number, Protocol::Udp),
+            Some((_, other)) => {
+                return Err(format!(
+                    "Invalid entry '{entry}': unknown protocol '{other}' (use tcp or udp)"
+                ))
+            }
+            None => (port, Protocol::Tcp),
+        };
+        let number =
+            number.parse::<u16>().ok().filter(|n| *n != 0).ok_or_else(|| {
+                format!("Invalid entry '{entry}': port must be between 1 and 65535")
+            })?;
+
+        Ok(Self { pattern: DomainPattern::parse(host)?, port: Some(Port { number, protocol }) })
+    }
+
+    /// Check whether this entry allows a connection to `host` on `port`.
+    pub fn allows(&self, host: &str, port: u16, protocol: Protocol) -> bool {
+        self.pattern.matches(host)
+            && self.port.is_none_or(|p| p.number == port && p.protocol == protocol)
+    }
+}
+
+impl fmt::Display for AllowEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{port}", self.pattern),
+            None => write!(f, "{}", self.pattern),
+        }
+    }
+}
+
+/// Parse a space-separated `allow_domains` list.
+pub fn parse_list(list: &str) -> Result<Vec<AllowEntry>, String> {
+    list.split_whitespace().map(AllowEntry::parse).collect()
+}
+
+/// Parse a space-separated `deny_domains` list.
+///
+/// Deny entries name hosts only; they apply to every port.
+pub fn parse_patterns(list: &str) -> Result<Vec<DomainPattern>, String> {
+    list.split_whitespace().map(DomainPattern::parse).collect()
+}
+
+/// Limit entries without a port to [`WEB_PORTS`] (strict mode).
+pub fn restrict_to_web_ports(entries: Vec<AllowEntry>) -> Vec<AllowEntry> {
+    entries
+        .into_iter()
+        .flat_map(|entry| match entry.port {
+            Some(_) => vec![entry],
+            None => WEB_PORTS
+                .iter()
+                .map(|port| AllowEntry { pattern: entry.pattern.clone(), port: Some(*port) })
+                .collect(),
+        })
+        .collect()
+}
+
+fn is_subdomain(name: &str, base: &str) -> bool {
+    name.strip_suffix(base)
+        .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.'))
+}
+
+/// Validate a DNS name: dot-separated labels of letters, digits, hyphens and underscores.
+fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() {
+        return Err("empty name");
+    }
+    if name.len() > 253 {
+        return Err("name is longer than 253 characters");
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err("each label must be 1-63 characters");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err("labels cannot start or end with a hyphen");
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("only letters, digits, hyphens and underscores are allowed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_forms() {
+        assert_eq!(
+            DomainPattern::parse("GitHub.com").unwrap(),
+            DomainPattern::Exact("github.com".to_string())
+        );
+        assert_eq!(
+            DomainPattern::parse("*.githubusercontent.com").unwrap(),
+            DomainPattern::Wildcard("githubusercontent.com".to_string())
+        );
+        assert_eq!(
+            DomainPattern::parse(".s3.amazonaws.com").unwrap(),
+            DomainPattern::Suffix("s3.amazonaws.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_entries() {
+        assert!(DomainPattern::parse("*").is_err());
+        assert!(DomainPattern::parse("*.com").is_err());
+        assert!(DomainPattern::parse("foo.*.com").is_err());
+        assert!(DomainPattern::parse("github.com;rm -rf /").is_err());
+        assert!(DomainPattern::parse("-bad.com").is_err());
+        assert!(DomainPattern::parse("a..b").is_err());
+    }
+
+    #[test]
+    fn test_wildcard_matching() {
+        let pattern = DomainPattern::parse("*.githubusercontent.com").unwrap();
+        assert!(pattern.matches("raw.githubusercontent.com"));
+        assert!(pattern.matches("a.b.githubusercontent.com."));
+        assert!(!pattern.matches("githubusercontent.com"));
+        assert!(!pattern.matches("evilgithubusercontent.com"));
+    }
+
+    #[test]
+    fn test_suffix_matching() {
+        let pattern = DomainPattern::parse(".amazonaws.com").unwrap();
+        assert!(pattern.matches("amazonaws.com"));
+        assert!(pattern.matches("bucket.s3.amazonaws.com"));
+        assert!(!pattern.matches("notamazonaws.com"));
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let list =
+            parse_list("github.com *.githubusercontent.com .pypi.org git.internal:22").unwrap();
+        let rendered: Vec<String> = list.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            rendered,
+            ["github.com", "*.githubusercontent.com", ".pypi.org", "git.internal:22/tcp"]
+        );
+    }
+
+    #[test]
+    fn test_parse_port_scoped_entries() {
+        let entry = AllowEntry::parse("pypi.org:443/tcp").unwrap();
+        assert_eq!(entry.port, Some(Port { number: 443, protocol: Protocol::Tcp }));
+        assert_eq!(entry, AllowEntry::parse("pypi.org:443").unwrap());
+
+        let entry = AllowEntry::parse("ntp.example.com:123/udp").unwrap();
+        assert!(entry.allows("ntp.example.com", 123, Protocol::Udp));
+        assert!(!entry.allows("ntp.example.com", 123, Protocol::Tcp));
+
+        assert!(AllowEntry::parse("github.com:0").is_err());
+        assert!(AllowEntry::parse("github.com:99999").is_err());
+        assert!(AllowEntry::parse("github.com:443/sctp").is_err());
+        assert!(AllowEntry::parse("github.com:").is_err());
+    }
+
+    #[test]
+    fn test_restrict_to_web_ports() {
+        let list = restrict_to_web_ports(parse_list("github.com git.internal:22").unwrap());
+        let rendered: Vec<String> = list.iter().map(ToString::to_string).collect();
+        assert_eq!(rendered, ["github.com:80/tcp", "github.com:443/tcp", "git.internal:22/tcp"]);
+    }
+
+    #[test]
+    fn test_parse_patterns_rejects_ports() {
+        let patterns = parse_patterns("gist.github.com .uploads.github.com").unwrap();
+        assert!(patterns[1].matches("uploads.github.com"));
+        assert!(parse_patterns("gist.github.com:443").is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Per-session egress byte quotas.
+//!
+//! Even with a strict allowlist, a compromised agent can upload a whole repository to an
+//! allowed host. Quotas bound how much can leave the sandbox: `max_egress` caps all
+//! outbound traffic and per-domain quotas cap traffic to the addresses of matching names.
+//!
+//! Enforcement happens in the firewall with the iptables `quota` match (see
+//! [`crate::firewall`]), separately for IPv4 and IPv6. Inside the container, [`monitor`]
+//! periodically writes the byte counters to a directory mounted from the host. On the
+//! host, a [`Session`] watches that file, stops the container once the combined usage
+//! reaches a quota, and prints the per-domain byte counts when the container exits.
+
+use crate::domains::DomainPattern;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Where the usage directory is mounted inside the container
+pub const STATS_DIR: &str = "/run/rustyolo/egress";
+
+/// Name of the usage file inside the usage directory
+const USAGE_FILE: &str = "usage";
+
+/// How often the counters are written and checked
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A quota on the traffic to the addresses of names matching `pattern`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainQuota {
+    pub pattern: DomainPattern,
+    pub limit: u64,
+}
+
+/// The egress quotas of a session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quotas {
+    /// Limit on all outbound traffic, in bytes
+    pub total: Option<u64>,
+
+    /// Per-domain limits, in bytes; traffic is charged to the first matching pattern
+    pub domains: Vec<DomainQuota>,
+}
+
+impl Quotas {
+    /// Parse `max_egress` and `domain=size` quota entries.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let quotas = Quotas::parse(Some("200MB"), &["github.com=50MB".to_string()])?;
+    /// assert_eq!(quotas.total, Some(200_000_000));
+    /// ```
+    pub fn parse(total: Option<&str>, domains: &[String]) -> Result<Self, String> {
+        let total = total
+            .filter(|size| !size.trim().is_empty())
+            .map(|size| parse_size(size).map_err(|e| format!("Invalid max_egress: {e}")))
+            .transpose()?;
+
+        let domains = domains
+            .iter()
+            .flat_map(|list| list.split_whitespace())
+            .map(|entry| {
+                let (pattern, size) = entry.split_once('=').ok_or_else(|| {
+                    format!(
+                        "Invalid egress quota '{entry}': expected domain=size (github.com=50MB)"
+                    )
+                })?;
+                Ok(DomainQuota {
+                    pattern: DomainPattern::parse(pattern)
+                        .map_err(|e| format!("Invalid egress quota '{entry}': {e}"))?,
+                    limit: parse_size(size)
+                        .map_err(|e| format!("Invalid egress quota '{entry}': {e}"))?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self { total, domains })
+    }
+
+    /// Whether no quota is configured (egress is then not counted at all)
+    pub fn is_empty(&self) -> bool {
+        self.total.is_none() && self.domains.is_empty()
+    }
+
+    /// Index of the per-domain quota that traffic to `name` is charged to
+    pub fn quota_for(&self, name: &str) -> Option<usize> {
+        self.domains.iter().position(|quota| quota.pattern.matches(name))
+    }
+
+    /// The quotas in the form the in-sandbox helpers read from `EGRESS_QUOTAS`
+    pub fn domains_env(&self) -> String {
+        let entries: Vec<String> =
+            self.domains.iter().map(|q| format!("{}={}", q.pattern, q.limit)).collect();
+        entries.join(" ")
+    }
+
+    /// Describe the first quota that `usage` has reached, if any
+    pub fn exceeded(&self, usage: &Usage) -> Option<String> {
+        if let Some(limit) = self.total.filter(|limit| usage.total >= *limit) {
+            return Some(format!(
+                "{} sent in total (max_egress is {})",
+                format_size(usage.total),
+                format_size(limit)
+            ));
+        }
+        self.domains.iter().find_map(|quota| {
+            let used = usage.matching(&quota.pattern);
+            (used >= quota.limit).then(|| {
+                format!(
+                    "{} sent to {} (quota is {})",
+                    format_size(used),
+                    quota.pattern,
+                    format_size(quota.limit)
+                )
+            })
+        })
+    }
+}
+
+/// Parse a byte size such as `200MB`, `1GiB` or `500000`.
+///
+/// `KB`/`MB`/`GB` (and `K`/`M`/`G`) are powers of 1000, `KiB`/`MiB`/`GiB` powers of 1024.
+/// Units are case-insensitive.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(format!("'{size}' is not a size (use e.g. 200MB, 1GB or 512MiB)")),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("'{size}' is not a size (use e.g. 200MB, 1GB or 512MiB)"))
+}
+
+/// Format a byte count for display, e.g. `12.3 MB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1_000_000_000, "GB"), (1_000_000, "MB"), (1_000, "KB")];
+    for (scale, unit) in UNITS {
+        if bytes >= scale {
+            return format!("{}.{} {unit}", bytes / scale, bytes % scale * 10 / scale);
+        }
+    }
+    format!("{bytes} B")
+}
+
+/// Byte counters of a session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// All outbound traffic, except loopback
+    pub total: u64,
+
+    /// Traffic to the addresses of each resolved trusted name
+    pub domains: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    /// Read the counters from `iptables-save -c` output.
+    ///
+    /// The total is the counter of the `OUTPUT` rule jumping to the egress chain; per-domain
+    /// counts are the counters of the accounting rules, which carry the name as a comment.
+    pub fn from_iptables_save(output: &str) -> Self {
+        let mut usage = Self::default();
+        for line in output.lines() {
+            let Some((counters, rule)) = line.strip_prefix('[').and_then(|l| l.split_once("] "))
+            else {
+                continue;
+            };
+            let Some(bytes) = counters.split_once(':').and_then(|(_, b)| b.parse::<u64>().ok())
+            else {
+                continue;
+            };
+
+            let words: Vec<&str> = rule.split_whitespace().collect();
+            if words == ["-A", "OUTPUT", "-j", crate::firewall::EGRESS_CHAIN] {
+                usage.total += bytes;
+            } else if words.get(1) == Some(&crate::firewall::ACCOUNT_CHAIN) {
+                if let Some(i) = words.iter().position(|w| *w == "--comment") {
+                    if let Some(name) = words.get(i + 1) {
+                        *usage.domains.entry(name.trim_matches('"').to_string()).or_default() +=
+                            bytes;
+                    }
+                }
+            }
+        }
+        usage
+    }
+
+    /// Add the counters of another address family
+    pub fn add(&mut self, other: &Self) {
+        self.total += other.total;
+        for (name, bytes) in &other.domains {
+            *self.domains.entry(name.clone()).or_default() += bytes;
+        }
+    }
+
+    /// Bytes sent to names matching `pattern`
+    pub fn matching(&self, pattern: &DomainPattern) -> u64 {
+        self.domains
+            .iter()
+            .filter(|(name, _)| pattern.matches(name))
+            .map(|(_, b)| b)
+            .sum()
+    }
+
+    fn render(&self) -> String {
+        let mut lines = vec![format!("total {}", self.total)];
+        lines.extend(self.domains.iter().map(|(name, bytes)| format!("{name} {bytes}")));
+        lines.join("\n") + "\n"
+    }
+
+    fn parse(content: &str) -> Option<Self> {
+        let mut lines = content.lines();
+        let total = lines.next()?.strip_prefix("total ")?.parse().ok()?;
+        let domains = lines
+            .map(|line| {
+                let (name, bytes) = line.split_once(' ')?;
+                Some((name.to_string(), bytes.parse().ok()?))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self { total, domains })
+    }
+}
+
+/// Write the egress counters to `stats_dir` every second, until the process is killed.
+///
+/// Runs inside the sandbox as root, next to the other helpers.
+pub fn monitor(stats_dir: &Path, ipv6: bool) -> Result<(), Box<dyn Error>> {
+    let programs: &[&str] = if ipv6 {
+        &["iptables-save", "ip6tables-save"]
+    } else {
+        &["iptables-save"]
+    };
+    let usage_file = stats_dir.join(USAGE_FILE);
+    let temp_file = stats_dir.join(format!("{USAGE_FILE}.tmp"));
+
+    loop {
+        let mut usage = Usage::default();
+        for program in programs {
+            let output = Command::new(program)
+                .args(["-c", "-t", "filter"])
+                .output()
+                .map_err(|e| format!("Failed to run {program}: {e}"))?;
+            usage.add(&Usage::from_iptables_save(&String::from_utf8_lossy(&output.stdout)));
+        }
+
+        // Replace the file atomically so the host never reads a partial update
+        fs::write(&temp_file, usage.render())
+            .and_then(|()| fs::rename(&temp_file, &usage_file))
+            .map_err(|e| format!("Failed to write {}: {e}", usage_file.display()))?;
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Host-side state of a session with egress quotas
+pub struct Session {
+    quotas: Quotas,
+
+    /// Private temporary directory holding the usage directory and the container ID file
+    dir: PathBuf,
+
+    stop: Arc<AtomicBool>,
+    watcher: Option<JoinHandle<bool>>,
+}
+
+impl Session {
+    /// Create the temporary directories for a session.
+    ///
+    /// The usage directory must be writable by the container's root user, which has no
+    /// `CAP_DAC_OVERRIDE`, so it is world-writable; it sits inside a private directory so
+    /// other users on the host cannot reach it.
+    pub fn create(quotas: Quotas) -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("rustyolo-egress-{}", std::process::id()));
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        let stats_dir = dir.join("stats");
+        fs::create_dir(&stats_dir)?;
+        fs::set_permissions(&stats_dir, fs::Permissions::from_mode(0o777))?;
+
+        Ok(Self { quotas, dir, stop: Arc::new(AtomicBool::new(false)), watcher: None })
+    }
+
+    /// The directory to mount at [`STATS_DIR`]
+    pub fn stats_dir(&self) -> PathBuf {
+        self.dir.join("stats")
+    }
+
+    /// The file Docker writes the container ID to (`docker run --cidfile`)
+    pub fn cidfile(&self) -> PathBuf {
+        self.dir.join("container.id")
+    }
+
+    /// Watch the usage in the background and stop the container once a quota is reached.
+    pub fn watch(&mut self) {
+        let quotas = self.quotas.clone();
+        let usage_file = self.stats_dir().join(USAGE_FILE);
+        let cidfile = self.cidfile();
+        let stop = Arc::clone(&self.stop);
+
+        self.watcher = Some(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL);
+                let Some(usage) = read_usage(&usage_file) else {
+                    continue;
+                };
+                if let Some(reason) = quotas.exceeded(&usage) {
+                    eprintln!("\r\n[RustyYOLO] ❌ Egress quota exceeded: {reason}.\r");
+                    eprintln!("[RustyYOLO] Stopping the session.\r");
+                    match fs::read_to_string(&cidfile) {
+                        Ok(id) => {
+                            let _ = Command::new("docker")
+                                .args(["kill", id.trim()])
+                                .stdout(std::process::Stdio::null())
+                                .status();
+                        }
+                        Err(e) => eprintln!(
+                            "[RustyYOLO] ⚠️  Could not read the container ID: {e}. \
+                             Outbound traffic stays blocked by the firewall.\r"
+                        ),
+                    }
+                    return true;
+                }
+            }
+            false
+        }));
+    }
+
+    /// Stop watching, print the egress summary and remove the temporary files.
+    ///
+    /// # Returns
+    ///
+    /// Whether the session was stopped because a quota was exceeded
+    pub fn finish(mut self) -> bool {
+        self.stop.store(true, Ordering::Relaxed);
+        let exceeded = self.watcher.take().is_some_and(|w| w.join().unwrap_or(false));
+
+        if let Some(usage) = read_usage(&self.stats_dir().join(USAGE_FILE)) {
+            self.report(&usage);
+        }
+        exceeded
+    }
+
+    fn report(&self, usage: &Usage) {
+        let limit = self.quotas.total.map(|l| format!(" (max_egress {})", format_size(l)));
+        println!(
+            "[RustyYOLO] Egress: {} sent{}",
+            format_size(usage.total),
+            limit.unwrap_or_default()
+        );
+
+        let mut domains: Vec<(&String, &u64)> = usage.domains.iter().collect();
+        domains.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let width = domains.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for (name, bytes) in domains {
+            println!("[RustyYOLO]   {name:<width$}  {}", format_size(*bytes));
+        }
+        for quota in &self.quotas.domains {
+            println!(
+                "[RustyYOLO]   quota {}: {} of {}",
+                quota.pattern,
+                format_size(usage.matching(&quota.pattern)),
+                format_size(quota.limit)
+            );
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn read_usage(path: &Path) -> Option<Usage> {
+    Usage::parse(&fs::read_to_string(path).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("200MB"), Ok(200_000_000));
+        assert_eq!(parse_size("200m"), Ok(200_000_000));
+        assert_eq!(parse_size("1 GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("0MB").is_err());
+        assert!(parse_size("1.5GB").is_err());
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("99999999999GB").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1_234_567), "1.2 MB");
+        assert_eq!(format_size(200_000_000), "200.0 MB");
+    }
+
+    #[test]
+    fn test_parse_quotas() {
+        let quotas =
+            Quotas::parse(Some("200MB"), &["github.com=50MB .npmjs.org=1GB".to_string()]).unwrap();
+        assert_eq!(quotas.total, Some(200_000_000));
+        assert_eq!(quotas.quota_for("registry.npmjs.org"), Some(1));
+        assert_eq!(quotas.quota_for("gist.github.com"), None);
+        assert_eq!(quotas.domains_env(), "github.com=50000000 .npmjs.org=1000000000");
+        assert_eq!(Quotas::parse(None, &[quotas.domains_env()]).unwrap().domains, quotas.domains);
+
+        assert!(Quotas::parse(None, &[]).unwrap().is_empty());
+        assert!(Quotas::parse(None, &["github.com".to_string()]).is_err());
+        assert!(Quotas::parse(None, &["github.com:443=5MB".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_usage_from_iptables_save() {
+        let output = "\
+# Generated by iptables-save
+*filter
+:OUTPUT DROP [120:45000]
+:RUSTYOLO-ACCOUNT - [0:0]
+[3:180] -A OUTPUT -o lo -j ACCEPT
+[100:40000] -A OUTPUT -j RUSTYOLO-EGRESS
+[10:20000] -A RUSTYOLO-ACCOUNT -d 140.82.112.3/32 -m comment --comment github.com -j RUSTYOLO-QUOTA-0
+[5:1000] -A RUSTYOLO-ACCOUNT -d 140.82.112.4/32 -m comment --comment github.com -j RUSTYOLO-QUOTA-0
+[7:700] -A RUSTYOLO-ACCOUNT -d 151.101.0.223/32 -m comment --comment \"pypi.org\"
+COMMIT
+";
+        let usage = Usage::from_iptables_save(output);
+        assert_eq!(usage.total, 40000);
+        assert_eq!(usage.domains["github.com"], 21000);
+        assert_eq!(usage.domains["pypi.org"], 700);
+        assert_eq!(Usage::parse(&usage.render()), Some(usage));
+    }
+
+    #[test]
+    fn test_exceeded_combines_families() {
+        let quotas = Quotas::parse(Some("1MB"), &["*.github.com=10KB".to_string()]).unwrap();
+        let mut usage = Usage { total: 600_000, domains: BTreeMap::new() };
+        assert_eq!(quotas.exceeded(&usage), None);
+
+        usage.add(&Usage { total: 600_000, domains: BTreeMap::new() });
+        assert_eq!(
+            quotas.exceeded(&usage).as_deref(),
+            Some("1.2 MB sent in total (max_egress is 1.0 MB)")
+        );
+
+        let mut usage = Usage::default();
+        usage.domains.insert("api.github.com".into(), 6_000);
+        usage.domains.insert("codeload.github.com".into(), 6_000);
+        usage.domains.insert("pypi.org".into(), 60_000);
+        assert_eq!(
+            quotas.exceeded(&usage).as_deref(),
+            Some("12.0 KB sent to *.github.com (quota is 10.0 KB)")
+        );
+    }
+}
@@ -0,0 +1,787 @@
+//! The sandbox firewall.
+//!
+//! The static ruleset is generated on the host from the parsed configuration and handed
+//! to the container, which applies it atomically with `iptables-restore` before the agent
+//! starts. The in-container helpers then use the runtime functions below to add rules for
+//! resolved addresses to dedicated chains that the ruleset jumps to before the final audit
+//! LOG rule. IPv4 rules go through `iptables` and IPv6 rules through `ip6tables`.
+//!
+//! Private, loopback, link-local and metadata ranges ([`crate::cidr::BLOCKED_RANGES`]) are
+//! dropped before any rule for a trusted domain is evaluated, so a trusted name that
+//! resolves into them (DNS rebinding) cannot reach internal services.
+//!
+//! On the session's own Docker network ([`crate::network`]), traffic to the network's subnet
+//! goes through [`SESSION_CHAIN`], which only lets it reach the declared sidecars.
+//!
+//! With egress quotas ([`crate::egress`]), all outbound traffic except loopback first
+//! passes through [`EGRESS_CHAIN`], which counts it and rejects it once a quota is used up.
+
+use crate::audit::AuditLevel;
+use crate::cidr::{Blocklist, Cidr};
+use crate::domains::{AllowEntry, DomainPattern, Port, Protocol};
+use crate::egress::Quotas;
+use crate::learn;
+use crate::network::Addresses;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::process::Command;
+
+/// Chain holding rules for the resolved IPs of trusted domains (managed by the refresher)
+pub const TRUSTED_CHAIN: &str = "RUSTYOLO-TRUSTED";
+
+/// Chain holding rules learned from DNS answers for wildcard/suffix patterns
+pub const DNS_CHAIN: &str = "RUSTYOLO-DNS";
+
+/// Chain dropping traffic to the blocked private/link-local/metadata ranges
+pub const BLOCKED_CHAIN: &str = "RUSTYOLO-BLOCKED";
+
+/// Chain holding rules for `host_ports` on the Docker host's gateway IP
+pub const HOST_CHAIN: &str = "RUSTYOLO-HOST";
+
+/// Chain dropping traffic to other containers on the session network, except sidecars
+pub const SESSION_CHAIN: &str = "RUSTYOLO-SESSION";
+
+/// Chain that counts all outbound traffic and enforces `max_egress`
+pub const EGRESS_CHAIN: &str = "RUSTYOLO-EGRESS";
+
+/// Chain holding per-name accounting rules for resolved addresses (filled at runtime)
+pub const ACCOUNT_CHAIN: &str = "RUSTYOLO-ACCOUNT";
+
+/// Name that `--add-host <name>:host-gateway` maps to the Docker host's gateway IP
+pub const HOST_GATEWAY: &str = "host.docker.internal";
+
+/// User the egress proxy runs as in proxy mode
+pub const PROXY_USER: &str = "rustyolo-proxy";
+
+/// Port the egress proxy listens on in proxy mode (see `EGRESS_PROXY_ADDR`)
+pub const PROXY_PORT: u16 = 3128;
+
+/// Docker's embedded DNS server, which `/etc/resolv.conf` names on user-defined networks
+pub const EMBEDDED_DNS: &str = "127.0.0.11";
+
+/// How outbound traffic is filtered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// iptables rules for the IPs that trusted domains resolve to
+    Firewall,
+
+    /// Only the egress proxy may connect out; it checks TLS SNI and HTTP Host
+    Proxy,
+}
+
+impl NetworkMode {
+    /// Parse a network mode from its configuration string (case-insensitive).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "firewall" => Some(Self::Firewall),
+            "proxy" => Some(Self::Proxy),
+            _ => None,
+        }
+    }
+}
+
+/// Which DNS servers the sandbox may query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsServers {
+    /// Any server (no DNS restrictions)
+    Any,
+
+    /// Only these servers, and only from the in-sandbox DNS forwarder
+    Only(Vec<IpAddr>),
+}
+
+/// Everything the static ruleset depends on
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub mode: NetworkMode,
+    pub dns_servers: DnsServers,
+
+    /// Trusted domains; their addresses are added at runtime inside the sandbox
+    pub domains: Vec<AllowEntry>,
+
+    /// Denied domains; they override `domains` and are enforced by name inside the sandbox
+    pub deny: Vec<DomainPattern>,
+
+    /// IP ranges allowed directly
+    pub cidrs: Vec<Cidr>,
+
+    /// TCP ports on the Docker host that may be reached through [`HOST_GATEWAY`]
+    pub host_ports: Vec<u16>,
+
+    /// Ranges that trusted domains may not resolve into, with their `allow_private` exemptions
+    pub blocklist: Blocklist,
+
+    /// The session network's subnets and sidecars (empty if it has not been created)
+    pub network: Addresses,
+
+    /// Egress byte quotas; outbound traffic is only counted when one is set
+    pub quotas: Quotas,
+
+    /// Addresses of the corporate proxy that the egress proxy tunnels through; when set, the
+    /// egress proxy may only connect to these
+    pub upstream_proxy: Vec<SocketAddr>,
+
+    /// Learn mode: remember the destinations of dropped packets in an iptables `recent` table
+    pub learn: bool,
+
+    pub audit: AuditLevel,
+}
+
+/// IP version of a ruleset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn includes(self, ip: IpAddr) -> bool {
+        ip.is_ipv6() == (self == Self::V6)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::V4 => "IPv4",
+            Self::V6 => "IPv6",
+        }
+    }
+}
+
+/// Generate the static ruleset for one address family, in `iptables-restore` format.
+///
+/// The ruleset covers the `filter` table (and, in proxy mode, the IPv4 `nat` table) and is
+/// applied in a single `iptables-restore` transaction, so the sandbox is never partially
+/// firewalled.
+///
+/// # Examples
+///
+/// ```no_run
+/// let rules = firewall::ruleset(&policy, Family::V4);
+/// // Inside the container: iptables-restore < rules
+/// ```
+pub fn ruleset(policy: &Policy, family: Family) -> String {
+    let mut lines = header(policy, family);
+    lines.push("*filter".into());
+    lines.extend(chain_declarations(policy));
+
+    lines.extend(embedded_dns_lines(policy, family));
+    lines.push("-A OUTPUT -o lo -j ACCEPT".into());
+
+    // Count every outbound packet, including those of established connections
+    if !policy.quotas.is_empty() {
+        lines.push(format!("-A OUTPUT -j {EGRESS_CHAIN}"));
+    }
+    lines.push("-A OUTPUT -m state --state RELATED,ESTABLISHED -j ACCEPT".into());
+
+    // IPv6 needs neighbor discovery to reach anything at all
+    if family == Family::V6 {
+        for icmp_type in
+            ["router-solicitation", "neighbour-solicitation", "neighbour-advertisement"]
+        {
+            lines.push(format!("-A OUTPUT -p ipv6-icmp --icmpv6-type {icmp_type} -j ACCEPT"));
+        }
+    }
+
+    // DNS: only the forwarder (running as root) may query the upstream servers
+    match &policy.dns_servers {
+        DnsServers::Any => {
+            for proto in ["udp", "tcp"] {
+                lines.push(format!("-A OUTPUT -p {proto} --dport 53 -j ACCEPT"));
+            }
+        }
+        DnsServers::Only(servers) => {
+            for server in servers.iter().filter(|ip| family.includes(**ip)) {
+                for proto in ["udp", "tcp"] {
+                    let matcher =
+                        format!("-d {server} -p {proto} --dport 53 -m owner --uid-owner 0");
+                    lines.extend(accept_lines(&matcher, "[AUDIT-DNS-ALLOW] ", policy.audit));
+                }
+            }
+        }
+    }
+
+    // Other containers on the session network stay unreachable even if allow_cidrs covers them
+    if !policy.network.subnets.is_empty() {
+        lines.push(format!("-A OUTPUT -j {SESSION_CHAIN}"));
+    }
+
+    for cidr in policy.cidrs.iter().filter(|cidr| cidr.is_ipv6() == (family == Family::V6)) {
+        lines.extend(accept_lines(&format!("-d {cidr}"), "[AUDIT-ALLOW] ", policy.audit));
+    }
+
+    // The corporate proxy usually sits in a private range, so it is accepted before the block
+    for addr in policy.upstream_proxy.iter().filter(|addr| family.includes(addr.ip())) {
+        lines.push(format!(
+            "-A OUTPUT -p tcp -d {} --dport {} -m owner --uid-owner {PROXY_USER} -j ACCEPT",
+            addr.ip(),
+            addr.port()
+        ));
+    }
+
+    // Host services are reached directly (not through the proxy) on the gateway IP only
+    if !policy.host_ports.is_empty() {
+        lines.push(format!("-A OUTPUT -j {HOST_CHAIN}"));
+    }
+
+    // Everything above was configured explicitly; everything below (trusted domains, the
+    // egress proxy) must not reach private ranges
+    lines.push(format!("-A OUTPUT -j {BLOCKED_CHAIN}"));
+
+    match policy.mode {
+        NetworkMode::Firewall => {
+            lines.push(format!("-A OUTPUT -j {TRUSTED_CHAIN}"));
+            lines.push(format!("-A OUTPUT -j {DNS_CHAIN}"));
+        }
+        NetworkMode::Proxy => {
+            // Behind an upstream proxy, the egress proxy only talks to that (accepted above)
+            if policy.upstream_proxy.is_empty() {
+                lines.push(format!(
+                    "-A OUTPUT -p tcp -m owner --uid-owner {PROXY_USER} -m multiport --dports 80,443 -j ACCEPT"
+                ));
+            }
+            // The proxy only listens on IPv4; reject direct IPv6 web traffic so clients
+            // fall back quickly
+            if family == Family::V6 {
+                lines.push(format!(
+                    "-A OUTPUT -p tcp -m owner ! --uid-owner {PROXY_USER} -m multiport --dports 80,443 -j REJECT --reject-with tcp-reset"
+                ));
+            }
+        }
+    }
+
+    if policy.audit.logs_blocked() {
+        lines.push("-A OUTPUT -j LOG --log-prefix \"[AUDIT-BLOCK] \" --log-level 4".into());
+    }
+    if policy.learn {
+        let table = match family {
+            Family::V4 => learn::RECENT_TABLE_V4,
+            Family::V6 => learn::RECENT_TABLE_V6,
+        };
+        lines.push(format!("-A OUTPUT -m recent --set --rdest --name {table}"));
+    }
+    lines.extend(blocked_lines(&policy.blocklist, family, policy.audit));
+    if !policy.network.subnets.is_empty() {
+        lines.extend(session_lines(&policy.network, family, policy.audit));
+    }
+    if !policy.quotas.is_empty() {
+        lines.extend(quota_lines(&policy.quotas, policy.audit));
+    }
+    lines.push("COMMIT".into());
+
+    if policy.mode == NetworkMode::Proxy && family == Family::V4 {
+        lines.extend(redirect_lines(policy));
+    }
+
+    let mut rules = lines.join("\n");
+    rules.push('\n');
+    rules
+}
+
+/// Rules keeping non-root users away from Docker's embedded DNS server when the DNS servers
+/// are restricted. It is on loopback but forwards anywhere, so the agent could otherwise
+/// bypass the forwarder; they precede the loopback ACCEPT.
+fn embedded_dns_lines(policy: &Policy, family: Family) -> Vec<String> {
+    let mut lines = Vec::new();
+    if family == Family::V6 || policy.dns_servers == DnsServers::Any {
+        return lines;
+    }
+    for proto in ["udp", "tcp"] {
+        let matcher = format!("-d {EMBEDDED_DNS} -p {proto} -m owner ! --uid-owner 0");
+        if policy.audit.logs_blocked() {
+            lines.push(format!(
+                "-A OUTPUT {matcher} -j LOG --log-prefix \"[AUDIT-BLOCK-DNS] \" --log-level 4"
+            ));
+        }
+        lines.push(format!("-A OUTPUT {matcher} -j DROP"));
+    }
+    lines
+}
+
+/// The nat table of a proxy mode ruleset.
+///
+/// Direct web traffic is transparently redirected into the proxy (for tools that ignore
+/// `HTTPS_PROXY`), except for allowed IP ranges and sidecars, which are reached directly.
+fn redirect_lines(policy: &Policy) -> Vec<String> {
+    let mut lines = Vec::from(
+        [
+            "*nat",
+            ":PREROUTING ACCEPT [0:0]",
+            ":INPUT ACCEPT [0:0]",
+            ":OUTPUT ACCEPT [0:0]",
+            ":POSTROUTING ACCEPT [0:0]",
+        ]
+        .map(String::from),
+    );
+    for cidr in policy.cidrs.iter().filter(|cidr| !cidr.is_ipv6()) {
+        lines.push(format!("-A OUTPUT -d {cidr} -j RETURN"));
+    }
+    for sidecar in policy.network.sidecars.iter().filter(|s| s.ip.is_ipv4()) {
+        lines.push(format!("-A OUTPUT -d {} -j RETURN", sidecar.ip));
+    }
+    lines.push(format!(
+        "-A OUTPUT -p tcp -m owner ! --uid-owner {PROXY_USER} -m multiport --dports 80,443 -j REDIRECT --to-ports {PROXY_PORT}"
+    ));
+    lines.push("COMMIT".into());
+    lines
+}
+
+/// Comment lines describing the policy at the top of a ruleset
+fn header(policy: &Policy, family: Family) -> Vec<String> {
+    let mode = match policy.mode {
+        NetworkMode::Firewall => "firewall",
+        NetworkMode::Proxy => "proxy",
+    };
+    let mut lines = vec![format!("# rustyolo sandbox firewall ({}, {mode} mode)", family.name())];
+    if !policy.domains.is_empty() {
+        lines.push(match policy.mode {
+            NetworkMode::Firewall => {
+                "# Trusted domains (their addresses are added to RUSTYOLO-TRUSTED and RUSTYOLO-DNS at runtime):"
+            }
+            NetworkMode::Proxy => "# Trusted domains (checked by name in the egress proxy):",
+        }.to_string());
+        lines.extend(policy.domains.iter().map(|entry| format!("#   {entry}")));
+    }
+    if !policy.upstream_proxy.is_empty() {
+        let addrs: Vec<String> = policy.upstream_proxy.iter().map(ToString::to_string).collect();
+        lines.push(format!(
+            "# Upstream proxy (the only destination of the egress proxy): {}",
+            addrs.join(" ")
+        ));
+    }
+    if !policy.network.subnets.is_empty() {
+        let subnets: Vec<String> = policy.network.subnets.iter().map(ToString::to_string).collect();
+        lines.push(format!(
+            "# Session network (other containers are unreachable): {}",
+            subnets.join(" ")
+        ));
+        for sidecar in &policy.network.sidecars {
+            lines.push(format!("#   sidecar {} ({})", sidecar.name, sidecar.ip));
+        }
+    }
+    if !policy.deny.is_empty() {
+        lines.push("# Denied domains (override trusted domains; refused by name):".into());
+        lines.extend(policy.deny.iter().map(|pattern| format!("#   {pattern}")));
+    }
+    if !policy.host_ports.is_empty() {
+        let ports: Vec<String> = policy.host_ports.iter().map(|p| format!("{p}/tcp")).collect();
+        lines.push(format!(
+            "# Host services ({HOST_GATEWAY}, added to {HOST_CHAIN} at startup): {}",
+            ports.join(" ")
+        ));
+    }
+    if !policy.quotas.is_empty() {
+        lines.push(format!(
+            "# Egress quotas (per address family; addresses are added to {ACCOUNT_CHAIN} at runtime):"
+        ));
+        if let Some(limit) = policy.quotas.total {
+            lines.push(format!("#   total: {}", crate::egress::format_size(limit)));
+        }
+        for quota in &policy.quotas.domains {
+            lines.push(format!(
+                "#   {}: {}",
+                quota.pattern,
+                crate::egress::format_size(quota.limit)
+            ));
+        }
+    }
+    lines
+}
+
+/// The built-in chains of the `filter` table and the chains the ruleset creates
+fn chain_declarations(policy: &Policy) -> Vec<String> {
+    let mut chains: Vec<String> =
+        [":INPUT ACCEPT [0:0]", ":FORWARD ACCEPT [0:0]", ":OUTPUT DROP [0:0]"]
+            .map(String::from)
+            .to_vec();
+    chains.push(format!(":{BLOCKED_CHAIN} - [0:0]"));
+    if !policy.network.subnets.is_empty() {
+        chains.push(format!(":{SESSION_CHAIN} - [0:0]"));
+    }
+    if !policy.host_ports.is_empty() {
+        chains.push(format!(":{HOST_CHAIN} - [0:0]"));
+    }
+    if policy.mode == NetworkMode::Firewall {
+        chains.push(format!(":{TRUSTED_CHAIN} - [0:0]"));
+        chains.push(format!(":{DNS_CHAIN} - [0:0]"));
+    }
+    if !policy.quotas.is_empty() {
+        chains.push(format!(":{EGRESS_CHAIN} - [0:0]"));
+        chains.push(format!(":{ACCOUNT_CHAIN} - [0:0]"));
+        for index in 0..policy.quotas.domains.len() {
+            chains.push(format!(":{} - [0:0]", quota_chain(index)));
+        }
+    }
+    chains
+}
+
+/// Name of the chain enforcing the per-domain quota at `index`
+fn quota_chain(index: usize) -> String {
+    format!("RUSTYOLO-QUOTA-{index}")
+}
+
+/// The rules of [`EGRESS_CHAIN`] and the per-domain quota chains: traffic within the quota
+/// returns, everything after it is rejected
+fn quota_lines(quotas: &Quotas, audit: AuditLevel) -> Vec<String> {
+    let mut lines = vec![format!("-A {EGRESS_CHAIN} -j {ACCOUNT_CHAIN}")];
+    let chains = quotas
+        .total
+        .map(|limit| (EGRESS_CHAIN.to_string(), limit))
+        .into_iter()
+        .chain(quotas.domains.iter().enumerate().map(|(i, q)| (quota_chain(i), q.limit)));
+    for (chain, limit) in chains {
+        lines.push(format!("-A {chain} -m quota --quota {limit} -j RETURN"));
+        if audit.logs_blocked() {
+            lines.push(format!("-A {chain} -j LOG --log-prefix \"[AUDIT-QUOTA] \" --log-level 4"));
+        }
+        lines.push(format!("-A {chain} -j REJECT"));
+    }
+    lines
+}
+
+/// The rules of [`BLOCKED_CHAIN`]: exemptions return, blocked ranges are dropped
+fn blocked_lines(blocklist: &Blocklist, family: Family, audit: AuditLevel) -> Vec<String> {
+    let in_family = |cidr: &&Cidr| cidr.is_ipv6() == (family == Family::V6);
+    let mut lines = Vec::new();
+    for cidr in blocklist.exemptions().iter().filter(in_family) {
+        lines.push(format!("-A {BLOCKED_CHAIN} -d {cidr} -j RETURN"));
+    }
+    for range in blocklist.ranges().iter().filter(in_family) {
+        if audit.logs_blocked() {
+            lines.push(format!(
+                "-A {BLOCKED_CHAIN} -d {range} -j LOG --log-prefix \"[AUDIT-BLOCK-PRIVATE] \" --log-level 4"
+            ));
+        }
+        lines.push(format!("-A {BLOCKED_CHAIN} -d {range} -j DROP"));
+    }
+    lines
+}
+
+/// The rules of [`SESSION_CHAIN`]: sidecars are accepted, the rest of the subnet is dropped
+fn session_lines(network: &Addresses, family: Family, audit: AuditLevel) -> Vec<String> {
+    let mut lines = Vec::new();
+    for sidecar in network.sidecars.iter().filter(|sidecar| family.includes(sidecar.ip)) {
+        if audit.logs_allowed() {
+            lines.push(format!(
+                "-A {SESSION_CHAIN} -d {} -j LOG --log-prefix \"[AUDIT-SIDECAR] \" --log-level 6",
+                sidecar.ip
+            ));
+        }
+        lines.push(format!("-A {SESSION_CHAIN} -d {} -j ACCEPT", sidecar.ip));
+    }
+    for subnet in network.subnets.iter().filter(|cidr| cidr.is_ipv6() == (family == Family::V6)) {
+        if audit.logs_blocked() {
+            lines.push(format!(
+                "-A {SESSION_CHAIN} -d {subnet} -j LOG --log-prefix \"[AUDIT-BLOCK-NETWORK] \" --log-level 4"
+            ));
+        }
+        lines.push(format!("-A {SESSION_CHAIN} -d {subnet} -j DROP"));
+    }
+    lines
+}
+
+/// An ACCEPT rule for `matcher`, preceded by a LOG rule in verbose audit mode
+fn accept_lines(matcher: &str, log_prefix: &str, audit: AuditLevel) -> Vec<String> {
+    let mut lines = Vec::new();
+    if audit.logs_allowed() {
+        lines.push(format!(
+            "-A OUTPUT {matcher} -j LOG --log-prefix \"{log_prefix}\" --log-level 6"
+        ));
+    }
+    lines.push(format!("-A OUTPUT {matcher} -j ACCEPT"));
+    lines
+}
+
+/// Allow outbound traffic to `ip` by appending an ACCEPT rule to `chain`.
+///
+/// In verbose audit mode, a LOG rule is added in front of the ACCEPT rule so that
+/// allowed connections show up with the `[AUDIT-ALLOW]` prefix.
+///
+/// # Arguments
+///
+/// * `chain` - The iptables chain to append to (e.g., [`DNS_CHAIN`])
+/// * `ip` - The destination address to allow
+/// * `port` - The only destination port to allow, or `None` for all ports
+/// * `audit` - Audit logging level
+pub fn allow_ip(
+    chain: &str,
+    ip: IpAddr,
+    port: Option<Port>,
+    audit: AuditLevel,
+) -> Result<(), String> {
+    for rule in ip_rules(ip, port, audit) {
+        iptables(ip, "-A", chain, &rule)?;
+    }
+    Ok(())
+}
+
+/// Allow the `host_ports` on the Docker host's gateway IP, which `--add-host` maps to
+/// [`HOST_GATEWAY`] in `/etc/hosts`.
+///
+/// # Arguments
+///
+/// * `ports` - TCP ports on the host to open
+/// * `ipv6` - Also open the ports on an IPv6 gateway address (requires `ip6tables`)
+/// * `audit` - Audit logging level
+pub fn allow_host_ports(ports: &[u16], ipv6: bool, audit: AuditLevel) -> Result<(), String> {
+    let gateways: Vec<IpAddr> = (HOST_GATEWAY, 0)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {HOST_GATEWAY}: {e}"))?
+        .map(|addr| addr.ip())
+        .filter(|ip| ipv6 || ip.is_ipv4())
+        .collect();
+    if gateways.is_empty() {
+        return Err(format!("{HOST_GATEWAY} has no usable address"));
+    }
+
+    for gateway in gateways {
+        for &number in ports {
+            let port = Port { number, protocol: Protocol::Tcp };
+            allow_ip(HOST_CHAIN, gateway, Some(port), audit)?;
+            println!(
+                "[RustyYOLO Firewall] ALLOWING host service: {gateway}{}",
+                port_suffix(Some(port))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Count traffic to `ip` under `name` in [`ACCOUNT_CHAIN`], charging it to the first
+/// per-domain quota matching `name`.
+///
+/// Does nothing if the address is already counted under that name.
+pub fn account_ip(ip: IpAddr, name: &str, quotas: &Quotas) -> Result<(), String> {
+    let mut rule: Vec<String> = ["-d", &ip.to_string(), "-m", "comment", "--comment", name]
+        .map(String::from)
+        .to_vec();
+    if let Some(index) = quotas.quota_for(name) {
+        rule.extend(["-j".into(), quota_chain(index)]);
+    }
+    if iptables(ip, "-C", ACCOUNT_CHAIN, &rule).is_ok() {
+        return Ok(());
+    }
+    iptables(ip, "-A", ACCOUNT_CHAIN, &rule)
+}
+
+/// Drop resolved addresses that fall into a blocked range, reporting each as suspicious.
+///
+/// The firewall would drop traffic to them anyway; screening them keeps rules for
+/// unreachable addresses out of the runtime chains and makes rebinding attempts visible.
+pub fn screen_resolved(name: &str, addresses: Vec<IpAddr>, blocklist: &Blocklist) -> Vec<IpAddr> {
+    addresses
+        .into_iter()
+        .filter(|ip| match blocklist.blocks(*ip) {
+            Some(range) => {
+                println!(
+                    "[RustyYOLO Firewall] SUSPICIOUS: {name} resolved to {ip} (in blocked range {range}); not allowed"
+                );
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Remove the rules added by [`allow_ip`] for `ip` from `chain`.
+///
+/// The port and audit level must match the ones used when the rules were added.
+pub fn remove_ip(
+    chain: &str,
+    ip: IpAddr,
+    port: Option<Port>,
+    audit: AuditLevel,
+) -> Result<(), String> {
+    for rule in ip_rules(ip, port, audit) {
+        iptables(ip, "-D", chain, &rule)?;
+    }
+    Ok(())
+}
+
+/// Describe a port scope for log messages, e.g. `" port 443/tcp"` (empty for all ports)
+pub fn port_suffix(port: Option<Port>) -> String {
+    port.map(|p| format!(" port {p}")).unwrap_or_default()
+}
+
+/// The rule specifications that allow traffic to `ip`, in chain order
+fn ip_rules(ip: IpAddr, port: Option<Port>, audit: AuditLevel) -> Vec<Vec<String>> {
+    let mut matcher = vec!["-d".to_string(), ip.to_string()];
+    if let Some(port) = port {
+        matcher.extend([
+            "-p".into(),
+            port.protocol.as_str().into(),
+            "--dport".into(),
+            port.number.to_string(),
+        ]);
+    }
+
+    let mut rules = Vec::new();
+    if audit.logs_allowed() {
+        let mut log = matcher.clone();
+        log.extend(
+            ["-j", "LOG", "--log-prefix", "[AUDIT-ALLOW] ", "--log-level", "6"].map(String::from),
+        );
+        rules.push(log);
+    }
+    matcher.extend(["-j".into(), "ACCEPT".into()]);
+    rules.push(matcher);
+    rules
+}
+
+/// Run `iptables` or `ip6tables`, depending on the address family of `ip`
+fn iptables(ip: IpAddr, action: &str, chain: &str, rule: &[String]) -> Result<(), String> {
+    let program = if ip.is_ipv6() {
+        "ip6tables"
+    } else {
+        "iptables"
+    };
+    let output = Command::new(program)
+        .arg(action)
+        .arg(chain)
+        .args(rule)
+        .output()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "{program} {action} {chain} {} failed: {}",
+            rule.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domains;
+    use std::path::Path;
+
+    fn policy(mode: NetworkMode, audit: AuditLevel) -> Policy {
+        Policy {
+            mode,
+            dns_servers: DnsServers::Only(
+                ["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111"]
+                    .map(|ip| ip.parse().unwrap())
+                    .to_vec(),
+            ),
+            domains: domains::parse_list("github.com *.githubusercontent.com git.internal:22")
+                .unwrap(),
+            deny: domains::parse_patterns("gist.github.com").unwrap(),
+            cidrs: crate::cidr::parse_list("10.20.0.0/16 fd00::/8").unwrap(),
+            host_ports: vec![11434, 5432],
+            blocklist: Blocklist::new(crate::cidr::parse_list("10.30.0.0/16").unwrap()),
+            network: Addresses::default(),
+            quotas: Quotas::default(),
+            upstream_proxy: Vec::new(),
+            learn: false,
+            audit,
+        }
+    }
+
+    /// Compare against `tests/golden/<name>`. Run with `UPDATE_GOLDEN=1` to rewrite the file.
+    fn assert_golden(name: &str, actual: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        assert_eq!(actual, expected, "{name} is out of date (run with UPDATE_GOLDEN=1 to update)");
+    }
+
+    #[test]
+    fn test_ruleset_firewall_mode() {
+        let policy = policy(NetworkMode::Firewall, AuditLevel::None);
+        assert_golden("firewall.v4.rules", &ruleset(&policy, Family::V4));
+        assert_golden("firewall.v6.rules", &ruleset(&policy, Family::V6));
+    }
+
+    #[test]
+    fn test_ruleset_firewall_mode_verbose() {
+        let policy = policy(NetworkMode::Firewall, AuditLevel::Verbose);
+        assert_golden("firewall-verbose.v4.rules", &ruleset(&policy, Family::V4));
+    }
+
+    #[test]
+    fn test_ruleset_proxy_mode() {
+        let policy = policy(NetworkMode::Proxy, AuditLevel::Basic);
+        assert_golden("proxy.v4.rules", &ruleset(&policy, Family::V4));
+        assert_golden("proxy.v6.rules", &ruleset(&policy, Family::V6));
+    }
+
+    #[test]
+    fn test_ruleset_egress_quotas() {
+        let mut policy = policy(NetworkMode::Firewall, AuditLevel::Basic);
+        policy.quotas = Quotas::parse(Some("200MB"), &["github.com=50MB".to_string()]).unwrap();
+        assert_golden("firewall-quotas.v4.rules", &ruleset(&policy, Family::V4));
+    }
+
+    #[test]
+    fn test_ruleset_upstream_proxy() {
+        let mut policy = policy(NetworkMode::Proxy, AuditLevel::None);
+        policy.upstream_proxy = vec!["10.0.0.8:3128".parse().unwrap()];
+        assert_golden("proxy-upstream.v4.rules", &ruleset(&policy, Family::V4));
+    }
+
+    #[test]
+    fn test_ruleset_session_network() {
+        let mut policy = policy(NetworkMode::Proxy, AuditLevel::Basic);
+        policy.network = Addresses {
+            subnets: crate::cidr::parse_list("172.18.0.0/16").unwrap(),
+            sidecars: vec![crate::network::Sidecar {
+                name: "postgres-dev".to_string(),
+                ip: IpAddr::from([172, 18, 0, 3]),
+            }],
+        };
+        assert_golden("proxy-network.v4.rules", &ruleset(&policy, Family::V4));
+        assert!(!ruleset(&policy, Family::V6).contains("-A RUSTYOLO-SESSION"));
+    }
+
+    #[test]
+    fn test_ruleset_learn_mode() {
+        let mut policy = policy(NetworkMode::Firewall, AuditLevel::None);
+        policy.learn = true;
+        assert!(ruleset(&policy, Family::V4)
+            .contains("-A OUTPUT -m recent --set --rdest --name rustyolo-learn\n"));
+        assert!(ruleset(&policy, Family::V6).contains("--name rustyolo-learn6\n"));
+    }
+
+    #[test]
+    fn test_ruleset_embedded_dns_is_root_only() {
+        let rules = ruleset(&policy(NetworkMode::Firewall, AuditLevel::None), Family::V4);
+        let drop = rules.find("-A OUTPUT -d 127.0.0.11 -p udp -m owner ! --uid-owner 0 -j DROP\n");
+        assert!(drop.unwrap() < rules.find("-A OUTPUT -o lo -j ACCEPT\n").unwrap());
+        assert!(rules.contains("-A OUTPUT -d 127.0.0.11 -p tcp -m owner ! --uid-owner 0 -j DROP\n"));
+        assert!(!ruleset(&policy(NetworkMode::Firewall, AuditLevel::None), Family::V6)
+            .contains("127.0.0.11"));
+    }
+
+    #[test]
+    fn test_ruleset_any_dns_server() {
+        let mut policy = policy(NetworkMode::Firewall, AuditLevel::None);
+        policy.dns_servers = DnsServers::Any;
+        let rules = ruleset(&policy, Family::V4);
+        assert!(rules.contains("-A OUTPUT -p udp --dport 53 -j ACCEPT\n"));
+        assert!(!rules.contains("--uid-owner 0"));
+        assert!(!rules.contains("127.0.0.11"));
+    }
+
+    #[test]
+    fn test_ip_rules_scope_to_port() {
+        let port = Port { number: 22, protocol: Protocol::Tcp };
+        let rules = ip_rules(IpAddr::from([10, 0, 0, 5]), Some(port), AuditLevel::Verbose);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0].join(" "),
+            "-d 10.0.0.5 -p tcp --dport 22 -j LOG --log-prefix [AUDIT-ALLOW]  --log-level 6"
+        );
+        assert_eq!(rules[1].join(" "), "-d 10.0.0.5 -p tcp --dport 22 -j ACCEPT");
+    }
+
+    #[test]
+    fn test_ip_rules_without_port_allow_everything() {
+        let rules = ip_rules(IpAddr::from([10, 0, 0, 5]), None, AuditLevel::None);
+        assert_eq!(rules, vec![vec!["-d", "10.0.0.5", "-j", "ACCEPT"]]);
+    }
+}
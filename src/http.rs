@@ -0,0 +1,250 @@
+//! HTTP method and path policies (`[network] http_policies`).
+//!
+//! Allowing a host allows everything on it: allowing `api.github.com` for reading
+//! releases also allows creating issues and gists. A policy narrows an allowed host down
+//! to the requests that are needed, e.g.
+//! `{ host = "api.github.com", methods = ["GET"], paths = ["/repos/our-org/*"] }`.
+//!
+//! Policies are enforced by the egress proxy on every request it can read: plain HTTP
+//! requests, and HTTPS requests to hosts with a policy, whose TLS the proxy terminates
+//! (see [`crate::intercept`]). Policies only restrict; the host must still be allowed by
+//! `allow_domains`. Hosts without a policy are not affected.
+
+use crate::config::HttpPolicy;
+use crate::domains::DomainPattern;
+use crate::proxy::percent_decode;
+use std::fmt;
+
+/// A validated HTTP policy for one host or pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Hosts the policy applies to
+    pub host: DomainPattern,
+
+    /// Allowed request methods (uppercase); empty means any
+    methods: Vec<String>,
+
+    /// Allowed path patterns; empty means any
+    paths: Vec<String>,
+}
+
+impl Policy {
+    /// Validate a policy from the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Policy)` - The policy, with methods in uppercase
+    /// * `Err(String)` - Why the policy was rejected
+    pub fn parse(policy: &HttpPolicy) -> Result<Self, String> {
+        let host = DomainPattern::parse(&policy.host)?;
+        let context = |reason: &str| format!("Invalid HTTP policy for '{}': {reason}", policy.host);
+
+        let methods = match &policy.methods {
+            None => Vec::new(),
+            Some(methods) if methods.is_empty() => {
+                return Err(context("methods is empty; omit it to allow any method"));
+            }
+            Some(methods) => methods
+                .iter()
+                .map(|method| {
+                    if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphabetic()) {
+                        return Err(context(&format!("'{method}' is not an HTTP method")));
+                    }
+                    Ok(method.to_ascii_uppercase())
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        let paths = match &policy.paths {
+            None => Vec::new(),
+            Some(paths) if paths.is_empty() => {
+                return Err(context("paths is empty; omit it to allow any path"));
+            }
+            Some(paths) => paths
+                .iter()
+                .map(|path| {
+                    if !path.starts_with('/') || path.contains(char::is_whitespace) {
+                        return Err(context(&format!(
+                            "path '{path}' must start with '/' and contain no spaces"
+                        )));
+                    }
+                    Ok(path.clone())
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(Self { host, methods, paths })
+    }
+
+    /// Whether the policy allows `method` on `path` (an already normalized request path)
+    fn permits(&self, method: &str, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.iter().any(|m| m == method))
+            && (self.paths.is_empty() || self.paths.iter().any(|p| glob_matches(p, path)))
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let methods = if self.methods.is_empty() {
+            "any method".to_string()
+        } else {
+            self.methods.join(",")
+        };
+        let paths = if self.paths.is_empty() {
+            "any path".to_string()
+        } else {
+            self.paths.join(" ")
+        };
+        write!(f, "{}: {methods} {paths}", self.host)
+    }
+}
+
+/// The HTTP policies of a session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policies(Vec<Policy>);
+
+impl Policies {
+    /// Validate the `http_policies` entries from the configuration file.
+    pub fn parse(policies: &[HttpPolicy]) -> Result<Self, String> {
+        policies.iter().map(Policy::parse).collect::<Result<_, _>>().map(Self)
+    }
+
+    /// Whether no policies are configured
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The configured policies
+    pub fn iter(&self) -> impl Iterator<Item = &Policy> {
+        self.0.iter()
+    }
+
+    /// Whether requests to `host` are restricted by a policy
+    pub fn restricts(&self, host: &str) -> bool {
+        self.0.iter().any(|policy| policy.host.matches(host))
+    }
+
+    /// Check whether a request may be sent to `host`.
+    ///
+    /// Hosts without a policy allow every request; otherwise one of the host's policies
+    /// must allow the method and path. The query string is ignored, and the path is
+    /// percent-decoded before matching. Paths with `.` or `..` segments are refused, since
+    /// the server would resolve them to a different path than the one checked.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The (normalized) host name the request is sent to
+    /// * `method` - The request method, compared case-sensitively
+    /// * `target` - The request target from the request line (origin-form)
+    pub fn allows(&self, host: &str, method: &str, target: &str) -> bool {
+        let mut policies = self.0.iter().filter(|policy| policy.host.matches(host)).peekable();
+        if policies.peek().is_none() {
+            return true;
+        }
+        let Some(path) = normalize_path(target) else {
+            return false;
+        };
+        policies.any(|policy| policy.permits(method, &path))
+    }
+}
+
+/// Strip the query string and percent-decode a request target, refusing dot segments.
+fn normalize_path(target: &str) -> Option<String> {
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let path = percent_decode(path)?;
+    let dot_segment = path.split(['/', '\\']).any(|segment| segment == "." || segment == "..");
+    (path.starts_with('/') && !dot_segment).then_some(path)
+}
+
+/// Match `text` against a pattern in which `*` matches any sequence of characters,
+/// including `/`.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it currently covers up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, covered)) = backtrack {
+            p = star + 1;
+            t = covered + 1;
+            backtrack = Some((star, covered + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(host: &str, methods: Option<&[&str]>, paths: Option<&[&str]>) -> HttpPolicy {
+        let strings = |list: &[&str]| list.iter().map(ToString::to_string).collect();
+        HttpPolicy {
+            host: host.to_string(),
+            methods: methods.map(strings),
+            paths: paths.map(strings),
+        }
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("/repos/our-org/*", "/repos/our-org/app/issues"));
+        assert!(glob_matches("/repos/our-org/*", "/repos/our-org/"));
+        assert!(!glob_matches("/repos/our-org/*", "/repos/other-org/app"));
+        assert!(glob_matches("/repos/*/releases", "/repos/our-org/app/releases"));
+        assert!(!glob_matches("/repos/*/releases", "/repos/our-org/app/releases/1"));
+        assert!(glob_matches("/user", "/user"));
+        assert!(!glob_matches("/user", "/users"));
+    }
+
+    #[test]
+    fn test_policies_allow() {
+        let policies = Policies::parse(&[
+            policy("api.github.com", Some(&["get", "HEAD"]), Some(&["/repos/our-org/*"])),
+            policy("api.github.com", Some(&["POST"]), Some(&["/graphql"])),
+        ])
+        .unwrap();
+
+        assert!(policies.allows("api.github.com", "GET", "/repos/our-org/app?per_page=100"));
+        assert!(policies.allows("api.github.com", "POST", "/graphql"));
+        assert!(!policies.allows("api.github.com", "POST", "/repos/our-org/app/issues"));
+        assert!(!policies.allows("api.github.com", "GET", "/repos/other-org/app"));
+        assert!(!policies.allows("api.github.com", "get", "/repos/our-org/app"));
+        // Hosts without a policy are unrestricted
+        assert!(!policies.restricts("github.com"));
+        assert!(policies.allows("github.com", "POST", "/our-org/app.git/git-receive-pack"));
+    }
+
+    #[test]
+    fn test_policies_refuse_path_tricks() {
+        let policies =
+            Policies::parse(&[policy("api.github.com", None, Some(&["/repos/our-org/*"]))])
+                .unwrap();
+        assert!(policies.allows("api.github.com", "DELETE", "/repos/our-org/app"));
+        assert!(!policies.allows("api.github.com", "GET", "/repos/our-org/../other-org/app"));
+        assert!(!policies.allows("api.github.com", "GET", "/repos/our-org/%2e%2e/other-org/app"));
+        assert!(!policies.allows("api.github.com", "GET", "/repos/our-org/..%5cother-org"));
+        assert!(!policies.allows("api.github.com", "GET", "/repos/our-org/%zz"));
+        assert!(!policies.allows("api.github.com", "GET", "*"));
+    }
+
+    #[test]
+    fn test_policy_parse_errors() {
+        assert!(Policy::parse(&policy("*.com", None, None)).is_err());
+        assert!(Policy::parse(&policy("api.github.com", Some(&[]), None))
+            .unwrap_err()
+            .contains("methods is empty"));
+        assert!(Policy::parse(&policy("api.github.com", Some(&["GET /"]), None)).is_err());
+        assert!(Policy::parse(&policy("api.github.com", None, Some(&["repos/*"])))
+            .unwrap_err()
+            .contains("must start with '/'"));
+    }
+}
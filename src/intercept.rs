@@ -0,0 +1,344 @@
+//! TLS interception for hosts with HTTP policies (`[network] http_policies`).
+//!
+//! Method and path rules can only be checked on requests the egress proxy can read. For
+//! hosts with a policy, the proxy therefore terminates TLS itself: it generates a CA for
+//! the session when it starts, the entrypoint installs that CA into the sandbox's trust
+//! store, and the proxy presents a certificate for the requested host signed by it. The
+//! decrypted request is checked, then sent to the real server over a new TLS connection
+//! that is verified against the system trust store.
+//!
+//! The CA's private key never leaves the proxy process, and the CA is name-constrained
+//! to the hosts with policies, so it cannot be used to impersonate any other site.
+//! Connections to hosts without a policy are not intercepted.
+
+use crate::certs::SYSTEM_BUNDLE;
+use crate::domains::DomainPattern;
+use crate::http::Policies;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, GeneralSubtree, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Only HTTP/1.1 is offered on both sides, since requests are checked one at a time
+const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// Issues certificates for intercepted hosts and opens TLS connections on their behalf
+pub struct Interceptor {
+    /// Signs the per-host certificates
+    issuer: Issuer<'static, KeyPair>,
+
+    /// The CA certificate, sent along with each host certificate
+    ca: CertificateDer<'static>,
+
+    /// The CA certificate in PEM format, for the entrypoint to install
+    ca_pem: String,
+
+    /// TLS server settings per intercepted host, created on first use
+    servers: Mutex<HashMap<String, Arc<ServerConfig>>>,
+
+    /// TLS client settings for connections to the real servers, loaded on first use
+    client: OnceLock<Result<Arc<ClientConfig>, String>>,
+}
+
+impl Interceptor {
+    /// Generate a session CA that may only issue certificates for the hosts with policies.
+    pub fn new(policies: &Policies) -> Result<Self, String> {
+        let error = |e: rcgen::Error| format!("Failed to create the interception CA: {e}");
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "RustyYOLO egress proxy");
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        params.name_constraints = Some(NameConstraints {
+            permitted_subtrees: policies
+                .iter()
+                .map(|policy| match &policy.host {
+                    DomainPattern::Exact(name)
+                    | DomainPattern::Wildcard(name)
+                    | DomainPattern::Suffix(name) => GeneralSubtree::DnsName(name.clone()),
+                })
+                .collect(),
+            excluded_subtrees: Vec::new(),
+        });
+
+        let key = KeyPair::generate().map_err(error)?;
+        let certificate = params.self_signed(&key).map_err(error)?;
+        Ok(Self {
+            issuer: Issuer::new(params, key),
+            ca: certificate.der().clone(),
+            ca_pem: certificate.pem(),
+            servers: Mutex::new(HashMap::new()),
+            client: OnceLock::new(),
+        })
+    }
+
+    /// The CA certificate in PEM format
+    pub fn ca_pem(&self) -> &str {
+        &self.ca_pem
+    }
+
+    /// Start a TLS server session for a client connecting to `host`.
+    pub fn accept(&self, host: &str) -> io::Result<ServerConnection> {
+        let config = self.server_config(host).map_err(io::Error::other)?;
+        ServerConnection::new(config).map_err(io::Error::other)
+    }
+
+    /// Start a TLS client session to the real `host`, verified against the system trust store.
+    pub fn connect(&self, host: &str) -> io::Result<ClientConnection> {
+        let config =
+            self.client.get_or_init(load_client_config).clone().map_err(io::Error::other)?;
+        let name = ServerName::try_from(host.to_string()).map_err(io::Error::other)?;
+        ClientConnection::new(config, name).map_err(io::Error::other)
+    }
+
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, String> {
+        let mut servers = self.servers.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(config) = servers.get(host) {
+            return Ok(Arc::clone(config));
+        }
+
+        let error =
+            |e: &dyn std::fmt::Display| format!("Failed to issue a certificate for {host}: {e}");
+        let mut params = CertificateParams::new(vec![host.to_string()]).map_err(|e| error(&e))?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        let key = KeyPair::generate().map_err(|e| error(&e))?;
+        let certificate = params.signed_by(&key, &self.issuer).map_err(|e| error(&e))?;
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certificate.der().clone(), self.ca.clone()],
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )
+            .map_err(|e| error(&e))?;
+        config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+
+        let config = Arc::new(config);
+        servers.insert(host.to_string(), Arc::clone(&config));
+        Ok(config)
+    }
+}
+
+fn load_client_config() -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    let certificates = CertificateDer::pem_file_iter(SYSTEM_BUNDLE)
+        .map_err(|e| format!("Failed to read {SYSTEM_BUNDLE}: {e}"))?;
+    roots.add_parsable_certificates(certificates.flatten());
+
+    let mut config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// A TCP stream whose first bytes were already read by the proxy and are replayed
+pub struct Replay {
+    prefix: Vec<u8>,
+    position: usize,
+    stream: TcpStream,
+}
+
+impl Replay {
+    pub fn new(prefix: Vec<u8>, stream: TcpStream) -> Self {
+        Self { prefix, position: 0, stream }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.prefix.len() {
+            let n = (&self.prefix[self.position..]).read(buf)?;
+            self.position += n;
+            return Ok(n);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// How a request's body is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// No body
+    None,
+
+    /// `Content-Length` bytes
+    Length(u64),
+
+    /// Chunked `Transfer-Encoding`
+    Chunked,
+}
+
+/// How the body of the request with header `head` is delimited.
+///
+/// Requests a server could delimit differently are rejected, as RFC 9112 §6.3 requires of
+/// intermediaries: more than one `Content-Length`, `Content-Length` together with
+/// `Transfer-Encoding`, a `Transfer-Encoding` other than exactly `chunked`, and header
+/// names with whitespace around them. Otherwise the rest of the body could be read as a
+/// second request that was never checked.
+pub fn framing(head: &str) -> Result<Framing, String> {
+    let mut lengths = Vec::new();
+    let mut encodings = Vec::new();
+    for line in head.split("\r\n").skip(1).filter(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.is_empty() || name.trim() != name {
+            return Err(format!("malformed header name '{name}'"));
+        }
+        if name.eq_ignore_ascii_case("content-length") {
+            lengths.push(value.trim());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            encodings.push(value.trim());
+        }
+    }
+
+    match (&lengths[..], &encodings[..]) {
+        ([], []) => Ok(Framing::None),
+        ([], [encoding]) if encoding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+        ([], _) => Err(format!("unsupported Transfer-Encoding '{}'", encodings.join(", "))),
+        ([length], []) if !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit()) => {
+            length
+                .parse()
+                .map(Framing::Length)
+                .map_err(|_| "invalid Content-Length".to_string())
+        }
+        ([_], []) => Err("invalid Content-Length".to_string()),
+        (_, []) => Err("more than one Content-Length".to_string()),
+        (_, _) => Err("both Content-Length and Transfer-Encoding".to_string()),
+    }
+}
+
+/// Copy a request body from `from` to `to`, as framed by the request header `head`.
+///
+/// Requests without `Content-Length` or chunked `Transfer-Encoding` have no body; requests
+/// whose [`framing`] is ambiguous are an `InvalidData` error.
+pub fn copy_body(head: &str, from: &mut impl Read, to: &mut impl Write) -> io::Result<()> {
+    match framing(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+        Framing::None => Ok(()),
+        Framing::Chunked => copy_chunked(from, to),
+        Framing::Length(length) => {
+            let copied = io::copy(&mut from.take(length), to)?;
+            if copied < length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Copy a chunked body, including the trailer section.
+fn copy_chunked(from: &mut impl Read, to: &mut impl Write) -> io::Result<()> {
+    loop {
+        let line = copy_line(from, to)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        if size == 0 {
+            // Trailer fields, up to the empty line
+            while !copy_line(from, to)?.is_empty() {}
+            return Ok(());
+        }
+        // The chunk data and its terminating CRLF
+        let length = size + 2;
+        if io::copy(&mut from.take(length), to)? < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Copy one CRLF-terminated line and return it without the line ending.
+fn copy_line(from: &mut impl Read, to: &mut impl Write) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() > 4096 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        from.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    to.write_all(&line)?;
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpPolicy;
+
+    #[test]
+    fn test_copy_body() {
+        let mut out = Vec::new();
+        let head = "POST /graphql HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        copy_body(head, &mut &b"hello, and the next request"[..], &mut out).unwrap();
+        assert_eq!(out, b"hello");
+
+        out.clear();
+        let head = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let body = b"5;ext=1\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1\r\n";
+        copy_body(head, &mut &body[..], &mut out).unwrap();
+        assert_eq!(out, &body[..body.len() - 16]);
+
+        out.clear();
+        copy_body("GET / HTTP/1.1\r\n\r\n", &mut &b"ignored"[..], &mut out).unwrap();
+        assert!(out.is_empty());
+
+        let head = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n";
+        assert!(copy_body(head, &mut &b"short"[..], &mut out).is_err());
+
+        // Framing a server could read differently is refused before anything is copied
+        for head in [
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 20\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: xchunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding : chunked\r\n\r\n",
+        ] {
+            out.clear();
+            let err = copy_body(head, &mut &b"0\r\n\r\nhello"[..], &mut out).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{head}");
+            assert!(out.is_empty());
+        }
+        assert_eq!(
+            framing("POST / HTTP/1.1\r\ntransfer-encoding: Chunked\r\n\r\n"),
+            Ok(Framing::Chunked)
+        );
+    }
+
+    #[test]
+    fn test_interceptor_issues_host_certificates() {
+        let policies = Policies::parse(&[HttpPolicy {
+            host: "api.github.com".to_string(),
+            methods: Some(vec!["GET".to_string()]),
+            paths: None,
+        }])
+        .unwrap();
+        let interceptor = Interceptor::new(&policies).unwrap();
+        assert!(interceptor.ca_pem().starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(interceptor.accept("api.github.com").is_ok());
+        assert_eq!(interceptor.servers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,406 @@
+//! Allowlist learning mode (`rustyolo learn`).
+//!
+//! Figuring out which domains a project needs is otherwise trial and error. In learn mode
+//! the sandbox keeps its usual policy but records every destination it blocks:
+//!
+//! * names the DNS forwarder answered with NXDOMAIN,
+//! * TLS SNI / HTTP `Host` names the egress proxy refused (proxy mode),
+//! * addresses of packets the firewall dropped, remembered by the iptables `recent` match
+//!   and named by a reverse lookup where possible.
+//!
+//! The records are appended to files in a directory mounted from the host. When the
+//! container exits, the host lists the candidates and writes the accepted ones into
+//! `allow_domains` in `.rustyolo.toml`.
+
+use crate::config::Config;
+use crate::domains::DomainPattern;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Where the learning directory is mounted inside the container; the entrypoint keeps its
+/// parent out of the agent's reach
+pub const LEARN_DIR: &str = "/run/rustyolo-learn/log";
+
+/// iptables `recent` table holding the destinations of dropped IPv4 packets
+pub const RECENT_TABLE_V4: &str = "rustyolo-learn";
+
+/// iptables `recent` table holding the destinations of dropped IPv6 packets
+pub const RECENT_TABLE_V6: &str = "rustyolo-learn6";
+
+/// How often the `recent` tables are read
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Appends blocked destinations to a log file in the learning directory
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// A recorder writing to `file` in `dir`; each helper uses its own file.
+    pub fn new(dir: &Path, file: &str) -> Self {
+        Self { path: dir.join(file) }
+    }
+
+    /// Record a blocked destination. `source` says how it was seen (`dns`, `proxy`, `ip`).
+    ///
+    /// Errors are ignored: learning must never get in the way of the session.
+    pub fn record(&self, source: &str, destination: &str) {
+        let line = format!("{source} {destination}\n");
+        let _ = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+    }
+}
+
+/// Record the addresses in the `recent` tables, with their reverse DNS names, until the
+/// process is killed.
+///
+/// # Arguments
+///
+/// * `recorder` - Where to record the addresses
+/// * `upstream` - DNS servers to send the reverse lookups to
+pub fn watch_blocked_ips(
+    recorder: &Recorder,
+    upstream: &[SocketAddr],
+) -> Result<(), Box<dyn Error>> {
+    let mut seen: HashSet<IpAddr> = HashSet::new();
+    loop {
+        for table in [RECENT_TABLE_V4, RECENT_TABLE_V6] {
+            let Ok(content) = fs::read_to_string(Path::new("/proc/net/xt_recent").join(table))
+            else {
+                continue;
+            };
+            for ip in parse_recent(&content) {
+                if seen.insert(ip) {
+                    let name = crate::dns::reverse_lookup(ip, upstream);
+                    recorder.record("ip", &format!("{ip} {}", name.as_deref().unwrap_or("-")));
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Addresses listed in a `/proc/net/xt_recent` table.
+///
+/// With `--rdest`, the table stores destination addresses, but still labels them `src=`.
+fn parse_recent(content: &str) -> Vec<IpAddr> {
+    content
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix("src="))
+        .filter_map(|ip| ip.parse().ok())
+        .collect()
+}
+
+/// A name the agent tried to reach, proposed for `allow_domains`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub name: String,
+
+    /// How the name was seen (e.g. "DNS lookup", "reverse DNS of 140.82.112.3")
+    pub seen_as: BTreeSet<String>,
+
+    /// Number of times it was blocked
+    pub count: usize,
+}
+
+/// Aggregate the recorded log lines into candidates, most frequently blocked first.
+///
+/// # Returns
+///
+/// The candidates, and the blocked addresses that have no name
+pub fn candidates(log: &str) -> (Vec<Candidate>, Vec<String>) {
+    let mut by_name: BTreeMap<String, Candidate> = BTreeMap::new();
+    let mut unnamed: BTreeSet<String> = BTreeSet::new();
+
+    for line in log.lines() {
+        let mut words = line.split_whitespace();
+        let (name, seen_as) = match (words.next(), words.next(), words.next()) {
+            (Some("dns"), Some(name), None) => (name.to_string(), "DNS lookup".to_string()),
+            (Some("proxy"), Some(name), None) => (name.to_string(), "proxy".to_string()),
+            (Some("ip"), Some(ip), Some("-")) => {
+                unnamed.insert(ip.to_string());
+                continue;
+            }
+            (Some("ip"), Some(ip), Some(name)) => {
+                (name.to_string(), format!("reverse DNS of {ip}"))
+            }
+            _ => continue,
+        };
+
+        // Skip single-label names (e.g. "wpad") and anything that is not a valid domain
+        let name = name.trim_end_matches('.').to_lowercase();
+        if !name.contains('.') || DomainPattern::parse(&name).is_err() {
+            continue;
+        }
+        let candidate = by_name.entry(name.clone()).or_insert_with(|| Candidate {
+            name,
+            seen_as: BTreeSet::new(),
+            count: 0,
+        });
+        candidate.seen_as.insert(seen_as);
+        candidate.count += 1;
+    }
+
+    let mut candidates: Vec<Candidate> = by_name.into_values().collect();
+    candidates.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    (candidates, unnamed.into_iter().collect())
+}
+
+/// Parse the user's answer to the candidate prompt into 0-based indices.
+///
+/// Accepts `all`, nothing / `none`, or 1-based numbers separated by spaces or commas.
+pub fn parse_selection(input: &str, count: usize) -> Result<Vec<usize>, String> {
+    let input = input.trim().to_lowercase();
+    match input.as_str() {
+        "" | "none" | "n" => return Ok(Vec::new()),
+        "all" | "a" => return Ok((0..count).collect()),
+        _ => {}
+    }
+
+    let mut selected = Vec::new();
+    for word in input.split(|c: char| c == ',' || c.is_whitespace()).filter(|w| !w.is_empty()) {
+        let index = word
+            .parse::<usize>()
+            .ok()
+            .filter(|n| (1..=count).contains(n))
+            .ok_or_else(|| format!("'{word}' is not a number between 1 and {count}"))?;
+        if !selected.contains(&(index - 1)) {
+            selected.push(index - 1);
+        }
+    }
+    Ok(selected)
+}
+
+/// Add `names` to `allow_domains` in the configuration file at `path`.
+///
+/// The file is created if it does not exist. An existing file is rewritten from the parsed
+/// configuration, which drops its comments, so a copy is kept as `<path>.bak`.
+pub fn add_to_config(path: &Path, names: &[String]) -> Result<(), String> {
+    let mut config = if path.exists() {
+        Config::load(path)?
+    } else {
+        Config::default()
+    };
+
+    let mut domains: Vec<String> = config
+        .default
+        .allow_domains
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    for name in names {
+        if !domains.contains(name) {
+            domains.push(name.clone());
+        }
+    }
+    config.default.allow_domains = Some(domains.join(" "));
+
+    if path.exists() {
+        let backup = PathBuf::from(format!("{}.bak", path.display()));
+        fs::copy(path, &backup).map_err(|e| {
+            format!("Failed to back up {} to {}: {e}", path.display(), backup.display())
+        })?;
+    }
+    config.save(path)
+}
+
+/// Host-side state of a learning session
+pub struct Session {
+    /// Private temporary directory holding the learning directory
+    dir: PathBuf,
+}
+
+impl Session {
+    /// Create the temporary learning directory.
+    ///
+    /// Both the container's root user and the egress proxy user write to it, so it is
+    /// world-writable; it sits inside a private directory so other users on the host cannot
+    /// reach it, and inside the container the entrypoint restricts the directory it is
+    /// mounted in to root and the egress proxy user, so the agent cannot add entries.
+    pub fn create() -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("rustyolo-learn-{}", std::process::id()));
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        let log_dir = dir.join("log");
+        fs::create_dir(&log_dir)?;
+        fs::set_permissions(&log_dir, fs::Permissions::from_mode(0o777))?;
+        Ok(Self { dir })
+    }
+
+    /// The directory to mount at [`LEARN_DIR`]
+    pub fn log_dir(&self) -> PathBuf {
+        self.dir.join("log")
+    }
+
+    /// List what was blocked during the session and offer to add it to `allow_domains` in
+    /// the configuration file at `config_path`.
+    pub fn finish(self, config_path: &Path) {
+        let mut log = String::new();
+        if let Ok(entries) = fs::read_dir(self.log_dir()) {
+            for entry in entries.flatten() {
+                log.push_str(&fs::read_to_string(entry.path()).unwrap_or_default());
+            }
+        }
+
+        let (candidates, unnamed) = candidates(&log);
+        if candidates.is_empty() && unnamed.is_empty() {
+            println!("[RustyYOLO] Learn mode: nothing was blocked during the session.");
+            return;
+        }
+
+        if !candidates.is_empty() {
+            println!("[RustyYOLO] Learn mode: blocked destinations seen during the session:");
+            let width = candidates.iter().map(|c| c.name.len()).max().unwrap_or(0);
+            for (i, candidate) in candidates.iter().enumerate() {
+                let seen_as: Vec<&str> = candidate.seen_as.iter().map(String::as_str).collect();
+                println!(
+                    "  {:>2}. {:<width$}  ({}, {}x)",
+                    i + 1,
+                    candidate.name,
+                    seen_as.join(", "),
+                    candidate.count
+                );
+            }
+        }
+        if !unnamed.is_empty() {
+            println!(
+                "[RustyYOLO] Blocked addresses without a name (use allow_cidrs to allow them): {}",
+                unnamed.join(" ")
+            );
+        }
+        if candidates.is_empty() {
+            return;
+        }
+
+        let names: Vec<String> = candidates.into_iter().map(|c| c.name).collect();
+        if !io::stdin().is_terminal() {
+            println!(
+                "[RustyYOLO] To allow them, add to [default] in {}: allow_domains = \"{}\"",
+                config_path.display(),
+                names.join(" ")
+            );
+            return;
+        }
+
+        let selected = loop {
+            print!(
+                "Add to allow_domains in {}? Numbers (e.g. \"1 3\"), \"all\" or Enter to skip: ",
+                config_path.display()
+            );
+            let _ = io::stdout().flush();
+            let mut input = String::new();
+            if io::stdin().lock().read_line(&mut input).unwrap_or(0) == 0 {
+                break Vec::new();
+            }
+            match parse_selection(&input, names.len()) {
+                Ok(selected) => break selected,
+                Err(e) => println!("[RustyYOLO] {e}"),
+            }
+        };
+        if selected.is_empty() {
+            println!("[RustyYOLO] No domains added.");
+            return;
+        }
+
+        let accepted: Vec<String> = selected.into_iter().map(|i| names[i].clone()).collect();
+        match add_to_config(config_path, &accepted) {
+            Ok(()) => println!(
+                "[RustyYOLO] ✅ Added to allow_domains in {}: {}",
+                config_path.display(),
+                accepted.join(" ")
+            ),
+            Err(e) => eprintln!("[RustyYOLO] ❌ {e}"),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recent() {
+        let content = "\
+src=140.82.112.3 ttl: 64 last_seen: 4295123 oldest_pkt: 2 4295100, 4295123
+src=2606:50c0:8000::154 ttl: 64 last_seen: 4295200 oldest_pkt: 1 4295200
+";
+        assert_eq!(
+            parse_recent(content),
+            [
+                "140.82.112.3".parse::<IpAddr>().unwrap(),
+                "2606:50c0:8000::154".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_candidates_aggregate_sources() {
+        let log = "\
+dns registry.npmjs.org
+dns registry.npmjs.org
+proxy registry.npmjs.org
+dns wpad
+ip 140.82.112.3 lb-140-82-112-3-iad.github.com.
+ip 203.0.113.9 -
+proxy files.pythonhosted.org
+";
+        let (candidates, unnamed) = candidates(log);
+        let names: Vec<&str> = candidates.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["registry.npmjs.org", "files.pythonhosted.org", "lb-140-82-112-3-iad.github.com"]
+        );
+        assert_eq!(candidates[0].count, 3);
+        assert_eq!(candidates[0].seen_as.iter().collect::<Vec<_>>(), ["DNS lookup", "proxy"]);
+        assert_eq!(unnamed, ["203.0.113.9"]);
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(parse_selection("", 3), Ok(vec![]));
+        assert_eq!(parse_selection("all\n", 3), Ok(vec![0, 1, 2]));
+        assert_eq!(parse_selection("3, 1 3", 3), Ok(vec![2, 0]));
+        assert!(parse_selection("4", 3).is_err());
+        assert!(parse_selection("0", 3).is_err());
+        assert!(parse_selection("github.com", 3).is_err());
+    }
+
+    #[test]
+    fn test_add_to_config_keeps_settings() {
+        let dir = std::env::temp_dir().join(format!("rustyolo-learn-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".rustyolo.toml");
+        fs::write(
+            &path,
+            "[default]\nallow_domains = \"github.com\"\n\n[resources]\nmemory = \"8g\"\n",
+        )
+        .unwrap();
+
+        add_to_config(&path, &["pypi.org".to_string(), "github.com".to_string()]).unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.default.allow_domains.as_deref(), Some("github.com pypi.org"));
+        assert_eq!(config.resources.memory.as_deref(), Some("8g"));
+        assert!(path.with_extension("toml.bak").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,34 @@
 use clap::{Args, Parser, Subcommand};
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
+mod approval;
+mod audit;
+mod certs;
+mod cidr;
 mod config;
+mod dns;
+mod domains;
+mod egress;
+mod firewall;
+mod http;
+mod intercept;
+mod learn;
+mod network;
+mod presets;
+mod proxy;
+mod refresh;
+mod resolve;
+mod snapshot;
 mod update;
+mod volumes;
+mod wire;
+mod workspace;
+mod worktree;
 
 // Embed the default seccomp profile at compile time
 const DEFAULT_SECCOMP_PROFILE: &str = include_str!("../seccomp/seccomp-default.json");
@@ -30,6 +53,27 @@ const DEFAULT_AGENT: &str = "claude";
 // Default audit log level
 const DEFAULT_AUDIT_LOG: &str = "none";
 
+// Default network mode ("firewall" or "proxy")
+const DEFAULT_NETWORK_MODE: &str = "firewall";
+
+// Default workspace mode ("bind" or "overlay")
+const DEFAULT_WORKSPACE: &str = "bind";
+
+// Default size above which an ignored path is left out of a git snapshot
+const DEFAULT_SNAPSHOT_IGNORED_LIMIT: &str = "100MB";
+
+// Address of the egress proxy inside the container (used in "proxy" network mode)
+const EGRESS_PROXY_ADDR: &str = "127.0.0.1:3128";
+
+// Address of the DNS forwarder inside the container
+const DNS_FORWARDER_ADDR: &str = "127.0.0.1:53";
+
+// Directory inside the container where the generated firewall rules are mounted
+const FIREWALL_RULES_DIR: &str = "/etc/rustyolo";
+
+// Default interval (in seconds) for re-resolving trusted domains during a session
+const DEFAULT_REFRESH_INTERVAL: u64 = 300;
+
 /// A secure, firewalled Docker wrapper for AI agents.
 ///
 /// This tool builds a 'docker run' command to enforce four layers of security:
@@ -64,15 +108,262 @@ enum Commands {
         #[arg(long)]
         yes: bool,
     },
+
+    /// Inspect the sandbox firewall
+    #[command(subcommand)]
+    Firewall(FirewallCommand),
+
+    /// Run the agent and propose trusted domains from what it tried to reach
+    Learn {
+        #[command(flatten)]
+        run_args: Box<RunArgs>,
+    },
+
+    /// Answer requests from a session started with --live-approval
+    Approve {
+        /// Process ID of the session (needed when several are running)
+        session: Option<String>,
+    },
+
+    /// Restore the project to its state before a session
+    Rollback {
+        /// Process ID of the session (default: the latest in the current directory)
+        session: Option<String>,
+
+        /// List the snapshots instead
+        #[arg(long)]
+        list: bool,
+
+        /// Restore without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Internal helpers that run inside the sandbox container
+    #[command(hide = true, subcommand)]
+    Sandbox(Box<SandboxCommand>),
+}
+
+#[derive(Subcommand, Debug)]
+enum FirewallCommand {
+    /// Print the iptables rules a run with these options would apply
+    Show {
+        #[command(flatten)]
+        run_args: Box<RunArgs>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SandboxCommand {
+    /// Run the domain-aware egress proxy
+    Proxy {
+        /// Address to listen on
+        #[arg(long, default_value = EGRESS_PROXY_ADDR)]
+        listen: String,
+
+        /// Space-separated addresses (ip:port) of the corporate proxy to tunnel through
+        #[arg(long, env = "UPSTREAM_PROXY", default_value = "")]
+        upstream_proxy: String,
+
+        /// Credentials (user:password) for the corporate proxy
+        #[arg(long, env = "UPSTREAM_PROXY_AUTH", hide_env_values = true)]
+        upstream_proxy_auth: Option<String>,
+
+        /// File to write the CA certificate for intercepting hosts with HTTP policies to
+        #[arg(long)]
+        intercept_ca: Option<PathBuf>,
+
+        #[command(flatten)]
+        policy: SandboxPolicy,
+    },
+
+    /// Resolve trusted domains into the firewall and keep re-resolving them
+    Refresh {
+        /// Seconds between refreshes (0 = resolve once and exit)
+        #[arg(long, env = "REFRESH_INTERVAL", default_value_t = 0)]
+        interval: u64,
+
+        /// File to create once the initial rules are in place
+        #[arg(long)]
+        ready_file: Option<PathBuf>,
+
+        /// Also allow IPv6 addresses through ip6tables
+        #[arg(long, env = "IPV6_FIREWALL")]
+        ipv6: bool,
+
+        /// Addresses resolved on the host, as space-separated domain=ip,ip entries
+        #[arg(long, env = "PINNED_ADDRESSES", default_value = "")]
+        pinned: String,
+
+        /// Space-separated DNS servers to check the pinned addresses against again
+        #[arg(long, env = "DNS_SERVERS", default_value = "")]
+        dns_servers: String,
+
+        /// File to write the re-checked pinned addresses to, for the DNS forwarder
+        #[arg(long)]
+        pins_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        policy: SandboxPolicy,
+    },
+
+    /// Open the host ports on the Docker host's gateway IP
+    HostPorts {
+        /// Comma-separated TCP ports on the host
+        #[arg(long, env = "HOST_PORTS", value_delimiter = ',')]
+        ports: Vec<u16>,
+
+        /// Also allow an IPv6 gateway address through ip6tables
+        #[arg(long, env = "IPV6_FIREWALL")]
+        ipv6: bool,
+
+        /// Audit logging level ("none", "basic" or "verbose")
+        #[arg(long, env = "AUDIT_LOG", default_value = DEFAULT_AUDIT_LOG)]
+        audit_log: String,
+    },
+
+    /// Run the DNS forwarder that only answers for trusted names
+    Dns {
+        /// Address to listen on
+        #[arg(long, default_value = DNS_FORWARDER_ADDR)]
+        listen: String,
+
+        /// Space-separated list of upstream DNS server IPs
+        #[arg(long)]
+        upstream: String,
+
+        /// Only filter queries; do not add firewall rules for the answers
+        #[arg(long)]
+        no_firewall: bool,
+
+        /// Also allow IPv6 addresses through ip6tables
+        #[arg(long, env = "IPV6_FIREWALL")]
+        ipv6: bool,
+
+        /// Addresses resolved on the host, as space-separated domain=ip,ip entries
+        #[arg(long, env = "PINNED_ADDRESSES", default_value = "")]
+        pinned: String,
+
+        /// File the refresher writes re-checked pinned addresses to
+        #[arg(long)]
+        pins_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        policy: SandboxPolicy,
+    },
+
+    /// Write the egress byte counters to the usage directory shared with the host
+    Egress {
+        /// Directory to write the counters to
+        #[arg(long, default_value = egress::STATS_DIR)]
+        stats_dir: PathBuf,
+
+        /// Also read the ip6tables counters
+        #[arg(long, env = "IPV6_FIREWALL")]
+        ipv6: bool,
+    },
+
+    /// Record the destinations of packets the firewall dropped (learn mode)
+    Learn {
+        /// Space-separated list of upstream DNS server IPs, for reverse lookups
+        #[arg(long)]
+        upstream: String,
+
+        /// Directory shared with the host
+        #[arg(long, env = "LEARN_DIR", default_value = learn::LEARN_DIR)]
+        learn_dir: PathBuf,
+    },
+}
+
+/// Policy settings shared by the in-sandbox helpers, passed in by the Rust wrapper as
+/// environment variables
+#[derive(Args, Debug)]
+struct SandboxPolicy {
+    /// Space-separated list of trusted domains and patterns
+    #[arg(long, env = "TRUSTED_DOMAINS", default_value = "")]
+    allow_domains: String,
+
+    /// Space-separated list of domains to refuse even if they are trusted
+    #[arg(long, env = "DENY_DOMAINS", default_value = "")]
+    deny_domains: String,
+
+    /// Space-separated networks exempted from the blocked private ranges
+    #[arg(long, env = "ALLOW_PRIVATE", default_value = "")]
+    allow_private: String,
+
+    /// Egress quota on all outbound traffic, in bytes
+    #[arg(long, env = "MAX_EGRESS", default_value = "")]
+    max_egress: String,
+
+    /// Space-separated per-domain egress quotas (domain=bytes)
+    #[arg(long, env = "EGRESS_QUOTAS", default_value = "")]
+    egress_quotas: String,
+
+    /// Audit logging level ("none", "basic" or "verbose")
+    #[arg(long, env = "AUDIT_LOG", default_value = DEFAULT_AUDIT_LOG)]
+    audit_log: String,
+
+    /// Directory to record blocked destinations in (learn mode)
+    #[arg(long, env = "LEARN_DIR")]
+    learn_dir: Option<PathBuf>,
+
+    /// Socket to ask the host about blocked names on (live approval)
+    #[arg(long, env = "APPROVAL_SOCKET")]
+    approval_socket: Option<PathBuf>,
+
+    /// HTTP method and path policies, as JSON
+    #[arg(long, env = "HTTP_POLICIES", default_value = "")]
+    http_policies: String,
+}
+
+impl SandboxPolicy {
+    fn allowlist(&self) -> Result<Vec<domains::AllowEntry>, String> {
+        domains::parse_list(&self.allow_domains)
+    }
+
+    fn denylist(&self) -> Result<Vec<domains::DomainPattern>, String> {
+        domains::parse_patterns(&self.deny_domains)
+    }
+
+    fn blocklist(&self) -> Result<cidr::Blocklist, String> {
+        cidr::parse_list(&self.allow_private).map(cidr::Blocklist::new)
+    }
+
+    fn quotas(&self) -> Result<egress::Quotas, String> {
+        egress::Quotas::parse(Some(&self.max_egress), std::slice::from_ref(&self.egress_quotas))
+    }
+
+    fn audit(&self) -> audit::AuditLevel {
+        audit::AuditLevel::parse(&self.audit_log).unwrap_or(audit::AuditLevel::None)
+    }
+
+    fn recorder(&self, file: &str) -> Option<learn::Recorder> {
+        self.learn_dir.as_deref().map(|dir| learn::Recorder::new(dir, file))
+    }
+
+    fn approval(&self) -> Option<approval::Client> {
+        self.approval_socket.as_deref().map(approval::Client::new)
+    }
+
+    fn http_policies(&self) -> Result<http::Policies, String> {
+        if self.http_policies.is_empty() {
+            return Ok(http::Policies::default());
+        }
+        let policies: Vec<config::HttpPolicy> = serde_json::from_str(&self.http_policies)
+            .map_err(|e| format!("Invalid HTTP policies: {e}"))?;
+        http::Policies::parse(&policies)
+    }
 }
 
+// CLI switches are naturally booleans
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Args, Debug)]
 struct RunArgs {
     /// The agent to run (e.g., 'claude', 'codex', 'gemini-cli').
     #[arg(default_value = DEFAULT_AGENT)]
     agent: String,
 
-    /// Additional volumes to mount (e.g., `-v ~/.ssh:/home/agent/.ssh:ro`)
+    /// Additional volumes to mount (e.g., `-v ~/.gitconfig:/home/agent/.gitconfig:ro`)
     #[arg(short = 'v', long = "volume")]
     volumes: Vec<String>,
 
@@ -82,17 +373,69 @@ struct RunArgs {
 
     /// Space-separated list of domains to allow outbound traffic to.
     /// All other traffic (except DNS) will be blocked.
+    /// Wildcards ("*.githubusercontent.com", subdomains only) and suffixes
+    /// (".githubusercontent.com", domain and subdomains) are allowed once resolved.
     /// Example: --allow-domains "github.com pypi.org npmjs.com"
     /// Note: Anthropic domains are automatically added when using Claude.
     #[arg(long, env = "TRUSTED_DOMAINS")]
     allow_domains: Option<String>,
 
+    /// Space-separated list of domains to block even if they match the trusted domains,
+    /// e.g. well-known exfiltration channels. Wildcards and suffixes are supported.
+    /// Example: --deny-domains "gist.github.com uploads.github.com"
+    #[arg(long)]
+    deny_domains: Option<String>,
+
+    /// Comma-separated allowlist presets to add to the trusted domains.
+    /// Built-in: github, go, node, python, rust. Define your own under [presets]
+    /// in the global config file (~/.config/rustyolo.toml on Linux).
+    /// Example: --allow-preset python,github
+    #[arg(long = "allow-preset", value_delimiter = ',')]
+    allow_presets: Vec<String>,
+
+    /// Space-separated list of IP addresses or CIDR ranges to allow outbound traffic to,
+    /// for hosts that are only reachable by IP (IPv4 or IPv6).
+    /// Example: --allow-cidrs "10.20.0.0/16 192.168.1.10"
+    #[arg(long)]
+    allow_cidrs: Option<String>,
+
+    /// Space-separated networks exempted from the blocked private, loopback, link-local
+    /// and metadata ranges. Trusted domains may resolve into them and the agent may
+    /// connect to them.
+    /// Example: --allow-private "10.20.0.0/16"
+    #[arg(long)]
+    allow_private: Option<String>,
+
     /// Mount a persistent auth directory. Maps your local dir
     /// to '/home/agent/.config/rustyolo' in the container.
     /// Recommended: ~/.config/rustyolo
     #[arg(long = "auth-home")]
     auth_home: Option<PathBuf>,
 
+    /// How the project directory is mounted at /app (default: bind).
+    /// - bind: the project itself, read-write; edits land immediately
+    /// - overlay: a scratch copy; its changes are reviewed and applied (or discarded)
+    ///   when the container exits
+    #[arg(long, default_value = DEFAULT_WORKSPACE)]
+    workspace: String,
+
+    /// Run the session in a new git worktree on its own branch (rustyolo/<session>),
+    /// created from the current HEAD and mounted at /app instead of the project.
+    /// The branch is reported when the container exits.
+    #[arg(long)]
+    worktree: bool,
+
+    /// Don't snapshot the project before the session. By default, a snapshot is taken
+    /// that `rustyolo rollback` restores.
+    #[arg(long)]
+    no_snapshot: bool,
+
+    /// Size above which an ignored path of a git repository (e.g., target/) is left out
+    /// of the snapshot, and left alone by rollback. Use "none" to snapshot all ignored
+    /// files.
+    #[arg(long, default_value = DEFAULT_SNAPSHOT_IGNORED_LIMIT)]
+    snapshot_ignored_limit: String,
+
     /// The Docker image to use.
     #[arg(long, default_value = DEFAULT_IMAGE)]
     image: String,
@@ -141,6 +484,11 @@ struct RunArgs {
     #[arg(long, default_value = DEFAULT_DNS_SERVERS)]
     dns_servers: String,
 
+    /// Disable IPv6 inside the container instead of firewalling it.
+    /// By default, trusted domains and DNS servers are allowed over both IPv4 and IPv6.
+    #[arg(long)]
+    disable_ipv6: bool,
+
     /// Enable audit logging of security events (default: none).
     /// - none: No audit logging (default)
     /// - basic: Log blocked network connections and syscalls
@@ -150,6 +498,79 @@ struct RunArgs {
     #[arg(long, default_value = DEFAULT_AUDIT_LOG)]
     audit_log: String,
 
+    /// How outbound traffic is filtered (default: firewall).
+    /// - firewall: iptables rules for the resolved IPs of trusted domains
+    /// - proxy: only an in-container proxy may connect out; it checks TLS SNI and HTTP Host
+    /// - offline: no network at all (loopback only); the firewall is skipped
+    #[arg(long = "network", default_value = DEFAULT_NETWORK_MODE)]
+    network_mode: String,
+
+    /// Seconds between re-resolving trusted domains in firewall mode (default: 300).
+    /// New IPs are allowed and IPs that stop resolving are expired. Use 0 to resolve
+    /// only once at startup.
+    #[arg(long, default_value_t = DEFAULT_REFRESH_INTERVAL)]
+    refresh_interval: u64,
+
+    /// Only open ports 80 and 443 for trusted domains listed without a port.
+    /// Entries with an explicit port (e.g., "git.internal:22") are unaffected.
+    #[arg(long)]
+    strict_ports: bool,
+
+    /// Comma-separated TCP ports on the Docker host the agent may reach through
+    /// host.docker.internal (e.g., a local LLM or database). Nothing else on the
+    /// host is reachable.
+    /// Example: --host-ports 11434,5432
+    #[arg(long, value_delimiter = ',')]
+    host_ports: Vec<u16>,
+
+    /// Corporate HTTP proxy that all allowed traffic must go through. Implies proxy mode.
+    /// Example: `--upstream-proxy http://proxy.corp:3128`
+    #[arg(long)]
+    upstream_proxy: Option<String>,
+
+    /// Egress quota on all outbound traffic for the session. The session is stopped
+    /// once it is used up.
+    /// Example: --max-egress 200MB
+    #[arg(long)]
+    max_egress: Option<String>,
+
+    /// Per-domain egress quota as domain=size. Can be specified multiple times.
+    /// Example: --egress-quota github.com=50MB
+    #[arg(long = "egress-quota", value_delimiter = ',')]
+    egress_quotas: Vec<String>,
+
+    /// Ask before refusing names outside the allowlist; answer with `rustyolo approve` in
+    /// another terminal
+    #[arg(long)]
+    live_approval: bool,
+
+    /// Running container the agent may reach by name on the session network (e.g., a
+    /// database). Can be specified multiple times.
+    /// Example: --sidecar postgres-dev
+    #[arg(long = "sidecar")]
+    sidecars: Vec<String>,
+
+    /// PEM CA certificate to trust inside the sandbox, e.g. the root of a TLS-inspecting
+    /// corporate gateway. Can be specified multiple times.
+    /// Example: --ca-cert ~/corp-root.pem
+    #[arg(long = "ca-cert")]
+    ca_certificates: Vec<String>,
+
+    /// Host path that volumes may not mount, in addition to the built-in deny tree
+    /// (system directories, credentials, container runtime sockets). `~` expands and `*`
+    /// matches within a name. Can be specified multiple times.
+    /// Example: --deny-mount ~/.kube
+    #[arg(long = "deny-mount")]
+    deny_mounts: Vec<String>,
+
+    /// HTTP methods and paths allowed on specific hosts (`[network] http_policies` only)
+    #[arg(skip)]
+    http_policies: Vec<config::HttpPolicy>,
+
+    /// Record blocked destinations and offer to trust them (set by `rustyolo learn`)
+    #[arg(skip)]
+    learn: bool,
+
     /// Print the Docker command without executing it (dry run mode)
     #[arg(long)]
     dry_run: bool,
@@ -162,6 +583,44 @@ fn main() {
         Some(Commands::Update { binary, image, yes }) => {
             handle_update(binary, image, yes);
         }
+        Some(Commands::Firewall(FirewallCommand::Show { mut run_args })) => {
+            if let Ok(Some(config)) = config::Config::try_load_from_current_dir() {
+                merge_config_with_args(&mut run_args, config);
+            }
+            show_firewall(&run_args);
+        }
+        Some(Commands::Learn { mut run_args }) => {
+            if let Ok(Some(config)) = config::Config::try_load_from_current_dir() {
+                println!("[RustyYOLO] Loaded configuration from {}", config::CONFIG_FILE);
+                merge_config_with_args(&mut run_args, config);
+            }
+            if is_offline(&run_args.network_mode) {
+                eprintln!(
+                    "[RustyYOLO] ❌ Learn mode needs a network; it cannot run in offline mode."
+                );
+                std::process::exit(1);
+            }
+            run_args.learn = true;
+            if !run_args.skip_version_check {
+                check_for_updates();
+            }
+            run_agent(*run_args);
+        }
+        Some(Commands::Approve { session }) => {
+            if let Err(e) = approval::run_operator(session.as_deref()) {
+                eprintln!("[RustyYOLO] ❌ {e}");
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Rollback { session, list, yes }) => {
+            if let Err(e) = snapshot::run_rollback(session.as_deref(), list, yes) {
+                eprintln!("[RustyYOLO] ❌ {e}");
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Sandbox(helper)) => {
+            run_sandbox_helper(*helper);
+        }
         None => {
             // Run mode - check for updates first unless skipped
             let mut run_args = cli.run_args.unwrap_or_else(|| RunArgs {
@@ -169,7 +628,15 @@ fn main() {
                 volumes: Vec::new(),
                 envs: Vec::new(),
                 allow_domains: None,
+                deny_domains: None,
+                allow_presets: Vec::new(),
+                allow_cidrs: None,
+                allow_private: None,
                 auth_home: None,
+                workspace: DEFAULT_WORKSPACE.to_string(),
+                worktree: false,
+                no_snapshot: false,
+                snapshot_ignored_limit: DEFAULT_SNAPSHOT_IGNORED_LIMIT.to_string(),
                 image: DEFAULT_IMAGE.to_string(),
                 additional: Vec::new(),
                 skip_version_check: false,
@@ -179,7 +646,21 @@ fn main() {
                 cpus: DEFAULT_CPUS.to_string(),
                 pids_limit: DEFAULT_PIDS_LIMIT.to_string(),
                 dns_servers: DEFAULT_DNS_SERVERS.to_string(),
+                disable_ipv6: false,
                 audit_log: DEFAULT_AUDIT_LOG.to_string(),
+                network_mode: DEFAULT_NETWORK_MODE.to_string(),
+                refresh_interval: DEFAULT_REFRESH_INTERVAL,
+                strict_ports: false,
+                host_ports: Vec::new(),
+                upstream_proxy: None,
+                max_egress: None,
+                egress_quotas: Vec::new(),
+                live_approval: false,
+                sidecars: Vec::new(),
+                ca_certificates: Vec::new(),
+                deny_mounts: Vec::new(),
+                http_policies: Vec::new(),
+                learn: false,
                 dry_run: false,
             });
 
@@ -260,6 +741,119 @@ fn handle_update(binary_only: bool, image_only: bool, yes: bool) {
     }
 }
 
+/// Runs one of the helpers that live inside the sandbox container.
+///
+/// These are started by `entrypoint.sh` and are not meant to be run on the host.
+fn run_sandbox_helper(helper: SandboxCommand) {
+    if let Err(e) = sandbox_helper(helper) {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        std::process::exit(1);
+    }
+}
+
+fn sandbox_helper(helper: SandboxCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match helper {
+        SandboxCommand::Proxy {
+            listen,
+            upstream_proxy,
+            upstream_proxy_auth,
+            intercept_ca,
+            policy,
+        } => {
+            let addrs = upstream_proxy
+                .split_whitespace()
+                .map(|addr| addr.parse().map_err(|e| format!("Invalid upstream proxy {addr}: {e}")))
+                .collect::<Result<Vec<std::net::SocketAddr>, String>>()?;
+            let upstream = (!addrs.is_empty())
+                .then_some(proxy::Upstream { addrs, credentials: upstream_proxy_auth });
+            let http_policies = policy.http_policies()?;
+            let interceptor = if http_policies.is_empty() {
+                None
+            } else {
+                let interceptor = intercept::Interceptor::new(&http_policies)?;
+                if let Some(path) = &intercept_ca {
+                    fs::write(path, interceptor.ca_pem())
+                        .map_err(|e| format!("Failed to write {}: {e}", path.display()))?;
+                }
+                Some(interceptor)
+            };
+            let config = proxy::ProxyConfig {
+                allowlist: policy.allowlist()?,
+                denylist: policy.denylist()?,
+                blocklist: policy.blocklist()?,
+                learn: policy.recorder("proxy.log"),
+                approval: policy.approval(),
+                upstream,
+                http_policies,
+                interceptor,
+                audit: policy.audit(),
+            };
+            proxy::run(&listen, config)
+        }
+        SandboxCommand::Refresh {
+            interval,
+            ready_file,
+            ipv6,
+            pinned,
+            dns_servers,
+            pins_file,
+            policy,
+        } => {
+            let config = refresh::RefreshConfig {
+                entries: policy.allowlist()?.into_iter().filter(|e| e.pattern.is_exact()).collect(),
+                pinned: resolve::parse_pins(&pinned)?,
+                servers: dns_servers
+                    .split_whitespace()
+                    .map(|ip| ip.parse().map_err(|_| format!("Invalid DNS server '{ip}'")))
+                    .collect::<Result<_, _>>()?,
+                pins_file,
+                interval,
+                ipv6,
+                blocklist: policy.blocklist()?,
+                quotas: policy.quotas()?,
+                ready_file,
+                audit: policy.audit(),
+            };
+            refresh::run(&config)
+        }
+        SandboxCommand::HostPorts { ports, ipv6, audit_log } => Ok(firewall::allow_host_ports(
+            &ports,
+            ipv6,
+            audit::AuditLevel::parse(&audit_log).unwrap_or(audit::AuditLevel::None),
+        )?),
+        SandboxCommand::Dns { listen, upstream, no_firewall, ipv6, pinned, pins_file, policy } => {
+            let config = dns::ForwarderConfig {
+                upstream: upstream
+                    .split_whitespace()
+                    .filter_map(|ip| ip.parse::<std::net::IpAddr>().ok())
+                    .map(|ip| std::net::SocketAddr::new(ip, 53))
+                    .collect(),
+                allowlist: policy.allowlist()?,
+                denylist: policy.denylist()?,
+                pinned: resolve::parse_pins(&pinned)?,
+                pins_file,
+                update_firewall: !no_firewall,
+                ipv6,
+                blocklist: policy.blocklist()?,
+                quotas: policy.quotas()?,
+                learn: policy.recorder("dns.log"),
+                approval: policy.approval(),
+                audit: policy.audit(),
+            };
+            dns::run(&listen, config)
+        }
+        SandboxCommand::Egress { stats_dir, ipv6 } => egress::monitor(&stats_dir, ipv6),
+        SandboxCommand::Learn { upstream, learn_dir } => {
+            let upstream: Vec<std::net::SocketAddr> = upstream
+                .split_whitespace()
+                .filter_map(|ip| ip.parse::<std::net::IpAddr>().ok())
+                .map(|ip| std::net::SocketAddr::new(ip, 53))
+                .collect();
+            learn::watch_blocked_ips(&learn::Recorder::new(&learn_dir, "ip.log"), &upstream)
+        }
+    }
+}
+
 /// Merges configuration file settings with command-line arguments.
 ///
 /// CLI arguments always take precedence over config file settings.
@@ -275,6 +869,21 @@ fn merge_config_with_args(args: &mut RunArgs, config: config::Config) {
         args.allow_domains = config.default.allow_domains;
     }
 
+    if args.deny_domains.is_none() {
+        args.deny_domains = config.default.deny_domains;
+    }
+
+    // Merge presets - only if CLI didn't provide any
+    if args.allow_presets.is_empty() {
+        if let Some(config_presets) = config.default.allow_presets {
+            args.allow_presets = config_presets;
+        }
+    }
+
+    if args.allow_cidrs.is_none() {
+        args.allow_cidrs = config.default.allow_cidrs;
+    }
+
     // Merge volumes - only if CLI didn't provide any
     if args.volumes.is_empty() {
         if let Some(config_volumes) = config.default.volumes {
@@ -293,6 +902,27 @@ fn merge_config_with_args(args: &mut RunArgs, config: config::Config) {
         args.auth_home = config.default.auth_home;
     }
 
+    // Only override workspace if it's still the default
+    if args.workspace == DEFAULT_WORKSPACE {
+        if let Some(config_workspace) = config.default.workspace {
+            args.workspace = config_workspace;
+        }
+    }
+
+    if !args.worktree {
+        args.worktree = config.default.worktree.unwrap_or(false);
+    }
+
+    if !args.no_snapshot {
+        args.no_snapshot = !config.default.snapshot.unwrap_or(true);
+    }
+
+    if args.snapshot_ignored_limit == DEFAULT_SNAPSHOT_IGNORED_LIMIT {
+        if let Some(config_limit) = config.default.snapshot_ignored_limit {
+            args.snapshot_ignored_limit = config_limit;
+        }
+    }
+
     // Only override image if it's still the default
     if args.image == DEFAULT_IMAGE {
         if let Some(config_image) = config.default.image {
@@ -337,6 +967,10 @@ fn merge_config_with_args(args: &mut RunArgs, config: config::Config) {
         }
     }
 
+    if !args.disable_ipv6 {
+        args.disable_ipv6 = config.security.disable_ipv6.unwrap_or(false);
+    }
+
     if args.audit_log == DEFAULT_AUDIT_LOG {
         if let Some(config_audit_log) = config.security.audit_log {
             args.audit_log = config_audit_log;
@@ -346,6 +980,87 @@ fn merge_config_with_args(args: &mut RunArgs, config: config::Config) {
     if args.inject_message.is_none() {
         args.inject_message = config.security.inject_message;
     }
+
+    // Merge CA certificates - only if CLI didn't provide any
+    if args.ca_certificates.is_empty() {
+        if let Some(config_certificates) = config.security.ca_certificates {
+            args.ca_certificates = config_certificates;
+        }
+    }
+
+    // Merge denied mounts - only if CLI didn't provide any
+    if args.deny_mounts.is_empty() {
+        if let Some(config_deny_mounts) = config.security.deny_mounts {
+            args.deny_mounts = config_deny_mounts;
+        }
+    }
+
+    merge_network_config(args, config.network);
+}
+
+/// Merges the `[network]` section of the configuration file with command-line arguments.
+fn merge_network_config(args: &mut RunArgs, network: config::NetworkConfig) {
+    if args.network_mode == DEFAULT_NETWORK_MODE {
+        if let Some(config_mode) = network.mode {
+            args.network_mode = config_mode;
+        }
+    }
+
+    if args.refresh_interval == DEFAULT_REFRESH_INTERVAL {
+        if let Some(config_interval) = network.refresh_interval {
+            args.refresh_interval = config_interval;
+        }
+    }
+
+    if !args.strict_ports {
+        args.strict_ports = network.strict_ports.unwrap_or(false);
+    }
+
+    if !args.live_approval {
+        args.live_approval = network.live_approval.unwrap_or(false);
+    }
+
+    if args.upstream_proxy.is_none() {
+        args.upstream_proxy = network.upstream_proxy;
+    }
+
+    // Merge sidecars - only if CLI didn't provide any
+    if args.sidecars.is_empty() {
+        if let Some(config_sidecars) = network.sidecars {
+            args.sidecars = config_sidecars;
+        }
+    }
+
+    if args.http_policies.is_empty() {
+        if let Some(config_policies) = network.http_policies {
+            args.http_policies = config_policies;
+        }
+    }
+
+    // Merge host ports - only if CLI didn't provide any
+    if args.host_ports.is_empty() {
+        if let Some(config_host_ports) = network.host_ports {
+            args.host_ports = config_host_ports;
+        }
+    }
+
+    if args.allow_private.is_none() {
+        args.allow_private = network.allow_private;
+    }
+
+    if args.max_egress.is_none() {
+        args.max_egress = network.max_egress;
+    }
+
+    // Merge per-domain egress quotas - only if CLI didn't provide any
+    if args.egress_quotas.is_empty() {
+        if let Some(config_quotas) = network.egress_quotas {
+            args.egress_quotas = config_quotas
+                .into_iter()
+                .map(|(domain, size)| format!("{domain}={size}"))
+                .collect();
+        }
+    }
 }
 
 fn check_for_updates() {
@@ -447,77 +1162,114 @@ fn setup_seccomp(docker_cmd: &mut Command, seccomp_profile: Option<&str>) -> Opt
     }
 }
 
+/// Mounts custom CA certificates for the entrypoint to install into the system trust store.
+///
+/// Tools that bring their own CA bundle are pointed at it as well: Node.js through
+/// `NODE_EXTRA_CA_CERTS`, Python `requests` through `REQUESTS_CA_BUNDLE`, and OpenSSL-based
+/// tools through `SSL_CERT_FILE`.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - The Docker command being built
+/// * `certificates` - Paths to PEM certificate files; `~` expands to the home directory
+///
+/// # Returns
+///
+/// The host-side copy of the certificates, which must be kept until the container exits
+///
+/// # Panics
+///
+/// Exits the process if a certificate cannot be read or is not in PEM format.
+fn setup_ca_certificates(
+    docker_cmd: &mut Command,
+    certificates: &[String],
+) -> Option<certs::CaBundle> {
+    if certificates.is_empty() {
+        return None;
+    }
+    let paths: Vec<PathBuf> = certificates.iter().map(|c| config::expand_home(c)).collect();
+    let (bundle, count) = certs::CaBundle::create(&paths).unwrap_or_else(|e| {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        std::process::exit(1);
+    });
+    println!("[RustyYOLO] Trusting {count} custom CA certificate(s)");
+
+    docker_cmd
+        .arg("-v")
+        .arg(format!("{}:{}:ro", bundle.dir().display(), certs::CA_DIR));
+    docker_cmd.arg("-e").arg(format!(
+        "NODE_EXTRA_CA_CERTS={}/{}",
+        certs::CA_DIR,
+        certs::EXTRA_BUNDLE
+    ));
+    docker_cmd.arg("-e").arg(format!("REQUESTS_CA_BUNDLE={}", certs::SYSTEM_BUNDLE));
+    docker_cmd.arg("-e").arg(format!("SSL_CERT_FILE={}", certs::SYSTEM_BUNDLE));
+    Some(bundle)
+}
+
 /// Validates user-supplied volumes for dangerous mounts that could enable container escape.
 ///
-/// This function performs security checks on volume mount specifications to prevent:
-/// - Docker socket mounting (complete container escape)
-/// - Mounting critical system directories (/proc, /sys, /dev, /boot, /etc)
+/// Each volume is parsed into a [`volumes::VolumeSpec`]: its host path is expanded (`~`,
+/// `$HOME`), normalized and resolved through symlinks, then checked against the deny tree.
+/// This prevents:
+/// - Container runtime socket mounting (complete container escape)
+/// - Mounting critical system directories (/etc, /proc, /sys, /dev, /boot, /root)
+/// - Mounting credentials (~/.aws, SSH private keys) or a directory containing them
 ///
 /// # Arguments
 ///
 /// * `volumes` - Slice of volume mount specifications (e.g., "/host/path:/container/path:ro")
+/// * `deny_mounts` - Additional host paths to deny (`--deny-mount` / `deny_mounts`)
 ///
 /// # Returns
 ///
-/// * `Some(String)` - Error message describing the security risk if a dangerous volume is detected
-/// * `None` - All volumes are safe to mount
+/// * `Ok(Vec<VolumeSpec>)` - The parsed volumes, to be mounted with their resolved host paths
+/// * `Err(String)` - Why a volume is invalid or dangerous, naming the rule that matched
 ///
 /// # Security
 ///
 /// This is a critical security function that prevents privilege escalation and container escape.
 /// It blocks mounts that would allow an agent to:
-/// - Spawn new containers via Docker socket
-/// - Inspect or manipulate host processes via /proc
+/// - Spawn new containers via the Docker (or another runtime's) socket
+/// - Inspect or manipulate host processes via /proc, or reach the host filesystem via
+///   /proc/<pid>/root
 /// - Access raw devices via /dev
 /// - Modify system configuration via /etc, /sys
 /// - Access boot files via /boot
+/// - Read cloud credentials or SSH private keys
+///
+/// Checking the resolved path catches symlinks and spellings such as `//etc` or `/etc/`.
 ///
 /// # Examples
 ///
 /// ```no_run
 /// // Safe volumes pass validation
-/// let safe = vec!["~/.ssh:/home/agent/.ssh:ro".to_string()];
-/// assert!(validate_volumes(&safe).is_none());
+/// let safe = vec!["~/.ssh/known_hosts:/home/agent/.ssh/known_hosts:ro".to_string()];
+/// assert!(validate_volumes(&safe, &[]).is_ok());
 ///
 /// // Dangerous volumes are rejected
 /// let dangerous = vec!["/var/run/docker.sock:/var/run/docker.sock".to_string()];
-/// assert!(validate_volumes(&dangerous).is_some());
+/// assert!(validate_volumes(&dangerous, &[]).is_err());
 /// ```
-fn validate_volumes(volumes: &[String]) -> Option<String> {
-    for volume in volumes {
-        let vol_lower = volume.to_lowercase();
-
-        // Check for Docker socket - enables complete container escape
-        if vol_lower.contains("docker.sock") {
-            return Some(format!(
-                "Mounting the Docker socket is forbidden (security risk: container escape).\n\
-                 Attempted mount: {volume}\n\
-                 This would allow the agent to spawn new containers and bypass all security restrictions."
-            ));
-        }
-
-        // Check for other dangerous system mounts
-        let dangerous_paths = [
-            ("/proc", "process information"),
-            ("/sys", "system configuration"),
-            ("/dev", "devices"),
-            ("/boot", "boot files"),
-            ("/etc", "system configuration"),
-        ];
-
-        for (path, description) in &dangerous_paths {
-            // Match exact mount of these system directories (not subdirectories in user projects)
-            // e.g., block "-v /proc:/proc" but allow "-v /home/user/myproc:/myproc"
-            if vol_lower.starts_with(&format!("{path}:")) {
-                return Some(format!(
-                    "Mounting {path} is forbidden (security risk: {description}).\n\
-                     Attempted mount: {volume}\n\
-                     If you need specific files, mount them individually rather than the entire directory."
-                ));
-            }
-        }
-    }
-    None
+fn validate_volumes(
+    volumes: &[String],
+    deny_mounts: &[String],
+) -> Result<Vec<volumes::VolumeSpec>, String> {
+    let cwd = env::current_dir().map_err(|e| format!("Failed to get current directory: {e}"))?;
+    let mut rules = volumes::default_rules();
+    rules.extend(
+        deny_mounts
+            .iter()
+            .map(|entry| volumes::DenyRule::new(entry, "listed in deny_mounts")),
+    );
+    volumes
+        .iter()
+        .map(|volume| {
+            let spec = volumes::VolumeSpec::parse(volume, &cwd)?;
+            volumes::check(&spec, &rules)?;
+            Ok(spec)
+        })
+        .collect()
 }
 
 /// Applies resource limits to the Docker command to prevent `DoS` attacks and resource exhaustion.
@@ -580,7 +1332,7 @@ fn apply_resource_limits(docker_cmd: &mut Command, memory: &str, cpus: &str, pid
 /// # Arguments
 ///
 /// * `docker_cmd` - Mutable reference to the Docker command being constructed
-/// * `dns_servers` - Space-separated list of allowed DNS server IPs, or "any" to disable restrictions
+/// * `dns_servers` - The allowed DNS servers (see [`parse_dns_servers`])
 ///
 /// # Security
 ///
@@ -588,31 +1340,63 @@ fn apply_resource_limits(docker_cmd: &mut Command, memory: &str, cpus: &str, pid
 /// well-known servers that are unlikely to be controlled by attackers. Restricting DNS
 /// prevents using arbitrary servers for data exfiltration.
 ///
-/// Setting `dns_servers` to "any" disables this protection and is **not recommended**.
+/// Only the in-sandbox DNS forwarder may query these servers. The agent resolves through
+/// the forwarder, which answers NXDOMAIN for names outside the allowlist, so queries like
+/// `secret-data.attacker.com` never reach a public resolver.
+///
+/// Setting `dns_servers` to "any" lets the agent query any server directly and is
+/// **not recommended**.
 ///
 /// # Examples
 ///
 /// ```no_run
 /// use std::process::Command;
 /// let mut cmd = Command::new("docker");
-/// configure_dns_restrictions(&mut cmd, "8.8.8.8 1.1.1.1");
+/// configure_dns_restrictions(&mut cmd, &parse_dns_servers("8.8.8.8 1.1.1.1"));
 /// ```
-fn configure_dns_restrictions(docker_cmd: &mut Command, dns_servers: &str) {
-    if dns_servers.to_lowercase() == "any" {
-        println!("[RustyYOLO] ⚠️  DNS restrictions disabled - exfiltration risk!");
-        docker_cmd.arg("-e").arg("DNS_SERVERS=any");
-    } else {
-        println!("[RustyYOLO] Allowed DNS servers: {dns_servers}");
-        docker_cmd.arg("-e").arg(format!("DNS_SERVERS={dns_servers}"));
+fn configure_dns_restrictions(docker_cmd: &mut Command, dns_servers: &firewall::DnsServers) {
+    match dns_servers {
+        firewall::DnsServers::Any => {
+            println!("[RustyYOLO] ⚠️  DNS restrictions disabled - exfiltration risk!");
+        }
+        firewall::DnsServers::Only(servers) => {
+            let rendered: Vec<String> = servers.iter().map(ToString::to_string).collect();
+            println!("[RustyYOLO] Allowed DNS servers: {}", rendered.join(" "));
+
+            // Configure Docker to use these DNS servers
+            // This ensures the container actually queries these servers instead of Docker's default
+            for dns_server in &rendered {
+                docker_cmd.arg("--dns").arg(dns_server);
+            }
 
-        // Configure Docker to use these DNS servers
-        // This ensures the container actually queries these servers instead of Docker's default
-        for dns_server in dns_servers.split_whitespace() {
-            docker_cmd.arg("--dns").arg(dns_server);
+            // On a user-defined network /etc/resolv.conf names Docker's embedded server
+            // instead, so the forwarder is told the servers directly
+            docker_cmd.arg("-e").arg(format!("DNS_SERVERS={}", rendered.join(" ")));
         }
     }
 }
 
+/// Parses the `dns_servers` setting.
+///
+/// # Panics
+///
+/// Exits the process if an entry is not an IPv4 or IPv6 address.
+fn parse_dns_servers(dns_servers: &str) -> firewall::DnsServers {
+    if dns_servers.eq_ignore_ascii_case("any") {
+        return firewall::DnsServers::Any;
+    }
+    let servers = dns_servers
+        .split_whitespace()
+        .map(|server| {
+            server.parse::<std::net::IpAddr>().unwrap_or_else(|_| {
+                eprintln!("[RustyYOLO] ❌ Invalid DNS server IP: '{server}'");
+                std::process::exit(1);
+            })
+        })
+        .collect();
+    firewall::DnsServers::Only(servers)
+}
+
 /// Configures audit logging level for security events in the container.
 ///
 /// This function enables logging of security-relevant events for forensics and monitoring:
@@ -660,17 +1444,917 @@ fn configure_audit_logging(docker_cmd: &mut Command, audit_log: &str) {
     }
 }
 
-/// Setup filesystem isolation by mounting volumes and setting working directory.
-fn setup_filesystem_isolation(
+/// Configures how outbound traffic from the container is filtered.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - Mutable reference to the Docker command being constructed
+/// * `policy` - The firewall policy; its mode is one of:
+///   - `Firewall` - iptables rules allow the IPs that trusted domains resolve to
+///   - `Proxy` - only the in-container egress proxy may connect out; it allows a connection
+///     when its TLS SNI or HTTP Host is a trusted domain
+/// * `refresh_interval` - Seconds between re-resolving trusted domains in firewall mode
+///   (0 resolves once at startup)
+/// * `sidecars` - Sidecar containers on the session network, reached directly in proxy mode
+///
+/// # Security
+///
+/// In proxy mode the allowlist is enforced per hostname rather than per IP, so rotating CDN
+/// addresses keep working and other sites that share an IP with a trusted domain stay
+/// blocked. Non-HTTP(S) traffic (e.g., SSH) is blocked entirely in this mode.
+fn configure_network_mode(
     docker_cmd: &mut Command,
-    volumes: Vec<String>,
-    envs: Vec<String>,
-    auth_home: Option<PathBuf>,
+    policy: &firewall::Policy,
+    refresh_interval: u64,
+    sidecars: &[String],
 ) {
-    // --- 1. Filesystem Isolation ---
-    let pwd = env::current_dir().expect("Failed to get current directory");
-    docker_cmd.arg("-v").arg(format!("{}:/app", pwd.display()));
-    docker_cmd.arg("-w").arg("/app");
+    match policy.mode {
+        firewall::NetworkMode::Firewall => {
+            if refresh_interval == 0 {
+                println!("[RustyYOLO] Trusted domains are resolved once at startup");
+            } else {
+                println!("[RustyYOLO] Re-resolving trusted domains every {refresh_interval}s");
+            }
+            docker_cmd.arg("-e").arg(format!("REFRESH_INTERVAL={refresh_interval}"));
+        }
+        firewall::NetworkMode::Proxy => {
+            println!("[RustyYOLO] Network mode: proxy (egress filtered by TLS SNI and HTTP Host)");
+            let proxy_url = format!("http://{EGRESS_PROXY_ADDR}");
+            docker_cmd.arg("-e").arg("NETWORK_MODE=proxy");
+            for var in ["HTTPS_PROXY", "HTTP_PROXY", "https_proxy", "http_proxy"] {
+                docker_cmd.arg("-e").arg(format!("{var}={proxy_url}"));
+            }
+
+            // Allowed IP ranges are reached directly, not through the proxy (it only checks names)
+            let mut no_proxy = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+            no_proxy.extend(policy.cidrs.iter().map(ToString::to_string));
+            if !policy.host_ports.is_empty() {
+                no_proxy.push(firewall::HOST_GATEWAY.to_string());
+            }
+            no_proxy.extend(sidecars.iter().cloned());
+            for var in ["NO_PROXY", "no_proxy"] {
+                docker_cmd.arg("-e").arg(format!("{var}={}", no_proxy.join(",")));
+            }
+        }
+    }
+}
+
+/// Builds and validates the trusted domain list.
+///
+/// Entries from the selected presets and, when running Claude, the Anthropic API domains
+/// are added automatically. Every entry is validated on the host so that malformed names,
+/// ports or overly broad wildcards never reach the sandbox.
+///
+/// # Arguments
+///
+/// * `allow_domains` - Space-separated entries (`host`, `host:port` or `host:port/proto`)
+///   from the CLI or config file
+/// * `presets` - Entries expanded from `--allow-preset` / `allow_presets`
+/// * `agent` - The agent being run
+/// * `strict_ports` - Limit entries without a port to 80/tcp and 443/tcp
+///
+/// # Panics
+///
+/// Exits the process if any entry is not a valid domain, pattern or port.
+fn trusted_entries(
+    allow_domains: Option<&str>,
+    presets: &[String],
+    agent: &str,
+    strict_ports: bool,
+) -> Vec<domains::AllowEntry> {
+    // Build the trusted domains list
+    let mut trusted_domains = allow_domains.unwrap_or_default().to_string();
+
+    // Add entries from the selected presets
+    for entry in presets {
+        if !trusted_domains.split_whitespace().any(|existing| existing == entry) {
+            trusted_domains = format!("{trusted_domains} {entry}");
+        }
+    }
+
+    // If using Claude, ensure Anthropic API domains are included
+    if agent == "claude" {
+        if trusted_domains.is_empty() {
+            trusted_domains = ANTHROPIC_DOMAINS.to_string();
+        } else if !trusted_domains.contains("anthropic.com") {
+            trusted_domains = format!("{trusted_domains} {ANTHROPIC_DOMAINS}");
+        }
+    }
+
+    // Validate domains, wildcard patterns and ports before they reach the container
+    let entries = domains::parse_list(&trusted_domains).unwrap_or_else(|e| {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        eprintln!(
+            "[RustyYOLO] Use plain names (github.com), wildcards (*.githubusercontent.com) \
+             or suffixes (.githubusercontent.com), optionally with a port (github.com:443/tcp)."
+        );
+        std::process::exit(1);
+    });
+
+    if strict_ports {
+        domains::restrict_to_web_ports(entries)
+    } else {
+        entries
+    }
+}
+
+/// Passes the trusted domain list to the container.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - Mutable reference to the Docker command being constructed
+/// * `entries` - Validated entries from [`trusted_entries`]
+/// * `presets` - Names of the allowlist presets the entries include
+/// * `strict_ports` - Whether entries without a port were limited to 80/443
+fn configure_trusted_domains(
+    docker_cmd: &mut Command,
+    entries: &[domains::AllowEntry],
+    presets: &[String],
+    strict_ports: bool,
+) {
+    if !presets.is_empty() {
+        println!("[RustyYOLO] Allowlist presets: {}", presets.join(", "));
+    }
+
+    let wildcards: Vec<String> = entries
+        .iter()
+        .filter(|e| !e.pattern.is_exact())
+        .map(ToString::to_string)
+        .collect();
+    if !wildcards.is_empty() {
+        println!(
+            "[RustyYOLO] Domain patterns (allowed once resolved in the sandbox): {}",
+            wildcards.join(" ")
+        );
+    }
+
+    if strict_ports {
+        println!("[RustyYOLO] Strict ports: entries without a port only allow 80/tcp and 443/tcp");
+    }
+
+    // Pass the normalized entries to the container if any are set
+    if !entries.is_empty() {
+        let rendered: Vec<String> = entries.iter().map(ToString::to_string).collect();
+        docker_cmd.arg("-e").arg(format!("TRUSTED_DOMAINS={}", rendered.join(" ")));
+    }
+}
+
+/// Resolves the exact trusted domains on the host and passes the checked addresses to the
+/// container, printing a table of what was pinned.
+///
+/// Only applies in firewall mode (the egress proxy checks names, not addresses) with
+/// restricted DNS servers, which are queried directly. See [`resolve`] for how answers are
+/// validated and cross-checked.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - Mutable reference to the Docker command being constructed
+/// * `policy` - The firewall policy, for its mode, trusted domains and DNS servers
+/// * `ipv6` - Also resolve AAAA records
+fn configure_pinned_addresses(docker_cmd: &mut Command, policy: &firewall::Policy, ipv6: bool) {
+    let firewall::DnsServers::Only(servers) = &policy.dns_servers else {
+        return;
+    };
+    if policy.mode != firewall::NetworkMode::Firewall || servers.is_empty() {
+        return;
+    }
+    let mut names: Vec<String> = Vec::new();
+    for entry in policy.domains.iter().filter(|entry| entry.pattern.is_exact()) {
+        let name = entry.pattern.to_string();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return;
+    }
+
+    println!("[RustyYOLO] Resolving trusted domains on {} DNS server(s)...", servers.len());
+    let pins = resolve::resolve(&names, servers, ipv6);
+    for line in resolve::table(&pins) {
+        println!("[RustyYOLO]   {line}");
+    }
+    if pins.iter().any(|pin| pin.addresses.is_empty()) {
+        println!(
+            "[RustyYOLO] ⚠️  Domains that were not pinned stay blocked until they pass the \
+             check at a refresh; check them against the DNS servers, or allow them with a \
+             wildcard pattern"
+        );
+    }
+
+    let rendered = resolve::render_pins(&pins);
+    if !rendered.is_empty() {
+        docker_cmd.arg("-e").arg(format!("PINNED_ADDRESSES={rendered}"));
+    }
+}
+
+/// Passes the denied domains to the container, where the DNS forwarder and the egress
+/// proxy refuse them even if they match a trusted domain.
+///
+/// # Security
+///
+/// Denied names are enforced by name. In firewall mode, a denied host that shares an IP
+/// address with a trusted one (e.g., `gist.github.com` and `github.com`) can still be
+/// reached by IP; use proxy mode to enforce the denylist on every connection.
+fn configure_denied_domains(docker_cmd: &mut Command, deny: &[domains::DomainPattern]) {
+    if deny.is_empty() {
+        return;
+    }
+    let rendered: Vec<String> = deny.iter().map(ToString::to_string).collect();
+    println!("[RustyYOLO] Denied domains (override trusted domains): {}", rendered.join(" "));
+    docker_cmd.arg("-e").arg(format!("DENY_DOMAINS={}", rendered.join(" ")));
+}
+
+/// Parses and validates the allowed IP ranges.
+///
+/// # Panics
+///
+/// Exits the process if any entry is not a valid IPv4 or IPv6 network.
+fn allowed_cidrs(allow_cidrs: Option<&str>) -> Vec<cidr::Cidr> {
+    cidr::parse_list(allow_cidrs.unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        eprintln!("[RustyYOLO] Use addresses (10.20.0.5) or networks (10.20.0.0/16, fd00::/8).");
+        std::process::exit(1);
+    })
+}
+
+/// Reports the allowed IP ranges (the rules for them are part of the firewall ruleset).
+fn report_allowed_cidrs(cidrs: &[cidr::Cidr], disable_ipv6: bool) {
+    if cidrs.is_empty() {
+        return;
+    }
+    if disable_ipv6 && cidrs.iter().any(cidr::Cidr::is_ipv6) {
+        println!(
+            "[RustyYOLO] ⚠️  IPv6 is disabled; IPv6 ranges in allow_cidrs will have no effect"
+        );
+    }
+    let rendered: Vec<String> = cidrs.iter().map(ToString::to_string).collect();
+    println!("[RustyYOLO] Allowed IP ranges: {}", rendered.join(" "));
+}
+
+/// Parses the networks exempted from the blocked private ranges.
+///
+/// # Panics
+///
+/// Exits the process if any entry is not a valid IPv4 or IPv6 network.
+fn private_blocklist(allow_private: Option<&str>) -> cidr::Blocklist {
+    let overrides = cidr::parse_list(allow_private.unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        eprintln!("[RustyYOLO] allow_private entries are addresses or networks (10.20.0.0/16).");
+        std::process::exit(1);
+    });
+    cidr::Blocklist::new(overrides)
+}
+
+/// Reports the exempted private ranges and passes them to the in-sandbox helpers, which
+/// refuse to connect to or allow addresses in the remaining blocked ranges.
+fn configure_private_ranges(docker_cmd: &mut Command, blocklist: &cidr::Blocklist) {
+    if blocklist.overrides().is_empty() {
+        return;
+    }
+    let rendered: Vec<String> = blocklist.overrides().iter().map(ToString::to_string).collect();
+    println!("[RustyYOLO] ⚠️  Private ranges exempted from blocking: {}", rendered.join(" "));
+    docker_cmd.arg("-e").arg(format!("ALLOW_PRIVATE={}", rendered.join(" ")));
+}
+
+/// Sets up egress byte accounting when quotas are configured.
+///
+/// The firewall enforces each quota per address family; the returned session watches the
+/// combined counters, which the container writes to a mounted directory, and stops the
+/// container once a quota is used up.
+///
+/// # Returns
+///
+/// The session, if quotas are configured, or why the temporary usage directory cannot be
+/// created
+fn configure_egress_quotas(
+    docker_cmd: &mut Command,
+    quotas: &egress::Quotas,
+) -> Result<Option<egress::Session>, String> {
+    if quotas.is_empty() {
+        return Ok(None);
+    }
+
+    if let Some(limit) = quotas.total {
+        println!("[RustyYOLO] Egress quota: {}", egress::format_size(limit));
+        docker_cmd.arg("-e").arg(format!("MAX_EGRESS={limit}"));
+    }
+    if !quotas.domains.is_empty() {
+        for quota in &quotas.domains {
+            println!(
+                "[RustyYOLO] Egress quota for {}: {}",
+                quota.pattern,
+                egress::format_size(quota.limit)
+            );
+        }
+        docker_cmd.arg("-e").arg(format!("EGRESS_QUOTAS={}", quotas.domains_env()));
+    }
+
+    let session = egress::Session::create(quotas.clone())
+        .map_err(|e| format!("Failed to create the egress usage directory: {e}"))?;
+    docker_cmd.arg("--cidfile").arg(session.cidfile());
+    docker_cmd
+        .arg("-v")
+        .arg(format!("{}:{}", session.stats_dir().display(), egress::STATS_DIR));
+    Ok(Some(session))
+}
+
+/// Sets up learn mode: the sandbox helpers record blocked destinations in a directory
+/// mounted from the host, which proposes them as trusted domains when the session ends.
+///
+/// # Returns
+///
+/// The session in learn mode, or why the temporary learning directory cannot be created
+fn configure_learning(
+    docker_cmd: &mut Command,
+    learn: bool,
+) -> Result<Option<learn::Session>, String> {
+    if !learn {
+        return Ok(None);
+    }
+
+    println!("[RustyYOLO] Learn mode: recording blocked destinations");
+    let session = learn::Session::create()
+        .map_err(|e| format!("Failed to create the learning directory: {e}"))?;
+    docker_cmd
+        .arg("-v")
+        .arg(format!("{}:{}", session.log_dir().display(), learn::LEARN_DIR));
+    docker_cmd.arg("-e").arg(format!("LEARN_DIR={}", learn::LEARN_DIR));
+    Ok(Some(session))
+}
+
+/// Sets up live approval: the sandbox helpers ask about names outside the allowlist over a
+/// unix socket mounted from the host, and the operator answers with `rustyolo approve`.
+///
+/// # Returns
+///
+/// The approval server, if enabled, or why its sockets cannot be created
+fn configure_live_approval(
+    docker_cmd: &mut Command,
+    enabled: bool,
+    strict_ports: bool,
+) -> Result<Option<approval::Server>, String> {
+    if !enabled {
+        return Ok(None);
+    }
+
+    let server = approval::Server::create(strict_ports, Path::new(config::CONFIG_FILE))
+        .map_err(|e| format!("Failed to create the live approval socket: {e}"))?;
+    println!(
+        "[RustyYOLO] Live approval: run 'rustyolo approve {}' in another terminal to answer \
+         requests for blocked domains",
+        std::process::id()
+    );
+    docker_cmd.arg("-v").arg(format!(
+        "{}:{}",
+        server.sandbox_dir().display(),
+        approval::APPROVAL_DIR
+    ));
+    docker_cmd.arg("-e").arg(format!(
+        "APPROVAL_SOCKET={}/{}",
+        approval::APPROVAL_DIR,
+        approval::APPROVAL_SOCKET
+    ));
+    Ok(Some(server))
+}
+
+/// Whether the network mode is "offline" (no network at all, so no firewall policy).
+fn is_offline(network_mode: &str) -> bool {
+    network_mode.eq_ignore_ascii_case("offline")
+}
+
+/// Starts the container without a network.
+///
+/// Docker gives the container only a loopback interface, so there is nothing to filter
+/// and the entrypoint skips the firewall setup entirely.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - Mutable reference to the Docker command being constructed
+/// * `args` - The run arguments; network settings in them are reported as ignored
+fn configure_offline_network(docker_cmd: &mut Command, args: &RunArgs) {
+    println!("[RustyYOLO] Network mode: offline (no network, loopback only)");
+    for warning in offline_warnings(args) {
+        println!("[RustyYOLO] ⚠️  {warning}");
+    }
+
+    docker_cmd.arg("--network").arg("none");
+    docker_cmd.arg("-e").arg("NETWORK_MODE=offline");
+}
+
+/// Warnings about run arguments that have no effect in offline mode.
+fn offline_warnings(args: &RunArgs) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if args.agent == "claude" {
+        warnings.push(format!(
+            "claude needs the Anthropic API ({ANTHROPIC_DOMAINS}), which is unreachable in \
+             offline mode"
+        ));
+    }
+    if args.allow_domains.is_some()
+        || args.allow_cidrs.is_some()
+        || args.allow_private.is_some()
+        || !args.allow_presets.is_empty()
+        || !args.host_ports.is_empty()
+        || args.max_egress.is_some()
+        || !args.egress_quotas.is_empty()
+        || args.live_approval
+        || args.upstream_proxy.is_some()
+        || !args.http_policies.is_empty()
+        || !args.sidecars.is_empty()
+    {
+        warnings.push(
+            "Trusted domains, IP ranges, host ports, egress quotas, live approval, the \
+             upstream proxy, HTTP policies and sidecars are ignored in offline mode"
+                .to_string(),
+        );
+    }
+    warnings
+}
+
+/// Validates the host ports, dropping duplicates.
+///
+/// # Panics
+///
+/// Exits the process if a port is 0.
+fn host_ports(ports: &[u16]) -> Vec<u16> {
+    if ports.contains(&0) {
+        eprintln!("[RustyYOLO] ❌ Invalid host port: 0");
+        std::process::exit(1);
+    }
+    let mut unique = Vec::new();
+    for port in ports {
+        if !unique.contains(port) {
+            unique.push(*port);
+        }
+    }
+    unique
+}
+
+/// Maps `host.docker.internal` to the Docker host and passes the host ports to the
+/// container, where they are opened on the gateway IP only.
+fn configure_host_ports(docker_cmd: &mut Command, ports: &[u16]) {
+    if ports.is_empty() {
+        return;
+    }
+    let rendered: Vec<String> = ports.iter().map(ToString::to_string).collect();
+    println!(
+        "[RustyYOLO] Host services reachable at {}: ports {}",
+        firewall::HOST_GATEWAY,
+        rendered.join(", ")
+    );
+    docker_cmd
+        .arg("--add-host")
+        .arg(format!("{}:host-gateway", firewall::HOST_GATEWAY));
+    docker_cmd.arg("-e").arg(format!("HOST_PORTS={}", rendered.join(",")));
+}
+
+/// Builds the firewall policy for a run from the merged CLI and config settings.
+///
+/// # Panics
+///
+/// Exits the process if the network mode, DNS servers, presets, trusted or denied domains, IP
+/// ranges, quotas, upstream proxy or HTTP policies are invalid, or if the global config file
+/// cannot be parsed.
+fn firewall_policy(args: &RunArgs) -> firewall::Policy {
+    let Some(mode) = firewall::NetworkMode::parse(&args.network_mode) else {
+        eprintln!(
+            "[RustyYOLO] ❌ Invalid network mode: '{}'. Use 'firewall', 'proxy' or 'offline'.",
+            args.network_mode
+        );
+        std::process::exit(1);
+    };
+
+    if let Err(e) = http::Policies::parse(&args.http_policies) {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        std::process::exit(1);
+    }
+
+    let global = config::GlobalConfig::try_load().unwrap_or_else(|e| {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        std::process::exit(1);
+    });
+    let presets = presets::expand(&args.allow_presets, &global.presets).unwrap_or_else(|e| {
+        eprintln!("[RustyYOLO] ❌ {e}");
+        std::process::exit(1);
+    });
+
+    let deny = domains::parse_patterns(args.deny_domains.as_deref().unwrap_or_default())
+        .unwrap_or_else(|e| {
+            eprintln!("[RustyYOLO] ❌ {e}");
+            eprintln!(
+                "[RustyYOLO] deny_domains entries are names or patterns without a port \
+                 (gist.github.com, *.example.com, .example.com)."
+            );
+            std::process::exit(1);
+        });
+
+    // Deny wins: exact trusted names that are also denied are never resolved into the firewall
+    let mut domains =
+        trusted_entries(args.allow_domains.as_deref(), &presets, &args.agent, args.strict_ports);
+    domains.retain(|entry| {
+        !(entry.pattern.is_exact() && deny.iter().any(|d| d.matches(&entry.pattern.to_string())))
+    });
+
+    let upstream_proxy = upstream_proxy_addrs(args.upstream_proxy.as_deref());
+    let mode =
+        effective_network_mode(mode, !upstream_proxy.is_empty(), !args.http_policies.is_empty());
+
+    firewall::Policy {
+        mode,
+        dns_servers: parse_dns_servers(&args.dns_servers),
+        domains,
+        deny,
+        cidrs: allowed_cidrs(args.allow_cidrs.as_deref()),
+        host_ports: host_ports(&args.host_ports),
+        blocklist: private_blocklist(args.allow_private.as_deref()),
+        network: network::Addresses::default(),
+        quotas: egress::Quotas::parse(args.max_egress.as_deref(), &args.egress_quotas)
+            .unwrap_or_else(|e| {
+                eprintln!("[RustyYOLO] ❌ {e}");
+                std::process::exit(1);
+            }),
+        upstream_proxy,
+        learn: args.learn,
+        audit: audit::AuditLevel::parse(&args.audit_log).unwrap_or(audit::AuditLevel::None),
+    }
+}
+
+/// Picks the network mode a run actually uses.
+///
+/// All traffic has to go through the corporate proxy, and HTTP policies are enforced by the
+/// egress proxy, so either one leaves the egress proxy as the only mode that can work.
+fn effective_network_mode(
+    mode: firewall::NetworkMode,
+    upstream_proxy: bool,
+    http_policies: bool,
+) -> firewall::NetworkMode {
+    if upstream_proxy || http_policies {
+        firewall::NetworkMode::Proxy
+    } else {
+        mode
+    }
+}
+
+/// Parses the upstream proxy URL, if one is configured, and resolves the proxy's addresses.
+///
+/// # Panics
+///
+/// Exits the process if the URL is invalid or the proxy's name does not resolve.
+fn upstream_proxy_addrs(url: Option<&str>) -> Vec<std::net::SocketAddr> {
+    let Some(url) = url else {
+        return Vec::new();
+    };
+    proxy::UpstreamProxy::parse(url)
+        .and_then(|proxy| proxy.resolve())
+        .unwrap_or_else(|e| {
+            eprintln!("[RustyYOLO] ❌ {e}");
+            std::process::exit(1);
+        })
+}
+
+/// Sends the egress proxy's traffic through the upstream (corporate) proxy.
+///
+/// The proxy's addresses are passed in the environment. Its credentials are written to a
+/// private `--env-file` instead, so they never appear in the printed Docker command; the
+/// entrypoint removes them from the environment before the agent starts.
+///
+/// # Returns
+///
+/// The private directory holding the credentials file, to be removed once the container has
+/// exited, or why it cannot be written
+fn configure_upstream_proxy(
+    docker_cmd: &mut Command,
+    url: Option<&str>,
+    addrs: &[std::net::SocketAddr],
+) -> Result<Option<PathBuf>, String> {
+    // Validated and resolved by firewall_policy()
+    let Some(proxy) = url.and_then(|url| proxy::UpstreamProxy::parse(url).ok()) else {
+        return Ok(None);
+    };
+    let rendered: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+    println!("[RustyYOLO] Upstream proxy: {proxy} ({})", rendered.join(" "));
+    docker_cmd.arg("-e").arg(format!("UPSTREAM_PROXY={}", rendered.join(" ")));
+
+    let Some(credentials) = proxy.credentials else {
+        return Ok(None);
+    };
+
+    // A private directory, so that no other user can read the file or plant one in its place
+    let dir = env::temp_dir().join(format!("rustyolo-proxy-auth-{}", std::process::id()));
+    let path = dir.join("auth.env");
+    let write = || -> std::io::Result<()> {
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        let mut file =
+            fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+        writeln!(file, "UPSTREAM_PROXY_AUTH={credentials}")
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!("Failed to write the upstream proxy credentials: {e}"));
+    }
+    docker_cmd.arg("--env-file").arg(&path);
+    Ok(Some(dir))
+}
+
+/// Passes the HTTP policies to the egress proxy.
+fn configure_http_policies(docker_cmd: &mut Command, policies: &[config::HttpPolicy]) {
+    // Validated by firewall_policy()
+    let Ok(parsed) = http::Policies::parse(policies) else {
+        return;
+    };
+    if parsed.is_empty() {
+        return;
+    }
+    println!(
+        "[RustyYOLO] HTTP policies (HTTPS to these hosts is intercepted by the egress proxy):"
+    );
+    for policy in parsed.iter() {
+        println!("[RustyYOLO]   {policy}");
+    }
+    let json = serde_json::to_string(policies).expect("HTTP policies serialize to JSON");
+    docker_cmd.arg("-e").arg(format!("HTTP_POLICIES={json}"));
+}
+
+/// Writes the firewall ruleset to temporary files and mounts them into the container.
+///
+/// The entrypoint applies them with `iptables-restore` / `ip6tables-restore` before
+/// anything else runs, so the whole ruleset takes effect at once. The files are written
+/// into a private directory, so that no other user can plant a file or symlink in their
+/// place or rewrite the ruleset before the container applies it.
+///
+/// # Returns
+///
+/// The temporary directory, to be removed once the container has exited.
+fn setup_firewall_rules(
+    docker_cmd: &mut Command,
+    policy: &firewall::Policy,
+    ipv6: bool,
+) -> Result<PathBuf, String> {
+    let mut families = vec![(firewall::Family::V4, "v4")];
+    if ipv6 {
+        families.push((firewall::Family::V6, "v6"));
+    }
+
+    let dir = env::temp_dir().join(format!("rustyolo-firewall-{}", std::process::id()));
+    let write = || -> std::io::Result<()> {
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        for (family, suffix) in &families {
+            let path = dir.join(format!("firewall.{suffix}.rules"));
+            let mut file =
+                fs::OpenOptions::new().write(true).create_new(true).mode(0o644).open(&path)?;
+            file.write_all(firewall::ruleset(policy, *family).as_bytes())?;
+        }
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!("Failed to write the firewall rules: {e}"));
+    }
+
+    for (_, suffix) in families {
+        let path = dir.join(format!("firewall.{suffix}.rules"));
+        docker_cmd
+            .arg("-v")
+            .arg(format!("{}:{FIREWALL_RULES_DIR}/firewall.{suffix}.rules:ro", path.display()));
+    }
+    Ok(dir)
+}
+
+/// Prints the firewall rules a run with the given options would apply.
+fn show_firewall(args: &RunArgs) {
+    if is_offline(&args.network_mode) {
+        println!("# Offline mode: the container has no network (docker run --network none)");
+        println!("# and no firewall rules are applied.");
+        return;
+    }
+    let policy = firewall_policy(args);
+    println!("# --- IPv4 (iptables-restore) ---");
+    print!("{}", firewall::ruleset(&policy, firewall::Family::V4));
+    if args.disable_ipv6 {
+        println!("# --- IPv6 disabled ---");
+    } else {
+        println!("# --- IPv6 (ip6tables-restore) ---");
+        print!("{}", firewall::ruleset(&policy, firewall::Family::V6));
+    }
+}
+
+/// What is mounted at `/app`, and what to do with it once the container has exited
+enum WorkspaceSession {
+    /// The project itself
+    Bind,
+
+    /// A scratch copy of the project, whose changes are reviewed
+    Overlay(workspace::Overlay),
+
+    /// A git worktree on the session branch, which is reported
+    Worktree(worktree::Worktree),
+}
+
+impl WorkspaceSession {
+    fn finish(self) {
+        match self {
+            Self::Bind => {}
+            Self::Overlay(overlay) => overlay.finish(),
+            Self::Worktree(worktree) => worktree.finish(),
+        }
+    }
+
+    /// Remove the workspace of a session that never started.
+    fn discard(self) {
+        match self {
+            Self::Bind | Self::Overlay(_) => {}
+            Self::Worktree(worktree) => worktree.discard(),
+        }
+    }
+}
+
+/// The workspace mode from the run arguments.
+///
+/// # Panics
+///
+/// Exits the process if the mode is invalid or `--worktree` is combined with an overlay.
+fn workspace_mode(args: &RunArgs) -> workspace::Mode {
+    let Some(mode) = workspace::Mode::parse(&args.workspace) else {
+        eprintln!(
+            "[RustyYOLO] ❌ Invalid workspace mode: '{}'. Use 'bind' or 'overlay'.",
+            args.workspace
+        );
+        std::process::exit(1);
+    };
+    if args.worktree && mode == workspace::Mode::Overlay {
+        eprintln!("[RustyYOLO] ❌ --worktree cannot be combined with the overlay workspace.");
+        std::process::exit(1);
+    }
+    mode
+}
+
+/// Prepares the project directory for the workspace mode.
+///
+/// In overlay mode, the project is copied to a scratch directory that is mounted instead.
+/// With `--worktree`, a new worktree and branch are created for the session and mounted
+/// instead, along with the repository's `.git` directory. In dry run mode, neither is made.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - The Docker command to add the worktree's mounts to
+/// * `args` - The run arguments (`worktree` and `dry_run` are used)
+/// * `mode` - The workspace mode, from [`workspace_mode`]
+/// * `project` - The project directory
+///
+/// # Returns
+///
+/// The directory to mount at `/app`, and what to finish when the container exits, or why
+/// the workspace cannot be created
+fn setup_workspace(
+    docker_cmd: &mut Command,
+    args: &RunArgs,
+    mode: workspace::Mode,
+    project: &Path,
+) -> Result<(PathBuf, WorkspaceSession), String> {
+    if args.worktree {
+        return setup_worktree(docker_cmd, project, args.dry_run);
+    }
+    if mode == workspace::Mode::Bind {
+        return Ok((project.to_path_buf(), WorkspaceSession::Bind));
+    }
+    if args.dry_run {
+        println!("[RustyYOLO] Workspace: overlay (the project is not copied in dry run mode)");
+        return Ok((project.to_path_buf(), WorkspaceSession::Bind));
+    }
+
+    let (overlay, count) = workspace::Overlay::create(project)?;
+    println!(
+        "[RustyYOLO] Workspace: overlay ({count} files copied to {}); changes are reviewed \
+         when the container exits",
+        overlay.work_dir().display()
+    );
+    Ok((overlay.work_dir(), WorkspaceSession::Overlay(overlay)))
+}
+
+/// Snapshots the project so that `rustyolo rollback` can restore it.
+///
+/// Nothing is snapshotted with `--no-snapshot`, in dry run mode, or with `--worktree`,
+/// which leaves the project alone.
+///
+/// # Arguments
+///
+/// * `args` - The run arguments
+/// * `project` - The project directory
+/// * `ignored_limit` - The limit from [`snapshot_ignored_limit`]
+///
+/// # Returns
+///
+/// Why the snapshot cannot be taken, if it cannot
+fn setup_snapshot(
+    args: &RunArgs,
+    project: &Path,
+    ignored_limit: Option<u64>,
+) -> Result<(), String> {
+    if args.no_snapshot || args.worktree {
+        return Ok(());
+    }
+    if args.dry_run {
+        println!("[RustyYOLO] Snapshot: not taken in dry run mode");
+        return Ok(());
+    }
+    let session = std::process::id().to_string();
+    match snapshot::Snapshot::create(project, &session, &snapshot::default_dir(), ignored_limit) {
+        Ok(snapshot) => {
+            println!(
+                "[RustyYOLO] Snapshot taken ({}); undo the session with: rustyolo rollback {}",
+                if snapshot.git.is_some() {
+                    "git"
+                } else {
+                    "tarball"
+                },
+                snapshot.session
+            );
+            let skipped = snapshot.git.as_ref().map_or(&[][..], |git| &git.skipped[..]);
+            if !skipped.is_empty() {
+                let shown = skipped.iter().take(5).map(String::as_str).collect::<Vec<_>>();
+                let more = match skipped.len() - shown.len() {
+                    0 => String::new(),
+                    n => format!(" and {n} more"),
+                };
+                println!(
+                    "[RustyYOLO] ⚠️  Ignored paths larger than {} are not in the snapshot and \
+                     rollback leaves them as they are: {}{more}",
+                    args.snapshot_ignored_limit,
+                    shown.join(" ")
+                );
+            }
+        }
+        Err(e) => {
+            return Err(format!("{e}\n[RustyYOLO] Pass --no-snapshot to run without a snapshot."));
+        }
+    }
+    Ok(())
+}
+
+/// The size above which ignored paths are left out of a git snapshot (`None` for no
+/// limit).
+///
+/// # Panics
+///
+/// Exits the process if the limit is invalid.
+fn snapshot_ignored_limit(args: &RunArgs) -> Option<u64> {
+    if args.snapshot_ignored_limit == "none" {
+        return None;
+    }
+    match egress::parse_size(&args.snapshot_ignored_limit) {
+        Ok(limit) => Some(limit),
+        Err(e) => {
+            eprintln!("[RustyYOLO] ❌ Invalid snapshot_ignored_limit: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Creates the session's git worktree under the cache directory and mounts its repository.
+fn setup_worktree(
+    docker_cmd: &mut Command,
+    project: &Path,
+    dry_run: bool,
+) -> Result<(PathBuf, WorkspaceSession), String> {
+    let session = std::process::id().to_string();
+    if dry_run {
+        println!(
+            "[RustyYOLO] Workspace: worktree on branch {}{session} (not created in dry run mode)",
+            worktree::BRANCH_PREFIX
+        );
+        return Ok((project.to_path_buf(), WorkspaceSession::Bind));
+    }
+
+    let parent = dirs::cache_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join("rustyolo")
+        .join("worktrees");
+    let worktree = worktree::Worktree::create(project, &session, &parent)?;
+    println!(
+        "[RustyYOLO] Workspace: worktree on branch {} ({})",
+        worktree.branch(),
+        worktree.path().display()
+    );
+    for mount in worktree.mounts() {
+        docker_cmd.arg("-v").arg(mount);
+    }
+    Ok((worktree.path().to_path_buf(), WorkspaceSession::Worktree(worktree)))
+}
+
+/// Setup filesystem isolation by mounting volumes and setting working directory.
+fn setup_filesystem_isolation(
+    docker_cmd: &mut Command,
+    app_dir: &Path,
+    volumes: Vec<String>,
+    envs: Vec<String>,
+    auth_home: Option<PathBuf>,
+) {
+    // --- 1. Filesystem Isolation ---
+    docker_cmd.arg("-v").arg(format!("{}:/app", app_dir.display()));
+    docker_cmd.arg("-w").arg("/app");
 
     // Add user-specified volumes
     for vol in volumes {
@@ -709,13 +2393,219 @@ fn setup_filesystem_isolation(
     docker_cmd.arg("-e").arg(format!("PERSISTENT_DIRS={container_auth_path}"));
 }
 
+/// Configures network isolation: either no network at all (offline mode), or the
+/// firewall policy with its DNS restrictions, trusted domains and generated ruleset.
+///
+/// # Arguments
+///
+/// * `docker_cmd` - The Docker command being constructed
+/// * `args` - The run arguments
+/// * `policy` - The policy from [`firewall_policy`], or `None` in offline mode
+///
+/// # Returns
+///
+/// The host-side resources of the network setup, to be released once the container has
+/// exited, or why they cannot be set up; what was set up so far is released then.
+fn configure_network(
+    docker_cmd: &mut Command,
+    args: &RunArgs,
+    policy: Option<firewall::Policy>,
+) -> Result<NetworkSession, String> {
+    let Some(mut policy) = policy else {
+        // --- No Network At All ---
+        configure_offline_network(docker_cmd, args);
+        return Ok(NetworkSession::default());
+    };
+
+    // --- HTTP Policies ---
+    configure_http_policies(docker_cmd, &args.http_policies);
+
+    // --- DNS Restrictions (Defense against DNS exfiltration) ---
+    configure_dns_restrictions(docker_cmd, &policy.dns_servers);
+
+    // --- Audit Logging ---
+    configure_audit_logging(docker_cmd, &args.audit_log);
+
+    // --- Egress Filtering Mode ---
+    configure_network_mode(docker_cmd, &policy, args.refresh_interval, &args.sidecars);
+
+    // --- Trusted Domains ---
+    configure_trusted_domains(docker_cmd, &policy.domains, &args.allow_presets, args.strict_ports);
+    configure_pinned_addresses(docker_cmd, &policy, !args.disable_ipv6);
+
+    // --- Denied Domains ---
+    configure_denied_domains(docker_cmd, &policy.deny);
+
+    // --- Trusted IP Ranges ---
+    report_allowed_cidrs(&policy.cidrs, args.disable_ipv6);
+
+    // --- Blocked Private Ranges ---
+    configure_private_ranges(docker_cmd, &policy.blocklist);
+
+    // --- Host Services ---
+    configure_host_ports(docker_cmd, &policy.host_ports);
+
+    // --- Egress Quotas ---
+    let egress = configure_egress_quotas(docker_cmd, &policy.quotas)?;
+
+    // --- Learn Mode ---
+    let learn = configure_learning(docker_cmd, policy.learn)?;
+
+    // --- Live Approval ---
+    let approval = configure_live_approval(docker_cmd, args.live_approval, args.strict_ports)?;
+
+    // --- Upstream Proxy ---
+    let mut temp_files: Vec<PathBuf> = configure_upstream_proxy(
+        docker_cmd,
+        args.upstream_proxy.as_deref(),
+        &policy.upstream_proxy,
+    )?
+    .into_iter()
+    .collect();
+
+    // --- Session Network ---
+    let docker_network = match configure_session_network(docker_cmd, &args.sidecars, args.dry_run) {
+        Ok(docker_network) => docker_network,
+        Err(e) => {
+            remove_temp_files(temp_files);
+            return Err(e);
+        }
+    };
+    if let Some(docker_network) = &docker_network {
+        policy.network = docker_network.addresses().clone();
+    }
+
+    // --- Firewall Ruleset ---
+    match setup_firewall_rules(docker_cmd, &policy, !args.disable_ipv6) {
+        Ok(dir) => temp_files.push(dir),
+        Err(e) => {
+            remove_temp_files(temp_files);
+            return Err(e);
+        }
+    }
+
+    Ok(NetworkSession { temp_files, egress, learn, approval, docker_network })
+}
+
+/// Runs the container on a Docker network of its own and attaches the sidecars to it.
+///
+/// In dry run mode, the network is not created.
+///
+/// # Returns
+///
+/// The network, which is removed when dropped, or why it could not be created or a sidecar
+/// could not be attached
+fn configure_session_network(
+    docker_cmd: &mut Command,
+    sidecars: &[String],
+    dry_run: bool,
+) -> Result<Option<network::SessionNetwork>, String> {
+    let session = std::process::id().to_string();
+    if dry_run {
+        let name = network::SessionNetwork::name_for(&session);
+        println!("[RustyYOLO] Session network: {name} (not created in dry run mode)");
+        docker_cmd.arg("--network").arg(name);
+        return Ok(None);
+    }
+
+    let docker_network = network::SessionNetwork::create(&session, sidecars)?;
+    let addresses = docker_network.addresses();
+    let subnets: Vec<String> = addresses.subnets.iter().map(ToString::to_string).collect();
+    println!(
+        "[RustyYOLO] Session network: {} ({}), isolated from other containers",
+        docker_network.name(),
+        subnets.join(" ")
+    );
+    for sidecar in &addresses.sidecars {
+        println!("[RustyYOLO] Sidecar reachable: {} ({})", sidecar.name, sidecar.ip);
+        docker_cmd.arg("--add-host").arg(format!("{}:{}", sidecar.name, sidecar.ip));
+    }
+    docker_cmd.arg("--network").arg(docker_network.name());
+    Ok(Some(docker_network))
+}
+
+/// Host-side resources of a session's network setup
+#[derive(Default)]
+struct NetworkSession {
+    /// Temporary files and directories passed to Docker (firewall rules, proxy credentials)
+    temp_files: Vec<PathBuf>,
+
+    /// Egress quota tracking, if quotas are configured
+    egress: Option<egress::Session>,
+
+    /// Blocked destinations recorded in learn mode
+    learn: Option<learn::Session>,
+
+    /// Control channel for live approval, if enabled
+    approval: Option<approval::Server>,
+
+    /// The session's Docker network, removed when dropped
+    docker_network: Option<network::SessionNetwork>,
+}
+
+impl NetworkSession {
+    /// Release the resources of a session that never started.
+    fn discard(self) {
+        remove_temp_files(self.temp_files);
+    }
+}
+
+/// Validates the run settings, then sets up the session's CA bundle, workspace, network
+/// and snapshot.
+///
+/// Everything that can be checked is checked before anything is written to disk: exiting
+/// skips Drop, so whatever was set up before a later failure is released first.
+///
+/// # Returns
+///
+/// The directory to mount at `/app`, and the CA bundle, workspace and network resources
+/// to release once the container has exited
+fn setup_session(
+    docker_cmd: &mut Command,
+    args: &RunArgs,
+    offline: bool,
+) -> (PathBuf, Option<certs::CaBundle>, WorkspaceSession, NetworkSession) {
+    let pwd = env::current_dir().expect("Failed to get current directory");
+    let workspace_mode = workspace_mode(args);
+    let ignored_limit = snapshot_ignored_limit(args);
+    let policy = (!offline).then(|| firewall_policy(args));
+
+    let ca_bundle = setup_ca_certificates(docker_cmd, &args.ca_certificates);
+    let (app_dir, workspace) = match setup_workspace(docker_cmd, args, workspace_mode, &pwd) {
+        Ok(workspace) => workspace,
+        Err(e) => {
+            drop(ca_bundle);
+            eprintln!("[RustyYOLO] ❌ {e}");
+            std::process::exit(1);
+        }
+    };
+    let network = match configure_network(docker_cmd, args, policy) {
+        Ok(network) => network,
+        Err(e) => {
+            drop(ca_bundle);
+            workspace.discard();
+            eprintln!("[RustyYOLO] ❌ {e}");
+            std::process::exit(1);
+        }
+    };
+    // Taken last, so that no snapshot is left of a session that never ran
+    if let Err(e) = setup_snapshot(args, &pwd, ignored_limit) {
+        drop(ca_bundle);
+        workspace.discard();
+        network.discard();
+        eprintln!("[RustyYOLO] ❌ {e}");
+        std::process::exit(1);
+    }
+    (app_dir, ca_bundle, workspace, network)
+}
+
 fn run_agent(args: RunArgs) {
     // Validate volumes before constructing the Docker command
-    if let Some(error_msg) = validate_volumes(&args.volumes) {
-        eprintln!("[RustyYOLO] ❌ Dangerous volume mount detected!");
+    let volumes = validate_volumes(&args.volumes, &args.deny_mounts).unwrap_or_else(|error_msg| {
+        eprintln!("[RustyYOLO] ❌ Volume mount rejected!");
         eprintln!("[RustyYOLO] {error_msg}");
         std::process::exit(1);
-    }
+    });
 
     let mut docker_cmd = Command::new("docker");
     docker_cmd.arg("run").arg("-it").arg("--rm");
@@ -724,12 +2614,20 @@ fn run_agent(args: RunArgs) {
     let _seccomp_temp_file = setup_seccomp(&mut docker_cmd, args.seccomp_profile.as_deref());
 
     // --- 3. Network Isolation ---
+    let offline = is_offline(&args.network_mode);
+
     // Drop all capabilities and only add necessary ones:
-    // - NET_ADMIN: needed for iptables firewall setup
+    // - NET_ADMIN: needed for iptables firewall setup (not in offline mode)
+    // - NET_BIND_SERVICE: needed for the root-owned DNS forwarder to listen on port 53
+    //   (not in offline mode)
     // - CHOWN: needed to fix file ownership in entrypoint.sh
     // - SETUID/SETGID: needed for gosu to switch from root to agent user
+    // The agent user does not keep any of these after gosu drops privileges.
     docker_cmd.arg("--cap-drop=ALL");
-    docker_cmd.arg("--cap-add=NET_ADMIN");
+    if !offline {
+        docker_cmd.arg("--cap-add=NET_ADMIN");
+        docker_cmd.arg("--cap-add=NET_BIND_SERVICE");
+    }
     docker_cmd.arg("--cap-add=CHOWN");
     docker_cmd.arg("--cap-add=SETUID");
     docker_cmd.arg("--cap-add=SETGID");
@@ -737,34 +2635,18 @@ fn run_agent(args: RunArgs) {
     // Prevent privilege escalation via setuid/setgid binaries
     docker_cmd.arg("--security-opt").arg("no-new-privileges");
 
-    // Disable IPv6 to prevent firewall bypass (iptables only configures IPv4)
-    docker_cmd.arg("--sysctl").arg("net.ipv6.conf.all.disable_ipv6=1");
+    // IPv6 is firewalled with ip6tables alongside IPv4 unless the user opts out of it entirely
+    if args.disable_ipv6 {
+        println!("[RustyYOLO] IPv6 disabled inside the container");
+        docker_cmd.arg("--sysctl").arg("net.ipv6.conf.all.disable_ipv6=1");
+        docker_cmd.arg("-e").arg("DISABLE_IPV6=1");
+    }
 
     // --- Resource Limits (Defense against DoS/crypto mining) ---
     apply_resource_limits(&mut docker_cmd, &args.memory, &args.cpus, &args.pids_limit);
 
-    // --- DNS Restrictions (Defense against DNS exfiltration) ---
-    configure_dns_restrictions(&mut docker_cmd, &args.dns_servers);
-
-    // --- Audit Logging ---
-    configure_audit_logging(&mut docker_cmd, &args.audit_log);
-
-    // Build the trusted domains list
-    let mut trusted_domains = args.allow_domains.unwrap_or_default();
-
-    // If using Claude, ensure Anthropic API domains are included
-    if args.agent == "claude" {
-        if trusted_domains.is_empty() {
-            trusted_domains = ANTHROPIC_DOMAINS.to_string();
-        } else if !trusted_domains.contains("anthropic.com") {
-            trusted_domains = format!("{trusted_domains} {ANTHROPIC_DOMAINS}");
-        }
-    }
-
-    // Pass the domains to the container if any are set
-    if !trusted_domains.is_empty() {
-        docker_cmd.arg("-e").arg(format!("TRUSTED_DOMAINS={trusted_domains}"));
-    }
+    let (app_dir, ca_bundle, workspace, mut network) =
+        setup_session(&mut docker_cmd, &args, offline);
 
     // --- 2. Privilege Isolation ---
     let uid = Command::new("id").arg("-u").output().expect("Failed to get UID");
@@ -777,7 +2659,8 @@ fn run_agent(args: RunArgs) {
     docker_cmd.arg("-e").arg(format!("AGENT_GID={gid_str}"));
 
     // --- 1. Filesystem Isolation ---
-    setup_filesystem_isolation(&mut docker_cmd, args.volumes, args.envs, args.auth_home);
+    let volumes = volumes.iter().map(ToString::to_string).collect();
+    setup_filesystem_isolation(&mut docker_cmd, &app_dir, volumes, args.envs, args.auth_home);
 
     // Add the image
     docker_cmd.arg(&args.image);
@@ -839,6 +2722,7 @@ fn run_agent(args: RunArgs) {
         let cmd_parts: Vec<String> =
             docker_cmd.get_args().map(|s| s.to_string_lossy().to_string()).collect();
         println!("docker {}", cmd_parts.join(" "));
+        network.discard();
         return;
     }
 
@@ -848,14 +2732,53 @@ fn run_agent(args: RunArgs) {
         .stderr(Stdio::inherit())
         .spawn()
         .expect("Failed to execute docker command.");
+    if let Some(egress) = &mut network.egress {
+        egress.watch();
+    }
+    if let Some(approval) = &mut network.approval {
+        approval.serve();
+    }
 
     let status = child.wait().expect("Failed to wait on docker command.");
+    drop(ca_bundle);
+    workspace.finish();
+    finish_session(network, status);
+}
+
+/// Releases the host-side resources of a session once the container has exited and
+/// reports how it ended.
+///
+/// # Panics
+///
+/// Exits the process with an error if the container failed or an egress quota was exceeded.
+fn finish_session(network: NetworkSession, status: std::process::ExitStatus) {
+    remove_temp_files(network.temp_files);
+    drop(network.docker_network);
+    let quota_exceeded = network.egress.is_some_and(egress::Session::finish);
+    if let Some(learn) = network.learn {
+        learn.finish(Path::new(config::CONFIG_FILE));
+    }
+    if quota_exceeded {
+        eprintln!("[RustyYOLO] ❌ The session was stopped because an egress quota was exceeded.");
+        std::process::exit(1);
+    }
     if !status.success() {
         eprintln!("[RustyYOLO] Container exited with an error.");
         std::process::exit(status.code().unwrap_or(1));
     }
 }
 
+/// Removes the temporary files and directories passed to Docker.
+fn remove_temp_files(paths: Vec<PathBuf>) {
+    for path in paths {
+        let _ = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -865,96 +2788,99 @@ mod tests {
     fn test_validate_volumes_safe_mounts() {
         // Safe volume mounts should pass
         let safe_volumes = vec![
-            "~/.ssh:/home/agent/.ssh:ro".to_string(),
+            "~/.ssh/known_hosts:/home/agent/.ssh/known_hosts:ro".to_string(),
             "~/.gitconfig:/home/agent/.gitconfig:ro".to_string(),
             "/home/user/project:/app".to_string(),
             "/tmp/data:/data:ro".to_string(),
         ];
-        assert!(validate_volumes(&safe_volumes).is_none());
+        assert!(validate_volumes(&safe_volumes, &[]).is_ok());
     }
 
     #[test]
     fn test_validate_volumes_docker_socket() {
         // Docker socket mounts should be blocked
         let dangerous = vec!["/var/run/docker.sock:/var/run/docker.sock".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
-        assert!(result.unwrap().contains("Docker socket"));
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Docker socket"));
     }
 
     #[test]
     fn test_validate_volumes_docker_socket_uppercase() {
         // Case-insensitive check for docker.sock
         let dangerous = vec!["/var/run/DOCKER.SOCK:/var/run/docker.sock".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_validate_volumes_proc_mount() {
         // /proc mounts should be blocked
         let dangerous = vec!["/proc:/proc".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
-        assert!(result.unwrap().contains("/proc"));
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("/proc"));
     }
 
     #[test]
     fn test_validate_volumes_sys_mount() {
         // /sys mounts should be blocked
         let dangerous = vec!["/sys:/sys:ro".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
-        assert!(result.unwrap().contains("/sys"));
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("/sys"));
     }
 
     #[test]
     fn test_validate_volumes_dev_mount() {
         // /dev mounts should be blocked
         let dangerous = vec!["/dev:/dev".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
-        assert!(result.unwrap().contains("/dev"));
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("/dev"));
     }
 
     #[test]
     fn test_validate_volumes_boot_mount() {
         // /boot mounts should be blocked
         let dangerous = vec!["/boot:/boot".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
-        assert!(result.unwrap().contains("/boot"));
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("/boot"));
     }
 
     #[test]
     fn test_validate_volumes_etc_mount() {
         // /etc mounts should be blocked
         let dangerous = vec!["/etc:/etc:ro".to_string()];
-        let result = validate_volumes(&dangerous);
-        assert!(result.is_some());
-        assert!(result.unwrap().contains("/etc"));
+        let result = validate_volumes(&dangerous, &[]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("/etc"));
     }
 
     #[test]
     fn test_validate_volumes_proc_subdirectory_allowed() {
         // User projects with "proc" in the name should be allowed
         let safe = vec!["/home/user/myproc:/myproc".to_string()];
-        assert!(validate_volumes(&safe).is_none());
+        assert!(validate_volumes(&safe, &[]).is_ok());
     }
 
     #[test]
     fn test_validate_volumes_mixed_safe_and_dangerous() {
         // If any volume is dangerous, should fail
-        let mixed = vec!["~/.ssh:/home/agent/.ssh:ro".to_string(), "/proc:/proc".to_string()];
-        let result = validate_volumes(&mixed);
-        assert!(result.is_some());
+        let mixed = vec![
+            "~/.ssh/known_hosts:/home/agent/.ssh/known_hosts:ro".to_string(),
+            "/proc:/proc".to_string(),
+        ];
+        let result = validate_volumes(&mixed, &[]);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_validate_volumes_empty_list() {
         // Empty volume list should pass
         let empty: Vec<String> = vec![];
-        assert!(validate_volumes(&empty).is_none());
+        assert!(validate_volumes(&empty, &[]).is_ok());
     }
 
     // Tests for setup_seccomp function
@@ -982,4 +2908,233 @@ mod tests {
         // Should have a seccomp profile path
         assert!(args.iter().any(|arg| arg.starts_with("seccomp=")));
     }
+
+    fn run_args(cli: &[&str]) -> RunArgs {
+        let cli =
+            Cli::try_parse_from(std::iter::once("rustyolo").chain(cli.iter().copied())).unwrap();
+        cli.run_args.unwrap()
+    }
+
+    /// The run arguments for `cli` after merging in the config file `toml_str`
+    fn merged(cli: &[&str], toml_str: &str) -> RunArgs {
+        let mut args = run_args(cli);
+        merge_config_with_args(&mut args, toml::from_str(toml_str).unwrap());
+        args
+    }
+
+    // Tests for merge_config_with_args function
+    #[test]
+    fn test_merge_config_with_args_upstream_proxy() {
+        let config = "[network]\nupstream_proxy = \"http://proxy.corp:3128\"";
+        let args = merged(&["codex"], config);
+        assert_eq!(args.upstream_proxy.as_deref(), Some("http://proxy.corp:3128"));
+
+        let args = merged(&["--upstream-proxy", "http://other:8080", "codex"], config);
+        assert_eq!(args.upstream_proxy.as_deref(), Some("http://other:8080"));
+    }
+
+    // Tests for effective_network_mode function
+    #[test]
+    fn test_upstream_proxy_forces_proxy_mode() {
+        use firewall::NetworkMode::{Firewall, Proxy};
+        assert_eq!(effective_network_mode(Firewall, false, false), Firewall);
+        assert_eq!(effective_network_mode(Firewall, true, false), Proxy);
+        assert_eq!(effective_network_mode(Proxy, true, false), Proxy);
+
+        assert!(upstream_proxy_addrs(None).is_empty());
+        assert_eq!(
+            upstream_proxy_addrs(Some("http://127.0.0.1:3128")),
+            vec!["127.0.0.1:3128".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_merge_config_with_args_network_mode() {
+        let config = "[network]\nmode = \"proxy\"";
+        assert_eq!(merged(&["codex"], config).network_mode, "proxy");
+        assert_eq!(merged(&["codex"], "").network_mode, DEFAULT_NETWORK_MODE);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_refresh_interval() {
+        let config = "[network]\nrefresh_interval = 60";
+        assert_eq!(merged(&["codex"], config).refresh_interval, 60);
+        assert_eq!(merged(&["--refresh-interval", "30", "codex"], config).refresh_interval, 30);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_strict_ports() {
+        assert!(merged(&["codex"], "[network]\nstrict_ports = true").strict_ports);
+        assert!(!merged(&["codex"], "").strict_ports);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_allow_cidrs() {
+        let config = "[default]\nallow_cidrs = \"10.0.0.0/8\"";
+        assert_eq!(merged(&["codex"], config).allow_cidrs.as_deref(), Some("10.0.0.0/8"));
+
+        let args = merged(&["--allow-cidrs", "192.168.1.0/24", "codex"], config);
+        assert_eq!(args.allow_cidrs.as_deref(), Some("192.168.1.0/24"));
+    }
+
+    #[test]
+    fn test_merge_config_with_args_allow_presets() {
+        let config = "[default]\nallow_presets = [\"rust\"]";
+        assert_eq!(merged(&["codex"], config).allow_presets, vec!["rust"]);
+        assert_eq!(
+            merged(&["--allow-preset", "python", "codex"], config).allow_presets,
+            vec!["python"]
+        );
+    }
+
+    #[test]
+    fn test_merge_config_with_args_deny_domains() {
+        let config = "[default]\ndeny_domains = \"gist.github.com\"";
+        assert_eq!(merged(&["codex"], config).deny_domains.as_deref(), Some("gist.github.com"));
+
+        let args = merged(&["--deny-domains", "pastebin.com", "codex"], config);
+        assert_eq!(args.deny_domains.as_deref(), Some("pastebin.com"));
+    }
+
+    #[test]
+    fn test_merge_config_with_args_offline_mode() {
+        let config = "[network]\nmode = \"offline\"";
+        assert_eq!(merged(&["codex"], config).network_mode, "offline");
+        assert_eq!(merged(&["--network", "proxy", "codex"], config).network_mode, "proxy");
+    }
+
+    // Tests for offline_warnings function
+    #[test]
+    fn test_offline_warnings() {
+        assert!(offline_warnings(&run_args(&["--network", "offline", "codex"])).is_empty());
+
+        let warnings = offline_warnings(&run_args(&["--network", "offline", "claude"]));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("Anthropic API"));
+
+        for flags in [
+            ["--allow-private", "10.20.0.0/16"],
+            ["--max-egress", "200MB"],
+            ["--upstream-proxy", "http://proxy.corp:3128"],
+            ["--sidecar", "postgres-dev"],
+        ] {
+            let args = run_args(&[flags[0], flags[1], "--network", "offline", "codex"]);
+            let warnings = offline_warnings(&args);
+            assert_eq!(warnings.len(), 1, "{flags:?}");
+            assert!(warnings[0].contains("ignored in offline mode"));
+        }
+
+        let mut args = run_args(&["--network", "offline", "codex"]);
+        args.live_approval = true;
+        assert_eq!(offline_warnings(&args).len(), 1);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_host_ports() {
+        let config = "[network]\nhost_ports = [5432, 11434]";
+        assert_eq!(merged(&["codex"], config).host_ports, vec![5432, 11434]);
+        assert_eq!(merged(&["--host-ports", "8080", "codex"], config).host_ports, vec![8080]);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_allow_private() {
+        let config = "[network]\nallow_private = \"10.20.0.0/16\"";
+        assert_eq!(merged(&["codex"], config).allow_private.as_deref(), Some("10.20.0.0/16"));
+
+        let args = merged(&["--allow-private", "10.30.0.0/16", "codex"], config);
+        assert_eq!(args.allow_private.as_deref(), Some("10.30.0.0/16"));
+    }
+
+    #[test]
+    fn test_merge_config_with_args_egress_quotas() {
+        let config =
+            "[network]\nmax_egress = \"200MB\"\negress_quotas = { \"github.com\" = \"50MB\" }";
+        let args = merged(&["codex"], config);
+        assert_eq!(args.max_egress.as_deref(), Some("200MB"));
+        assert_eq!(args.egress_quotas, vec!["github.com=50MB"]);
+
+        let args = merged(&["--egress-quota", "pypi.org=1GB", "codex"], config);
+        assert_eq!(args.max_egress.as_deref(), Some("200MB"));
+        assert_eq!(args.egress_quotas, vec!["pypi.org=1GB"]);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_live_approval() {
+        assert!(merged(&["codex"], "[network]\nlive_approval = true").live_approval);
+        assert!(!merged(&["codex"], "").live_approval);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_ca_certificates() {
+        let config = "[security]\nca_certificates = [\"corp.pem\"]";
+        assert_eq!(merged(&["codex"], config).ca_certificates, vec!["corp.pem"]);
+        assert_eq!(
+            merged(&["--ca-cert", "other.pem", "codex"], config).ca_certificates,
+            vec!["other.pem"]
+        );
+    }
+
+    #[test]
+    fn test_merge_config_with_args_http_policies() {
+        let args = merged(
+            &["codex"],
+            "[network]\nhttp_policies = [{ host = \"api.github.com\", methods = [\"GET\"] }]",
+        );
+        assert_eq!(args.http_policies.len(), 1);
+        assert_eq!(args.http_policies[0].host, "api.github.com");
+        assert!(merged(&["codex"], "").http_policies.is_empty());
+    }
+
+    #[test]
+    fn test_http_policies_force_proxy_mode() {
+        use firewall::NetworkMode::{Firewall, Proxy};
+        assert_eq!(effective_network_mode(Firewall, false, true), Proxy);
+        assert_eq!(effective_network_mode(Proxy, false, true), Proxy);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_sidecars() {
+        let config = "[network]\nsidecars = [\"postgres-dev\"]";
+        assert_eq!(merged(&["codex"], config).sidecars, vec!["postgres-dev"]);
+        assert_eq!(
+            merged(&["--sidecar", "redis-dev", "codex"], config).sidecars,
+            vec!["redis-dev"]
+        );
+    }
+
+    #[test]
+    fn test_merge_config_with_args_workspace() {
+        let args = merged(&["codex"], "[default]\nworkspace = \"overlay\"");
+        assert_eq!(args.workspace, "overlay");
+        assert_eq!(workspace_mode(&args), workspace::Mode::Overlay);
+        assert_eq!(workspace_mode(&merged(&["codex"], "")), workspace::Mode::Bind);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_worktree() {
+        assert!(merged(&["codex"], "[default]\nworktree = true").worktree);
+        assert!(!merged(&["codex"], "").worktree);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_snapshot() {
+        let config = "[default]\nsnapshot = false\nsnapshot_ignored_limit = \"none\"";
+        let args = merged(&["codex"], config);
+        assert!(args.no_snapshot);
+        assert_eq!(args.snapshot_ignored_limit, "none");
+
+        let args = merged(&["--snapshot-ignored-limit", "1GB", "codex"], config);
+        assert_eq!(args.snapshot_ignored_limit, "1GB");
+        assert!(!merged(&["codex"], "").no_snapshot);
+    }
+
+    #[test]
+    fn test_merge_config_with_args_deny_mounts() {
+        let config = "[security]\ndeny_mounts = [\"~/.kube\"]";
+        assert_eq!(merged(&["codex"], config).deny_mounts, vec!["~/.kube"]);
+        assert_eq!(
+            merged(&["--deny-mount", "~/.aws", "codex"], config).deny_mounts,
+            vec!["~/.aws"]
+        );
+    }
 }
@@ -0,0 +1,213 @@
+//! A dedicated Docker network for each session.
+//!
+//! On Docker's default bridge network, every container can reach every other one: two
+//! sandboxes, or a sandbox and an unrelated database container on the same host. Each
+//! session therefore runs on its own user-defined bridge network, named after the session,
+//! with inter-container communication disabled. The sandbox firewall additionally drops
+//! traffic to the network's subnet (see [`crate::firewall`]), except to the sidecars: the
+//! containers the user declared with `--sidecar` / `[network] sidecars`, which are
+//! attached to the network for the session and reachable by name.
+//!
+//! Inter-container communication has to be enabled on the network when there are
+//! sidecars, since Docker would otherwise drop the traffic to them; only the agent and
+//! the sidecars are on it, and the agent's firewall only lets it reach the sidecars.
+//!
+//! The network is removed (and the sidecars detached) when the session ends.
+
+use crate::cidr::Cidr;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Label on session networks, set to the session ID; stale networks left by a crashed
+/// session can be removed with `docker network prune --filter label=rustyolo.session`
+pub const SESSION_LABEL: &str = "rustyolo.session";
+
+/// A container attached to the session network that the agent may reach
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sidecar {
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+/// The addresses of a session network, as far as the firewall is concerned
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Addresses {
+    /// Subnets of the network; traffic to them is dropped
+    pub subnets: Vec<Cidr>,
+
+    /// Sidecars on the network; traffic to them is allowed
+    pub sidecars: Vec<Sidecar>,
+}
+
+/// A session network, removed when dropped
+pub struct SessionNetwork {
+    name: String,
+    addresses: Addresses,
+}
+
+impl SessionNetwork {
+    /// Docker network name for a session
+    pub fn name_for(session: &str) -> String {
+        format!("rustyolo-{session}")
+    }
+
+    /// Create the network for `session` and attach the sidecars to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session ID the network is named and labeled after
+    /// * `sidecars` - Names of running containers to attach
+    ///
+    /// # Returns
+    ///
+    /// The network, or why it could not be set up (in which case nothing is left behind)
+    pub fn create(session: &str, sidecars: &[String]) -> Result<Self, String> {
+        let name = Self::name_for(session);
+        docker(Command::new("docker").args(create_args(session, sidecars)))
+            .map_err(|e| format!("Failed to create the session network {name}: {e}"))?;
+
+        // From here on, dropping the network removes it again
+        let mut network = Self { name, addresses: Addresses::default() };
+        let subnets = docker(Command::new("docker").args([
+            "network",
+            "inspect",
+            "--format",
+            "{{range .IPAM.Config}}{{.Subnet}} {{end}}",
+            &network.name,
+        ]))?;
+        network.addresses.subnets = parse_subnets(&subnets)?;
+
+        for sidecar in sidecars {
+            let ip = network.attach(sidecar)?;
+            network.addresses.sidecars.push(Sidecar { name: sidecar.clone(), ip });
+        }
+        Ok(network)
+    }
+
+    /// Connect a running container to the network and return its address on it.
+    fn attach(&self, container: &str) -> Result<IpAddr, String> {
+        docker(Command::new("docker").args(["network", "connect", &self.name, container]))
+            .map_err(|e| format!("Failed to attach sidecar '{container}': {e}"))?;
+        let format =
+            format!("{{{{(index .NetworkSettings.Networks \"{}\").IPAddress}}}}", self.name);
+        let ip = docker(Command::new("docker").args(["inspect", "--format", &format, container]))?;
+        parse_sidecar_ip(&ip, container, &self.name)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn addresses(&self) -> &Addresses {
+        &self.addresses
+    }
+}
+
+impl Drop for SessionNetwork {
+    fn drop(&mut self) {
+        for sidecar in &self.addresses.sidecars {
+            let _ = docker(Command::new("docker").args([
+                "network",
+                "disconnect",
+                "--force",
+                &self.name,
+                &sidecar.name,
+            ]));
+        }
+        // `docker run --rm` may still be removing the container, which keeps the network busy
+        let mut result = Ok(String::new());
+        for _ in 0..10 {
+            result = docker(Command::new("docker").args(["network", "rm", &self.name]));
+            if result.is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(300));
+        }
+        if let Err(e) = result {
+            eprintln!("[RustyYOLO] ⚠️  Failed to remove the session network {}: {e}", self.name);
+        }
+    }
+}
+
+/// Arguments of the `docker network create` command for a session's network.
+fn create_args(session: &str, sidecars: &[String]) -> Vec<String> {
+    let mut args: Vec<String> = ["network", "create", "--driver", "bridge", "--label"]
+        .map(String::from)
+        .to_vec();
+    args.push(format!("{SESSION_LABEL}={session}"));
+    if sidecars.is_empty() {
+        args.extend(["--opt", "com.docker.network.bridge.enable_icc=false"].map(String::from));
+    }
+    args.push(SessionNetwork::name_for(session));
+    args
+}
+
+/// Parse the space-separated subnets printed by `docker network inspect`.
+fn parse_subnets(output: &str) -> Result<Vec<Cidr>, String> {
+    output.split_whitespace().map(Cidr::parse).collect()
+}
+
+/// Parse a sidecar's address on `network` as printed by `docker inspect`; it is empty when
+/// the container has no IPv4 address there.
+fn parse_sidecar_ip(output: &str, container: &str, network: &str) -> Result<IpAddr, String> {
+    output
+        .trim()
+        .parse()
+        .map_err(|_| format!("Sidecar '{container}' has no IPv4 address on {network}"))
+}
+
+/// Run a Docker command, returning its output or its error message.
+fn docker(command: &mut Command) -> Result<String, String> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run docker: {e}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_args() {
+        assert_eq!(
+            create_args("4242", &[]).join(" "),
+            "network create --driver bridge --label rustyolo.session=4242 \
+             --opt com.docker.network.bridge.enable_icc=false rustyolo-4242"
+        );
+        // Sidecars need inter-container communication
+        assert_eq!(
+            create_args("4242", &["postgres-dev".to_string()]).join(" "),
+            "network create --driver bridge --label rustyolo.session=4242 rustyolo-4242"
+        );
+    }
+
+    #[test]
+    fn test_parse_subnets() {
+        assert_eq!(
+            parse_subnets("172.18.0.0/16 fd00:18::/64 \n").unwrap(),
+            crate::cidr::parse_list("172.18.0.0/16 fd00:18::/64").unwrap()
+        );
+        assert!(parse_subnets("").unwrap().is_empty());
+        assert!(parse_subnets("172.18.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_parse_sidecar_ip() {
+        assert_eq!(
+            parse_sidecar_ip("172.18.0.3\n", "postgres-dev", "rustyolo-4242"),
+            Ok(IpAddr::from([172, 18, 0, 3]))
+        );
+        assert_eq!(
+            parse_sidecar_ip("\n", "postgres-dev", "rustyolo-4242"),
+            Err("Sidecar 'postgres-dev' has no IPv4 address on rustyolo-4242".to_string())
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Named allowlist presets for common ecosystems.
+//!
+//! A preset expands to a fixed list of `allow_domains` entries, so teams don't have to
+//! re-type registry hostnames in every project. User-defined presets from the global
+//! config file (see [`crate::config::GlobalConfig`]) take precedence over built-in
+//! presets with the same name.
+
+use std::collections::BTreeMap;
+
+/// Built-in presets, sorted by name
+pub const BUILTIN: &[(&str, &[&str])] = &[
+    (
+        "github",
+        &[
+            "github.com",
+            "api.github.com",
+            "codeload.github.com",
+            "objects.githubusercontent.com",
+            "raw.githubusercontent.com",
+        ],
+    ),
+    ("go", &["proxy.golang.org", "sum.golang.org"]),
+    ("node", &["registry.npmjs.org", "npmjs.org", "registry.yarnpkg.com"]),
+    ("python", &["pypi.org", "files.pythonhosted.org"]),
+    (
+        "rust",
+        &["crates.io", "static.crates.io", "index.crates.io", "static.rust-lang.org"],
+    ),
+];
+
+/// Expand preset names into `allow_domains` entries.
+///
+/// # Arguments
+///
+/// * `names` - Preset names, e.g. from `--allow-preset python,node`
+/// * `custom` - User-defined presets from the global config file
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The entries of all presets, in order and without duplicates
+/// * `Err(String)` - The first unknown preset name, with the available names
+///
+/// # Examples
+///
+/// ```no_run
+/// let entries = expand(&["python".to_string()], &BTreeMap::new())?;
+/// assert_eq!(entries, ["pypi.org", "files.pythonhosted.org"]);
+/// ```
+pub fn expand(
+    names: &[String],
+    custom: &BTreeMap<String, Vec<String>>,
+) -> Result<Vec<String>, String> {
+    let mut entries: Vec<String> = Vec::new();
+    for name in names.iter().map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        let preset: Vec<String> = if let Some(domains) = custom.get(name) {
+            domains.clone()
+        } else if let Some((_, domains)) = BUILTIN.iter().find(|(builtin, _)| *builtin == name) {
+            domains.iter().map(ToString::to_string).collect()
+        } else {
+            return Err(format!(
+                "Unknown allowlist preset '{name}'. Available presets: {}",
+                available(custom).join(", ")
+            ));
+        };
+
+        for entry in preset {
+            if !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Names of all presets, built-in and user-defined
+fn available(custom: &BTreeMap<String, Vec<String>>) -> Vec<&str> {
+    let mut names: Vec<&str> = BUILTIN.iter().map(|(name, _)| *name).collect();
+    names.extend(custom.keys().map(String::as_str));
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_expand_builtin_presets() {
+        let entries = expand(&names(&["python", "go"]), &BTreeMap::new()).unwrap();
+        assert_eq!(
+            entries,
+            ["pypi.org", "files.pythonhosted.org", "proxy.golang.org", "sum.golang.org"]
+        );
+    }
+
+    #[test]
+    fn test_expand_custom_preset_overrides_builtin() {
+        let mut custom = BTreeMap::new();
+        custom.insert("python".to_string(), names(&["pypi.internal.example.com"]));
+        custom.insert("corp".to_string(), names(&["git.corp.example.com:22", "pypi.org"]));
+
+        let entries = expand(&names(&["python", "corp", "python"]), &custom).unwrap();
+        assert_eq!(entries, ["pypi.internal.example.com", "git.corp.example.com:22", "pypi.org"]);
+    }
+
+    #[test]
+    fn test_expand_rejects_unknown_preset() {
+        let mut custom = BTreeMap::new();
+        custom.insert("corp".to_string(), Vec::new());
+
+        let err = expand(&names(&["pyhton"]), &custom).unwrap_err();
+        assert!(err.contains("'pyhton'"));
+        assert!(err.contains("corp, github, go, node, python, rust"));
+    }
+
+    #[test]
+    fn test_builtin_presets_are_valid_entries() {
+        for (name, domains) in BUILTIN {
+            assert!(crate::domains::parse_list(&domains.join(" ")).is_ok(), "preset {name}");
+        }
+    }
+}
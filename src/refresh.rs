@@ -0,0 +1,368 @@
+//! Background re-resolution of trusted domains inside the sandbox.
+//!
+//! CDN-backed domains such as `api.anthropic.com` or `github.com` move between IPs over
+//! time, so rules built once at container start go stale during long sessions. The
+//! refresher owns the [`firewall::TRUSTED_CHAIN`] chain: it resolves every trusted domain
+//! at startup, then re-resolves them at a fixed interval, adding rules for new addresses
+//! and expiring addresses that have stopped appearing.
+//!
+//! Domains pinned on the host (see [`crate::resolve`]) are never looked up through the
+//! sandbox resolver. At every refresh they are checked again against the configured DNS
+//! servers with the host's agreement check instead; addresses that pass it are opened and
+//! published to the DNS forwarder, and a domain that fails it keeps its last checked
+//! addresses (none: it stays blocked).
+
+use crate::audit::AuditLevel;
+use crate::cidr::Blocklist;
+use crate::domains::{AllowEntry, Port};
+use crate::egress::Quotas;
+use crate::firewall;
+use crate::resolve;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// Number of consecutive refreshes an address may be missing from DNS answers before its
+/// rules are removed. Round-robin DNS often returns only a subset of a domain's addresses,
+/// so dropping an address the first time it is missing would make connections flap.
+const STALE_AFTER: u64 = 3;
+
+/// Runtime settings for the refresher
+pub struct RefreshConfig {
+    /// Entries with exact domain names to resolve (patterns are handled by the DNS forwarder)
+    pub entries: Vec<AllowEntry>,
+
+    /// Addresses resolved and checked on the host, used instead of every lookup
+    pub pinned: BTreeMap<String, Vec<IpAddr>>,
+
+    /// DNS servers the pinned names are checked against again at every refresh
+    pub servers: Vec<IpAddr>,
+
+    /// File the re-checked pins are written to for the DNS forwarder
+    pub pins_file: Option<PathBuf>,
+
+    /// Seconds between refreshes; 0 resolves once and exits
+    pub interval: u64,
+
+    /// Also allow IPv6 addresses (AAAA records) through `ip6tables`
+    pub ipv6: bool,
+
+    /// Ranges resolved addresses may not fall into
+    pub blocklist: Blocklist,
+
+    /// Egress quotas; when set, traffic to new addresses is counted per domain
+    pub quotas: Quotas,
+
+    /// File to create once the initial rules are in place
+    pub ready_file: Option<PathBuf>,
+
+    /// Audit logging level
+    pub audit: AuditLevel,
+}
+
+/// The result of resolving one domain
+pub struct Resolution {
+    pub domain: String,
+
+    /// Ports the domain is allowed on (`None` = all ports)
+    pub ports: Vec<Option<Port>>,
+
+    /// Resolved addresses, or `None` if the lookup failed
+    pub addresses: Option<Vec<IpAddr>>,
+}
+
+/// A change to apply to the trusted chain
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Added { ip: IpAddr, port: Option<Port>, domain: String },
+    Expired { ip: IpAddr, port: Option<Port>, domain: String },
+}
+
+#[derive(Debug)]
+struct Seen {
+    domain: String,
+    last_seen: u64,
+}
+
+/// The set of address/port pairs currently allowed in the trusted chain
+#[derive(Debug, Default)]
+pub struct TrustedSet {
+    entries: BTreeMap<(IpAddr, Option<Port>), Seen>,
+    generation: u64,
+}
+
+impl TrustedSet {
+    /// Record one round of resolutions and return the rule changes it requires.
+    ///
+    /// Addresses of domains whose lookup failed are kept alive, so a transient DNS outage
+    /// does not cut off the session.
+    pub fn update(&mut self, results: &[Resolution]) -> Vec<Change> {
+        self.generation += 1;
+        let generation = self.generation;
+        let mut changes = Vec::new();
+
+        for result in results {
+            match &result.addresses {
+                Some(addresses) => {
+                    for key in
+                        addresses.iter().flat_map(|ip| result.ports.iter().map(|p| (*ip, *p)))
+                    {
+                        if let Some(seen) = self.entries.get_mut(&key) {
+                            seen.last_seen = generation;
+                        } else {
+                            self.entries.insert(
+                                key,
+                                Seen { domain: result.domain.clone(), last_seen: generation },
+                            );
+                            changes.push(Change::Added {
+                                ip: key.0,
+                                port: key.1,
+                                domain: result.domain.clone(),
+                            });
+                        }
+                    }
+                }
+                None => {
+                    for seen in self.entries.values_mut().filter(|s| s.domain == result.domain) {
+                        seen.last_seen = generation;
+                    }
+                }
+            }
+        }
+
+        self.entries.retain(|&(ip, port), seen| {
+            if generation - seen.last_seen >= STALE_AFTER {
+                changes.push(Change::Expired { ip, port, domain: seen.domain.clone() });
+                false
+            } else {
+                true
+            }
+        });
+
+        changes
+    }
+}
+
+/// Resolve the trusted domains, apply the rules, and keep refreshing them.
+///
+/// Runs until the process is killed, unless `interval` is 0.
+pub fn run(config: &RefreshConfig) -> Result<(), Box<dyn Error>> {
+    let mut targets: BTreeMap<String, Vec<Option<Port>>> = BTreeMap::new();
+    for entry in &config.entries {
+        targets.entry(entry.pattern.to_string()).or_default().push(entry.port);
+    }
+
+    let mut trusted = TrustedSet::default();
+    let mut pinned = config.pinned.clone();
+    let mut first_pass = true;
+
+    loop {
+        let repinned = !first_pass && recheck_pins(&mut pinned, config);
+        let results: Vec<Resolution> = targets
+            .iter()
+            .map(|(domain, ports)| {
+                let mut result = match pinned.get(domain) {
+                    Some(pinned) => Resolution {
+                        domain: domain.clone(),
+                        ports: ports.clone(),
+                        addresses: Some(
+                            pinned
+                                .iter()
+                                .copied()
+                                .filter(|ip| config.ipv6 || ip.is_ipv4())
+                                .collect(),
+                        ),
+                    },
+                    _ => resolve(domain, ports, config.ipv6),
+                };
+                result.addresses = result
+                    .addresses
+                    .map(|ips| firewall::screen_resolved(domain, ips, &config.blocklist));
+                result
+            })
+            .collect();
+        for result in results.iter().filter(|r| r.addresses.is_none()) {
+            eprintln!("[RustyYOLO Firewall] WARNING: Could not resolve {}", result.domain);
+        }
+
+        for change in trusted.update(&results) {
+            apply(&change, config);
+        }
+
+        // Published once the new addresses are open, so answers never name a closed one
+        if let Some(pins_file) = config.pins_file.as_ref().filter(|_| repinned) {
+            if let Err(e) = resolve::write_pins(pins_file, &pinned) {
+                eprintln!("[RustyYOLO Firewall] ERROR: {e}");
+            }
+        }
+
+        if first_pass {
+            first_pass = false;
+            if let Some(ready_file) = &config.ready_file {
+                fs::write(ready_file, b"")
+                    .map_err(|e| format!("Failed to write {}: {e}", ready_file.display()))?;
+            }
+        }
+
+        if config.interval == 0 {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(config.interval));
+    }
+}
+
+/// Check the pinned names again against the configured servers.
+///
+/// # Returns
+///
+/// Whether any pin changed
+fn recheck_pins(pinned: &mut BTreeMap<String, Vec<IpAddr>>, config: &RefreshConfig) -> bool {
+    if pinned.is_empty() || config.servers.is_empty() {
+        return false;
+    }
+    let names: Vec<String> = pinned.keys().cloned().collect();
+    let fresh = resolve::resolve(&names, &config.servers, config.ipv6);
+    for pin in fresh.iter().filter(|pin| pin.addresses.is_empty()) {
+        eprintln!(
+            "[RustyYOLO Firewall] WARNING: Could not check {} again ({}); keeping its pinned \
+             addresses",
+            pin.domain, pin.check
+        );
+    }
+    resolve::repin(pinned, fresh)
+}
+
+fn resolve(domain: &str, ports: &[Option<Port>], ipv6: bool) -> Resolution {
+    let addresses = (domain, 0).to_socket_addrs().ok().map(|addrs| {
+        let mut ips: Vec<IpAddr> =
+            addrs.map(|addr| addr.ip()).filter(|ip| ipv6 || ip.is_ipv4()).collect();
+        ips.sort_unstable();
+        ips.dedup();
+        ips
+    });
+    Resolution {
+        domain: domain.to_string(),
+        ports: ports.to_vec(),
+        addresses: addresses.filter(|ips| !ips.is_empty()),
+    }
+}
+
+fn apply(change: &Change, config: &RefreshConfig) {
+    let audit: AuditLevel = config.audit;
+    let result = match change {
+        Change::Added { ip, port, domain } => {
+            println!(
+                "[RustyYOLO Firewall] ALLOWING IP: {ip}{} (for {domain})",
+                firewall::port_suffix(*port)
+            );
+            if config.quotas.is_empty() {
+                firewall::allow_ip(firewall::TRUSTED_CHAIN, *ip, *port, audit)
+            } else {
+                firewall::account_ip(*ip, domain, &config.quotas)
+                    .and_then(|()| firewall::allow_ip(firewall::TRUSTED_CHAIN, *ip, *port, audit))
+            }
+        }
+        Change::Expired { ip, port, domain } => {
+            println!(
+                "[RustyYOLO Firewall] EXPIRING IP: {ip}{} (for {domain}, no longer resolved)",
+                firewall::port_suffix(*port)
+            );
+            firewall::remove_ip(firewall::TRUSTED_CHAIN, *ip, *port, audit)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("[RustyYOLO Firewall] ERROR: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(domain: &str, ips: &[[u8; 4]]) -> Resolution {
+        Resolution {
+            domain: domain.to_string(),
+            ports: vec![None],
+            addresses: Some(ips.iter().map(|o| IpAddr::from(*o)).collect()),
+        }
+    }
+
+    #[test]
+    fn test_update_adds_new_addresses_once() {
+        let mut trusted = TrustedSet::default();
+        let changes = trusted.update(&[resolved("github.com", &[[140, 82, 112, 3]])]);
+        assert_eq!(
+            changes,
+            vec![Change::Added {
+                ip: IpAddr::from([140, 82, 112, 3]),
+                port: None,
+                domain: "github.com".into()
+            }]
+        );
+
+        let changes = trusted.update(&[resolved("github.com", &[[140, 82, 112, 3]])]);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_update_expires_addresses_after_grace_period() {
+        let mut trusted = TrustedSet::default();
+        trusted.update(&[resolved("github.com", &[[140, 82, 112, 3]])]);
+
+        // The domain moves to a new address
+        for _ in 1..STALE_AFTER {
+            let changes = trusted.update(&[resolved("github.com", &[[140, 82, 112, 4]])]);
+            assert!(!changes.iter().any(|c| matches!(c, Change::Expired { .. })));
+        }
+        let changes = trusted.update(&[resolved("github.com", &[[140, 82, 112, 4]])]);
+        assert_eq!(
+            changes,
+            vec![Change::Expired {
+                ip: IpAddr::from([140, 82, 112, 3]),
+                port: None,
+                domain: "github.com".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_update_tracks_each_port_separately() {
+        let mut trusted = TrustedSet::default();
+        let mut result = resolved("github.com", &[[140, 82, 112, 3]]);
+        result.ports = AllowEntry::parse("github.com:22")
+            .into_iter()
+            .chain(AllowEntry::parse("github.com:443"))
+            .map(|e| e.port)
+            .collect();
+
+        let changes = trusted.update(&[result]);
+        let ports: Vec<String> = changes
+            .iter()
+            .map(|c| match c {
+                Change::Added { port, .. } | Change::Expired { port, .. } => {
+                    firewall::port_suffix(*port)
+                }
+            })
+            .collect();
+        assert_eq!(ports, [" port 22/tcp", " port 443/tcp"]);
+    }
+
+    #[test]
+    fn test_update_keeps_addresses_when_lookup_fails() {
+        let mut trusted = TrustedSet::default();
+        trusted.update(&[resolved("github.com", &[[140, 82, 112, 3]])]);
+
+        for _ in 0..STALE_AFTER * 2 {
+            let changes = trusted.update(&[Resolution {
+                domain: "github.com".into(),
+                ports: vec![None],
+                addresses: None,
+            }]);
+            assert!(changes.is_empty());
+        }
+    }
+}
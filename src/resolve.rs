@@ -0,0 +1,377 @@
+//! Host-side resolution of trusted domains.
+//!
+//! Inside the container, trusted domains would be resolved by whatever resolver Docker
+//! hands the sandbox, so a poisoned or hostile resolver would decide what the firewall
+//! allows. Before the container starts, rustyolo therefore resolves the exact names in
+//! `allow_domains` itself, querying every configured `dns_servers` entry directly:
+//!
+//! - Queries ask for DNSSEC validation (the AD and DO bits). When a server reports a
+//!   validated answer, only validated answers are used for that name.
+//! - Otherwise, the servers' answers are cross-checked: with more than one answering
+//!   server, only addresses returned by at least two of them are pinned. A name the
+//!   servers disagree about entirely, or that no server resolves, is pinned to nothing.
+//!
+//! The pins are passed into the container (`PINNED_ADDRESSES`): the refresher opens the
+//! pinned addresses instead of resolving the names, and the DNS forwarder answers queries
+//! for them with the pinned addresses and never opens rules for them. A name pinned to
+//! nothing stays blocked. The in-sandbox resolver only decides about names matched by
+//! wildcard and suffix patterns.
+//!
+//! CDN-backed names move between addresses during long sessions, so the refresher runs
+//! the same check against the same servers every refresh interval (root may query them
+//! directly) and hands the new pins to the DNS forwarder through a file. A name that fails
+//! the check keeps its last checked addresses; one that passes it later is opened then.
+//!
+//! Validation is done by the configured resolvers, not by rustyolo: the AD bit says
+//! that the resolver checked the DNSSEC signatures, and is only as trustworthy as the
+//! path to the resolver.
+
+use crate::dns::{self, TYPE_A, TYPE_AAAA};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::thread;
+
+/// One server's answer for a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    /// Addresses returned, or `None` if the server did not answer
+    pub addresses: Option<Vec<IpAddr>>,
+
+    /// Whether the server validated every answer with DNSSEC
+    pub authenticated: bool,
+}
+
+/// How the pinned addresses of a name were checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// `servers` of `of` servers returned DNSSEC-validated answers
+    Validated { servers: usize, of: usize },
+
+    /// `servers` of `of` servers returned at least one of the pinned addresses
+    Agreed { servers: usize, of: usize },
+
+    /// Only one of `of` servers answered, so there was nothing to compare with
+    Single { of: usize },
+
+    /// The servers returned no address in common; the name is pinned to nothing
+    Mismatch,
+
+    /// No server returned an address; the name is pinned to nothing
+    Unresolved,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Validated { servers, of } => write!(f, "DNSSEC validated ({servers}/{of})"),
+            Self::Agreed { servers, of } => write!(f, "unsigned, {servers}/{of} servers agree"),
+            Self::Single { of } => write!(f, "unsigned, only 1/{of} servers answered"),
+            Self::Mismatch => write!(f, "servers disagree, blocked"),
+            Self::Unresolved => write!(f, "no answer, blocked"),
+        }
+    }
+}
+
+/// The outcome of resolving one trusted domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    pub domain: String,
+
+    /// Addresses to open in the sandbox (empty if the name stays blocked)
+    pub addresses: Vec<IpAddr>,
+
+    pub check: Check,
+}
+
+/// Decide which addresses to pin from the servers' answers for one name.
+pub fn decide(domain: &str, lookups: &[Lookup]) -> Pin {
+    let answered: Vec<(&Vec<IpAddr>, bool)> = lookups
+        .iter()
+        .filter_map(|lookup| lookup.addresses.as_ref().map(|a| (a, lookup.authenticated)))
+        .filter(|(addresses, _)| !addresses.is_empty())
+        .collect();
+    let of = lookups.len();
+    let pin = |addresses: BTreeSet<IpAddr>, check| Pin {
+        domain: domain.to_string(),
+        addresses: addresses.into_iter().collect(),
+        check,
+    };
+
+    // Validated answers win over any unvalidated ones
+    let validated: Vec<&Vec<IpAddr>> = answered
+        .iter()
+        .filter(|(_, authenticated)| *authenticated)
+        .map(|(a, _)| *a)
+        .collect();
+    if !validated.is_empty() {
+        let servers = validated.len();
+        return pin(
+            validated.into_iter().flatten().copied().collect(),
+            Check::Validated { servers, of },
+        );
+    }
+
+    match answered.as_slice() {
+        [] => pin(BTreeSet::new(), Check::Unresolved),
+        [(addresses, _)] => pin(addresses.iter().copied().collect(), Check::Single { of }),
+        _ => {
+            // Round-robin answers overlap without being equal; require a second opinion
+            let mut votes: BTreeMap<IpAddr, usize> = BTreeMap::new();
+            for (addresses, _) in &answered {
+                for ip in addresses.iter().collect::<BTreeSet<_>>() {
+                    *votes.entry(*ip).or_default() += 1;
+                }
+            }
+            let confirmed: BTreeSet<IpAddr> =
+                votes.into_iter().filter(|(_, count)| *count >= 2).map(|(ip, _)| ip).collect();
+            if confirmed.is_empty() {
+                return pin(confirmed, Check::Mismatch);
+            }
+            let servers = answered
+                .iter()
+                .filter(|(addresses, _)| addresses.iter().any(|ip| confirmed.contains(ip)))
+                .count();
+            pin(confirmed, Check::Agreed { servers, of })
+        }
+    }
+}
+
+/// Ask one server for the addresses of `domain`.
+fn lookup(server: IpAddr, domain: &str, ipv6: bool) -> Lookup {
+    let server = SocketAddr::new(server, 53);
+    let types: &[u16] = if ipv6 {
+        &[TYPE_A, TYPE_AAAA]
+    } else {
+        &[TYPE_A]
+    };
+    let mut result = Lookup { addresses: Some(Vec::new()), authenticated: true };
+    for record_type in types {
+        match dns::lookup(&server, domain, *record_type) {
+            Some(answer) => {
+                result.authenticated &= answer.authenticated;
+                if let Some(addresses) = &mut result.addresses {
+                    addresses.extend(answer.addresses);
+                }
+            }
+            None => return Lookup { addresses: None, authenticated: false },
+        }
+    }
+    result
+}
+
+/// Resolve each domain on every server, in parallel, and decide what to pin.
+///
+/// # Arguments
+///
+/// * `domains` - Exact trusted names (patterns cannot be resolved up front)
+/// * `servers` - The configured DNS servers
+/// * `ipv6` - Also look up AAAA records
+pub fn resolve(domains: &[String], servers: &[IpAddr], ipv6: bool) -> Vec<Pin> {
+    thread::scope(|scope| {
+        let handles: Vec<Vec<_>> = domains
+            .iter()
+            .map(|domain| {
+                servers
+                    .iter()
+                    .map(|server| scope.spawn(move || lookup(*server, domain, ipv6)))
+                    .collect()
+            })
+            .collect();
+        domains
+            .iter()
+            .zip(handles)
+            .map(|(domain, handles)| {
+                let lookups: Vec<Lookup> = handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or(Lookup { addresses: None, authenticated: false })
+                    })
+                    .collect();
+                decide(domain, &lookups)
+            })
+            .collect()
+    })
+}
+
+/// Render the pinned addresses for `PINNED_ADDRESSES`, e.g.
+/// `github.com=140.82.112.3,140.82.112.4 api.github.com=140.82.112.6 typo.example=`.
+pub fn render_pins(pins: &[Pin]) -> String {
+    pins.iter()
+        .map(|pin| render_pin(&pin.domain, &pin.addresses))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn render_pin(domain: &str, addresses: &[IpAddr]) -> String {
+    let addresses: Vec<String> = addresses.iter().map(ToString::to_string).collect();
+    format!("{domain}={}", addresses.join(","))
+}
+
+/// Take the addresses of names that passed the check again into `pins`.
+///
+/// A name the servers now disagree about or do not resolve keeps its last checked
+/// addresses, so a flaky or hostile server can neither block a working name nor move it.
+///
+/// # Returns
+///
+/// Whether any pin changed
+pub fn repin(pins: &mut BTreeMap<String, Vec<IpAddr>>, fresh: Vec<Pin>) -> bool {
+    let mut changed = false;
+    for pin in fresh.into_iter().filter(|pin| !pin.addresses.is_empty()) {
+        let current = pins.entry(pin.domain).or_default();
+        if *current != pin.addresses {
+            *current = pin.addresses;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Write `pins` to `path` in the format of [`parse_pins`], replacing the file in one step
+/// so the DNS forwarder never reads a partial list.
+pub fn write_pins(path: &Path, pins: &BTreeMap<String, Vec<IpAddr>>) -> Result<(), String> {
+    let rendered: Vec<String> =
+        pins.iter().map(|(domain, addresses)| render_pin(domain, addresses)).collect();
+    let staged = path.with_extension("tmp");
+    fs::write(&staged, rendered.join(" "))
+        .and_then(|()| fs::rename(&staged, path))
+        .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
+
+/// Parse `PINNED_ADDRESSES` as rendered by [`render_pins`].
+pub fn parse_pins(list: &str) -> Result<BTreeMap<String, Vec<IpAddr>>, String> {
+    list.split_whitespace()
+        .map(|entry| {
+            let invalid = || format!("Invalid pinned addresses '{entry}'");
+            let (domain, addresses) = entry.split_once('=').ok_or_else(invalid)?;
+            let addresses = addresses
+                .split(',')
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| ip.parse().map_err(|_| invalid()))
+                .collect::<Result<_, _>>()?;
+            Ok((domain.to_string(), addresses))
+        })
+        .collect()
+}
+
+/// Format the pins as a table for the terminal, one line per domain.
+pub fn table(pins: &[Pin]) -> Vec<String> {
+    let rows: Vec<[String; 3]> = pins
+        .iter()
+        .map(|pin| {
+            let addresses: Vec<String> = pin.addresses.iter().map(ToString::to_string).collect();
+            let addresses = if addresses.is_empty() {
+                "-".to_string()
+            } else {
+                addresses.join(" ")
+            };
+            [pin.domain.clone(), addresses, pin.check.to_string()]
+        })
+        .collect();
+    let header = ["DOMAIN", "ADDRESSES", "CHECK"].map(String::from);
+    let width = |column: usize| {
+        rows.iter()
+            .chain([&header])
+            .map(|row| row[column].len())
+            .max()
+            .unwrap_or_default()
+    };
+    let (domain_width, address_width) = (width(0), width(1));
+    [&header]
+        .into_iter()
+        .chain(&rows)
+        .map(|[domain, addresses, check]| {
+            format!("{domain:<domain_width$}  {addresses:<address_width$}  {check}")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(ips: &[[u8; 4]], authenticated: bool) -> Lookup {
+        Lookup { addresses: Some(ips.iter().map(|o| IpAddr::from(*o)).collect()), authenticated }
+    }
+
+    const NO_ANSWER: Lookup = Lookup { addresses: None, authenticated: false };
+
+    #[test]
+    fn test_decide_prefers_validated_answers() {
+        let pin = decide(
+            "api.anthropic.com",
+            &[answer(&[[160, 79, 104, 10]], true), answer(&[[6, 6, 6, 6]], false), NO_ANSWER],
+        );
+        assert_eq!(pin.addresses, vec![IpAddr::from([160, 79, 104, 10])]);
+        assert_eq!(pin.check, Check::Validated { servers: 1, of: 3 });
+    }
+
+    #[test]
+    fn test_decide_requires_agreement_between_servers() {
+        // Overlapping round-robin answers: only the shared address is confirmed
+        let pin = decide(
+            "github.com",
+            &[
+                answer(&[[140, 82, 112, 3], [140, 82, 112, 4]], false),
+                answer(&[[140, 82, 112, 4]], false),
+                answer(&[[6, 6, 6, 6]], false),
+            ],
+        );
+        assert_eq!(pin.addresses, vec![IpAddr::from([140, 82, 112, 4])]);
+        assert_eq!(pin.check, Check::Agreed { servers: 2, of: 3 });
+
+        let pin =
+            decide("github.com", &[answer(&[[1, 1, 1, 1]], false), answer(&[[6, 6, 6, 6]], false)]);
+        assert!(pin.addresses.is_empty());
+        assert_eq!(pin.check, Check::Mismatch);
+
+        let pin = decide("github.com", &[answer(&[[140, 82, 112, 3]], false), NO_ANSWER]);
+        assert_eq!(pin.check, Check::Single { of: 2 });
+        assert_eq!(decide("github.com", &[NO_ANSWER, answer(&[], true)]).check, Check::Unresolved);
+    }
+
+    #[test]
+    fn test_pins_round_trip() {
+        let pins = [
+            decide("github.com", &[answer(&[[140, 82, 112, 3], [140, 82, 112, 4]], true)]),
+            decide("unresolved.example", &[NO_ANSWER]),
+        ];
+        let rendered = render_pins(&pins);
+        assert_eq!(rendered, "github.com=140.82.112.3,140.82.112.4 unresolved.example=");
+        let parsed = parse_pins(&rendered).unwrap();
+        assert_eq!(parsed["github.com"], pins[0].addresses);
+        assert!(parsed["unresolved.example"].is_empty());
+        assert!(parse_pins("github.com=not-an-ip").is_err());
+        assert!(parse_pins("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_repin_keeps_addresses_that_fail_the_check() {
+        let mut pins =
+            parse_pins("github.com=140.82.112.3 cdn.example=1.1.1.1 typo.example=").unwrap();
+        let fresh = vec![
+            decide("github.com", &[answer(&[[140, 82, 112, 4]], true)]),
+            decide(
+                "cdn.example",
+                &[answer(&[[2, 2, 2, 2]], false), answer(&[[6, 6, 6, 6]], false)],
+            ),
+            decide("typo.example", &[NO_ANSWER]),
+        ];
+        assert!(repin(&mut pins, fresh));
+        assert_eq!(pins["github.com"], vec![IpAddr::from([140, 82, 112, 4])]);
+        assert_eq!(pins["cdn.example"], vec![IpAddr::from([1, 1, 1, 1])]);
+        assert!(pins["typo.example"].is_empty());
+        assert!(!repin(
+            &mut pins,
+            vec![decide("github.com", &[answer(&[[140, 82, 112, 4]], true)])]
+        ));
+
+        let path = std::env::temp_dir().join(format!("rustyolo-pins-test-{}", std::process::id()));
+        write_pins(&path, &pins).unwrap();
+        assert_eq!(parse_pins(&fs::read_to_string(&path).unwrap()).unwrap(), pins);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,837 @@
+//! Pre-session snapshots of the project and `rustyolo rollback`.
+//!
+//! Before the container starts, the project directory is snapshotted so that a session
+//! that went wrong can be undone with one command:
+//!
+//! * **git repositories** - the working tree, untracked files included, is committed like
+//!   `git stash` does: a commit of the index and a commit of the working tree on top of
+//!   `HEAD`, kept on a hidden ref, `refs/rustyolo/snapshots/<session>`. Ignored files
+//!   (`.env`, local config) are archived in a compressed tarball next to it. Ignored paths
+//!   larger than a limit, build directories like `target/`, are left out and rollback
+//!   leaves them alone; they are listed when the snapshot is taken.
+//! * **other directories** - a compressed tarball of the whole directory.
+//!
+//! The snapshot's metadata and tarballs are kept in rustyolo's data directory
+//! (`~/.local/share/rustyolo/snapshots` on Linux), which is not mounted into the
+//! container; the auth home is, so the agent could rewrite anything kept there. A git
+//! snapshot's objects live in the repository, which the agent can write: it cannot forge
+//! them, but a session that deleted `.git` cannot be rolled back.
+//!
+//! Rollback restores the files byte for byte and, for git repositories, the branch, `HEAD`
+//! and the index. Files that were not in the snapshot are removed. Only the most recent
+//! [`KEEP_PER_PROJECT`] snapshots of a project are kept.
+//!
+//! The session can also write the repository's config, which git would obey during a
+//! rollback. Git is run on the project with its git directory and work tree named
+//! explicitly, with fsmonitor and hooks turned off and replace refs ignored, and a rollback is refused if the
+//! `core.*`, `include*` or `filter.*` settings (a `core.worktree` elsewhere, or filter
+//! drivers, which checking files out runs) changed since the snapshot.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of the hidden refs git snapshots are kept on
+pub const REF_PREFIX: &str = "refs/rustyolo/snapshots/";
+
+/// Number of snapshots kept for each project; older ones are removed
+pub const KEEP_PER_PROJECT: usize = 10;
+
+/// The git state a snapshot was taken of
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitState {
+    /// Commit of the working tree, kept on the hidden ref
+    pub commit: String,
+
+    /// Commit of the index
+    pub index: String,
+
+    /// The commit `HEAD` pointed to, if any
+    pub head: Option<String>,
+
+    /// The branch that was checked out, if any (e.g. `refs/heads/main`)
+    pub branch: Option<String>,
+
+    /// The `core.*`, `include*` and `filter.*` settings, as `git config --get-regexp`
+    /// lists them
+    #[serde(default)]
+    pub config: Vec<String>,
+
+    /// Ignored paths in the snapshot's tarball; directories end in `/`
+    #[serde(default)]
+    pub ignored: Vec<String>,
+
+    /// Ignored paths left out of the snapshot for being larger than the limit, which
+    /// rollback leaves alone; directories end in `/`
+    #[serde(default)]
+    pub skipped: Vec<String>,
+}
+
+/// A snapshot of a project directory, taken before a session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The session ID the snapshot is named after
+    pub session: String,
+
+    /// The project directory (absolute)
+    pub project: PathBuf,
+
+    /// When the snapshot was taken, in seconds since the Unix epoch
+    pub created: u64,
+
+    /// The git state, for git repositories; other projects are kept as a tarball
+    pub git: Option<GitState>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.git.is_some() { "git" } else { "tarball" };
+        write!(
+            f,
+            "{}  {}  {kind}  {}",
+            self.session,
+            age(now().saturating_sub(self.created)),
+            self.project.display()
+        )
+    }
+}
+
+/// Directory snapshots are kept in
+pub fn default_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("rustyolo")
+        .join("snapshots")
+}
+
+impl Snapshot {
+    /// Snapshot `project` before a session.
+    ///
+    /// # Arguments
+    ///
+    /// * `project` - The project directory
+    /// * `session` - The session ID the snapshot is named after; `-2`, `-3`, ... is appended
+    ///   if a snapshot by that name exists
+    /// * `dir` - Directory to keep the snapshot's metadata and tarball in
+    /// * `ignored_limit` - Size above which an ignored path of a git repository is left
+    ///   out of the snapshot, or `None` to archive them all
+    pub fn create(
+        project: &Path,
+        session: &str,
+        dir: &Path,
+        ignored_limit: Option<u64>,
+    ) -> Result<Self, String> {
+        let project = project
+            .canonicalize()
+            .map_err(|e| format!("Failed to resolve {}: {e}", project.display()))?;
+        fs::create_dir_all(dir)
+            .and_then(|()| fs::set_permissions(dir, fs::Permissions::from_mode(0o700)))
+            .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+
+        let mut suffix = 1;
+        let session = loop {
+            let id = if suffix == 1 {
+                session.to_string()
+            } else {
+                format!("{session}-{suffix}")
+            };
+            if !dir.join(format!("{id}.toml")).exists() {
+                break id;
+            }
+            suffix += 1;
+        };
+
+        let mut snapshot = Self { session, project, created: now(), git: None };
+        if snapshot.is_repository() {
+            let mut state = snapshot.commit_git(dir)?;
+            let (ignored, skipped) = state.ignored.drain(..).partition(|path| {
+                let path = snapshot.project.join(path);
+                !dir.starts_with(&path)
+                    && ignored_limit.is_none_or(|limit| disk_size(&path, limit) <= limit)
+            });
+            (state.ignored, state.skipped) = (ignored, skipped);
+            if !state.ignored.is_empty() {
+                let mut list = state.ignored.join("\0").into_bytes();
+                list.push(0);
+                snapshot.write_archive(dir, &["--null", "-T", "-"], Some(&list))?;
+            }
+            snapshot.git = Some(state);
+        } else {
+            if dir.starts_with(&snapshot.project) {
+                return Err(format!(
+                    "Cannot snapshot {} into {}, which is inside it",
+                    snapshot.project.display(),
+                    dir.display()
+                ));
+            }
+            snapshot.write_archive(dir, &["."], None)?;
+        }
+
+        let metadata = toml::to_string_pretty(&snapshot)
+            .map_err(|e| format!("Failed to serialize the snapshot: {e}"))?;
+        fs::write(dir.join(format!("{}.toml", snapshot.session)), metadata)
+            .map_err(|e| format!("Failed to save the snapshot: {e}"))?;
+        prune(dir, &snapshot.project);
+        Ok(snapshot)
+    }
+
+    /// Whether the project is the top level of a git repository (a subdirectory is
+    /// archived instead, since rolling back the whole repository would touch files the
+    /// session could not reach)
+    fn is_repository(&self) -> bool {
+        git(&self.project, None, &["rev-parse", "--show-toplevel"])
+            .ok()
+            .and_then(|toplevel| Path::new(&toplevel).canonicalize().ok())
+            .is_some_and(|toplevel| toplevel == self.project)
+    }
+
+    /// Commit the index and the working tree, and keep them on the hidden ref.
+    fn commit_git(&self, dir: &Path) -> Result<GitState, String> {
+        let project = &self.project;
+        let head = git(project, None, &["rev-parse", "--verify", "--quiet", "HEAD"]).ok();
+        let branch = git(project, None, &["symbolic-ref", "--quiet", "HEAD"]).ok();
+        let parents: Vec<&str> = head.iter().flat_map(|head| ["-p", head.as_str()]).collect();
+        let message = format!("rustyolo snapshot before session {}", self.session);
+        let commit_tree = |tree: &str, parents: &[&str]| {
+            let mut args = vec!["commit-tree", tree, "-m", &message];
+            args.extend(parents);
+            git(project, None, &args)
+        };
+
+        let index_tree = git(project, None, &["write-tree"])
+            .map_err(|e| format!("Failed to snapshot the git index: {e}"))?;
+        let index = commit_tree(&index_tree, &parents)?;
+
+        // Add the untracked files in a copy of the index, leaving the real one untouched
+        let scratch = self.scratch_index(dir);
+        let real_index = PathBuf::from(git(project, None, &["rev-parse", "--git-path", "index"])?);
+        let real_index = project.join(real_index);
+        let tree = (|| {
+            if real_index.exists() {
+                fs::copy(&real_index, &scratch).map_err(|e| e.to_string())?;
+            }
+            git(project, Some(&scratch), &["add", "--all"])?;
+            git(project, Some(&scratch), &["write-tree"])
+        })();
+        let _ = fs::remove_file(&scratch);
+        let tree = tree.map_err(|e| format!("Failed to snapshot the working tree: {e}"))?;
+
+        let mut parents = parents.clone();
+        parents.extend(["-p", index.as_str()]);
+        let commit = commit_tree(&tree, &parents)?;
+        let reference = format!("{REF_PREFIX}{}", self.session);
+        git(project, None, &["update-ref", &reference, &commit])?;
+
+        let ignored = git(
+            project,
+            None,
+            &["ls-files", "-z", "--others", "--ignored", "--exclude-standard", "--directory"],
+        )
+        .map_err(|e| format!("Failed to list the ignored files: {e}"))?
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(String::from)
+        .collect();
+        Ok(GitState {
+            commit,
+            index,
+            head,
+            branch,
+            config: watched_config(project),
+            ignored,
+            skipped: Vec::new(),
+        })
+    }
+
+    fn archive(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.tar.gz", self.session))
+    }
+
+    /// A scratch git index, in the private snapshot directory rather than a shared one
+    /// where another user could plant a symlink at its predictable name
+    fn scratch_index(&self, dir: &Path) -> PathBuf {
+        let index = dir.join(format!("{}.index", self.session));
+        let _ = fs::remove_file(&index);
+        index
+    }
+
+    /// Archive the project's `paths` into the snapshot's tarball, reading the list of
+    /// paths from `stdin` if given.
+    fn write_archive(
+        &self,
+        dir: &Path,
+        paths: &[&str],
+        stdin: Option<&[u8]>,
+    ) -> Result<(), String> {
+        let archive = self.archive(dir);
+        let mut child = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&self.project)
+            .args(paths)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run tar: {e}"))?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            let _ = pipe.write_all(input);
+        }
+        let output = child.wait_with_output().map_err(|e| format!("Failed to run tar: {e}"))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            let _ = fs::remove_file(&archive);
+            Err(format!(
+                "Failed to archive {}: {}",
+                self.project.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+
+    /// Run tar on the snapshot's tarball with the project as the working directory.
+    fn tar(&self, dir: &Path, args: &[&str]) -> Result<(), String> {
+        let output = Command::new("tar")
+            .args(args)
+            .arg(self.archive(dir))
+            .arg("-C")
+            .arg(&self.project)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .output()
+            .map_err(|e| format!("Failed to run tar: {e}"))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+        }
+    }
+
+    /// Restore the project to the snapshot, discarding what changed since.
+    pub fn restore(&self, dir: &Path) -> Result<(), String> {
+        match &self.git {
+            Some(state) => self.restore_git(state, dir),
+            None => self.restore_archive(dir),
+        }
+    }
+
+    fn restore_git(&self, state: &GitState, dir: &Path) -> Result<(), String> {
+        let project = &self.project;
+        let config = watched_config(project);
+        let changed: Vec<&String> = config
+            .iter()
+            .filter(|setting| !state.config.contains(setting))
+            .chain(state.config.iter().filter(|setting| !config.contains(setting)))
+            .collect();
+        if !changed.is_empty() {
+            let changed: Vec<&str> = changed.iter().map(|setting| setting.as_str()).collect();
+            return Err(format!(
+                "Refusing to roll back: git settings that restoring the files would obey \
+                 changed since the snapshot:\n  {}\nRestore them in the repository's config \
+                 and try again",
+                changed.join("\n  ")
+            ));
+        }
+        for object in [&state.commit, &state.index].into_iter().chain(&state.head) {
+            git(project, None, &["cat-file", "-e", &format!("{object}^{{commit}}")]).map_err(
+                |_| format!("The snapshot's commit {object} is missing from the repository"),
+            )?;
+        }
+        // Read the whole tarball of ignored files before changing anything
+        if !state.ignored.is_empty() {
+            self.tar(dir, &["-tzf"]).map_err(|e| {
+                format!("The snapshot {} is unreadable: {e}", self.archive(dir).display())
+            })?;
+        }
+
+        // Branch and HEAD
+        match (&state.branch, &state.head) {
+            (Some(branch), Some(head)) => {
+                git(project, None, &["update-ref", branch, head])?;
+                git(project, None, &["symbolic-ref", "HEAD", branch])?;
+            }
+            (Some(branch), None) => {
+                let _ = git(project, None, &["update-ref", "-d", branch]);
+                git(project, None, &["symbolic-ref", "HEAD", branch])?;
+            }
+            (None, Some(head)) => {
+                git(project, None, &["update-ref", "--no-deref", "HEAD", head])?;
+            }
+            (None, None) => {}
+        }
+
+        // Working tree, through a scratch index so that every file is written
+        let scratch = self.scratch_index(dir);
+        let tree = format!("{}^{{tree}}", state.commit);
+        let untracked = (|| {
+            git(project, Some(&scratch), &["read-tree", &tree])?;
+            git(project, Some(&scratch), &["checkout-index", "--all", "--force"])?;
+            git(project, Some(&scratch), &["ls-files", "-z", "--others", "--exclude-standard"])
+        })();
+        let _ = fs::remove_file(&scratch);
+        let untracked = untracked.map_err(|e| format!("Failed to restore the files: {e}"))?;
+        for path in untracked.split('\0').filter(|path| !path.is_empty()) {
+            remove(project, path)?;
+        }
+
+        // Ignored files, except the paths that were too large to archive
+        let ignored = git(
+            project,
+            None,
+            &["ls-files", "-z", "--others", "--ignored", "--exclude-standard", "--directory"],
+        )
+        .map_err(|e| format!("Failed to list the ignored files: {e}"))?;
+        let skipped: Vec<&Path> = state.skipped.iter().map(Path::new).collect();
+        for path in ignored.split('\0').filter(|path| !path.is_empty()) {
+            let overlaps =
+                |skipped: &&Path| Path::new(path).starts_with(skipped) || skipped.starts_with(path);
+            if !skipped.iter().any(overlaps) {
+                remove(project, path)?;
+            }
+        }
+        if !state.ignored.is_empty() {
+            self.tar(dir, &["-xpzf"])
+                .map_err(|e| format!("Failed to extract {}: {e}", self.archive(dir).display()))?;
+        }
+
+        // Index
+        git(project, None, &["read-tree", &format!("{}^{{tree}}", state.index)])?;
+        let _ = git(project, None, &["update-index", "-q", "--refresh"]);
+        Ok(())
+    }
+
+    fn restore_archive(&self, dir: &Path) -> Result<(), String> {
+        let archive = self.archive(dir);
+
+        // Read the whole archive before deleting anything
+        self.tar(dir, &["-tzf"])
+            .map_err(|e| format!("The snapshot {} is unreadable: {e}", archive.display()))?;
+        let entries = fs::read_dir(&self.project)
+            .map_err(|e| format!("Failed to read {}: {e}", self.project.display()))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let removed = if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            removed.map_err(|e| format!("Failed to remove {}: {e}", path.display()))?;
+        }
+        self.tar(dir, &["-xpzf"])
+            .map_err(|e| format!("Failed to extract {}: {e}", archive.display()))
+    }
+
+    /// Remove the snapshot: its metadata, tarball and hidden ref.
+    fn remove(&self, dir: &Path) {
+        if self.git.is_some() {
+            let reference = format!("{REF_PREFIX}{}", self.session);
+            let _ = git(&self.project, None, &["update-ref", "-d", &reference]);
+        }
+        let _ = fs::remove_file(self.archive(dir));
+        let _ = fs::remove_file(dir.join(format!("{}.toml", self.session)));
+    }
+}
+
+/// All snapshots in `dir`, newest first
+pub fn list(dir: &Path) -> Vec<Snapshot> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut snapshots: Vec<Snapshot> = entries
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|entry| toml::from_str(&fs::read_to_string(entry.path()).ok()?).ok())
+        .collect();
+    snapshots.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.session.cmp(&a.session)));
+    snapshots
+}
+
+/// Remove all but the most recent [`KEEP_PER_PROJECT`] snapshots of `project`.
+fn prune(dir: &Path, project: &Path) {
+    for old in list(dir)
+        .into_iter()
+        .filter(|snapshot| snapshot.project == project)
+        .skip(KEEP_PER_PROJECT)
+    {
+        old.remove(dir);
+    }
+}
+
+/// Restore a snapshot after asking for confirmation (`rustyolo rollback`).
+///
+/// # Arguments
+///
+/// * `session` - The session to roll back; defaults to the latest one in the current
+///   directory
+/// * `list_only` - Only list the snapshots
+/// * `yes` - Restore without asking
+pub fn run_rollback(session: Option<&str>, list_only: bool, yes: bool) -> Result<(), String> {
+    let dir = default_dir();
+    let snapshots = list(&dir);
+    if list_only {
+        if snapshots.is_empty() {
+            println!("[RustyYOLO] No snapshots in {}", dir.display());
+        }
+        for snapshot in &snapshots {
+            println!("{snapshot}");
+        }
+        return Ok(());
+    }
+
+    let snapshot = if let Some(id) = session {
+        snapshots
+            .iter()
+            .find(|snapshot| snapshot.session == id)
+            .ok_or_else(|| format!("No snapshot for session {id}; see rustyolo rollback --list"))?
+    } else {
+        let project = std::env::current_dir()
+            .and_then(|dir| dir.canonicalize())
+            .map_err(|e| format!("Failed to get the current directory: {e}"))?;
+        snapshots.iter().find(|snapshot| snapshot.project == project).ok_or_else(|| {
+            format!("No snapshot of {}; see rustyolo rollback --list", project.display())
+        })?
+    };
+
+    println!(
+        "[RustyYOLO] Snapshot before session {} ({}) of {}",
+        snapshot.session,
+        age(now().saturating_sub(snapshot.created)),
+        snapshot.project.display()
+    );
+    if !yes {
+        if !io::stdin().is_terminal() {
+            return Err("rustyolo rollback needs a terminal to confirm on (or --yes)".to_string());
+        }
+        print!("Restore it? Everything changed since is lost. [y/N] ");
+        let _ = io::stdout().flush();
+        let mut input = String::new();
+        if io::stdin().lock().read_line(&mut input).unwrap_or(0) == 0
+            || !input.trim().eq_ignore_ascii_case("y")
+        {
+            println!("[RustyYOLO] Nothing restored.");
+            return Ok(());
+        }
+    }
+    snapshot.restore(&dir)?;
+    println!("[RustyYOLO] ✅ Restored {}", snapshot.project.display());
+    Ok(())
+}
+
+/// Remove `path`, a file or directory relative to `project`, and the directories it
+/// leaves empty.
+fn remove(project: &Path, path: &str) -> Result<(), String> {
+    let path = project.join(path);
+    let removed = if path.symlink_metadata().is_ok_and(|metadata| metadata.is_dir()) {
+        fs::remove_dir_all(&path)
+    } else {
+        fs::remove_file(&path)
+    };
+    removed.map_err(|e| format!("Failed to remove {}: {e}", path.display()))?;
+    remove_empty_parents(&path, project);
+    Ok(())
+}
+
+/// The size of the files under `path`, counted until it exceeds `limit`
+fn disk_size(path: &Path, limit: u64) -> u64 {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+        size += disk_size(&entry.path(), limit - size);
+        if size > limit {
+            break;
+        }
+    }
+    size
+}
+
+/// Remove the directories between `path` and `root` that are left empty.
+fn remove_empty_parents(path: &Path, root: &Path) {
+    for parent in path.ancestors().skip(1) {
+        if parent == root || fs::remove_dir(parent).is_err() {
+            break;
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// A rough age, e.g. `5m ago`
+fn age(seconds: u64) -> String {
+    match seconds {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86_400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86_400),
+    }
+}
+
+/// The repository's `core.*`, `include*` and `filter.*` settings, one `name value` line
+/// each
+fn watched_config(project: &Path) -> Vec<String> {
+    // `git config --get-regexp` fails when nothing matches
+    git(
+        project,
+        None,
+        &["config", "--get-regexp", r"^(core|include|includeif|filter)\."],
+    )
+    .map(|output| output.lines().map(String::from).collect())
+    .unwrap_or_default()
+}
+
+/// Run git on the repository at `dir`, optionally on another index file, returning its
+/// trimmed output or its error message. The repository may have been written by the
+/// session, so its git directory and work tree are named explicitly instead of taken from
+/// `core.worktree`, and its fsmonitor and hooks, which git would run, are turned off.
+fn git(dir: &Path, index: Option<&Path>, args: &[&str]) -> Result<String, String> {
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(dir)
+        .arg(format!("--git-dir={}", dir.join(".git").display()))
+        .arg(format!("--work-tree={}", dir.display()))
+        .args(["-c", "core.fsmonitor=false", "-c", "core.hooksPath=/dev/null"])
+        .args(args)
+        .stdin(Stdio::null());
+    if let Some(index) = index {
+        command.env("GIT_INDEX_FILE", index);
+    }
+    // The session can plant replace refs that swap the snapshot's objects for its own
+    command.env("GIT_NO_REPLACE_OBJECTS", "1");
+    // Snapshot commits need an identity, which the user may not have configured
+    for (name, value) in [
+        ("GIT_AUTHOR_NAME", "rustyolo"),
+        ("GIT_AUTHOR_EMAIL", "rustyolo@localhost"),
+        ("GIT_COMMITTER_NAME", "rustyolo"),
+        ("GIT_COMMITTER_EMAIL", "rustyolo@localhost"),
+    ] {
+        command.env(name, value);
+    }
+    let output = command.output().map_err(|e| format!("failed to run git: {e}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!("rustyolo-snapshot-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("project")).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_git_snapshot_rollback() {
+        let root = scratch("git");
+        let project = root.join("project");
+        let run = |args: &[&str]| git(&project, None, args).unwrap();
+        run(&["init", "--quiet", "--initial-branch=main"]);
+        fs::write(project.join(".gitignore"), "target/\n.env\n*.log\n").unwrap();
+        fs::write(project.join("main.rs"), "fn main() {}\n").unwrap();
+        run(&["add", "."]);
+        run(&["commit", "-qm", "init"]);
+        fs::write(project.join("main.rs"), "fn main() { staged(); }\n").unwrap();
+        run(&["add", "main.rs"]);
+        fs::write(project.join("notes.txt"), "untracked\n").unwrap();
+        fs::create_dir_all(project.join("target")).unwrap();
+        fs::write(project.join("target/app"), [0; 200]).unwrap();
+        fs::write(project.join(".env"), "TOKEN=1\n").unwrap();
+        let head = run(&["rev-parse", "HEAD"]);
+
+        let snapshot = Snapshot::create(&project, "7", &root.join("snapshots"), Some(100)).unwrap();
+        let state = snapshot.git.as_ref().unwrap();
+        assert_eq!(
+            (&state.ignored[..], &state.skipped[..]),
+            (&[".env".to_string()][..], &["target/".to_string()][..])
+        );
+        assert!(run(&["status", "--porcelain"]).contains("notes.txt"));
+
+        // The session goes off the rails
+        fs::write(project.join("target/build.log"), "ignored\n").unwrap();
+        fs::write(project.join(".env"), "TOKEN=stolen\n").unwrap();
+        fs::write(project.join("debug.log"), "new\n").unwrap();
+        fs::write(project.join("main.rs"), "broken").unwrap();
+        fs::remove_file(project.join("notes.txt")).unwrap();
+        fs::create_dir_all(project.join("junk/deep")).unwrap();
+        fs::write(project.join("junk/deep/file"), "x").unwrap();
+        run(&["add", "--all"]);
+        run(&["commit", "-qm", "oops"]);
+
+        snapshot.restore(&root.join("snapshots")).unwrap();
+        assert!(!root.join("snapshots/7.index").exists());
+        assert_eq!(run(&["rev-parse", "HEAD"]), head);
+        assert_eq!(run(&["symbolic-ref", "HEAD"]), "refs/heads/main");
+        assert_eq!(
+            fs::read_to_string(project.join("main.rs")).unwrap(),
+            "fn main() { staged(); }\n"
+        );
+        assert_eq!(fs::read_to_string(project.join("notes.txt")).unwrap(), "untracked\n");
+        assert!(!project.join("junk").exists());
+        assert!(project.join("target/build.log").exists());
+        assert_eq!(fs::read_to_string(project.join(".env")).unwrap(), "TOKEN=1\n");
+        assert!(!project.join("debug.log").exists());
+
+        // Without a limit, everything ignored is in the snapshot
+        let all = Snapshot::create(&project, "8", &root.join("snapshots"), None).unwrap();
+        assert_eq!(all.git.as_ref().unwrap().ignored, [".env", "target/"]);
+        fs::remove_dir_all(project.join("target")).unwrap();
+        all.restore(&root.join("snapshots")).unwrap();
+        assert_eq!(fs::read(project.join("target/app")).unwrap(), [0; 200]);
+        assert!(project.join("target/build.log").exists());
+        assert_eq!(run(&["status", "--porcelain"]), "M  main.rs\n?? notes.txt");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_git_rollback_ignores_session_config() {
+        let root = scratch("config");
+        let project = root.join("project");
+        let run = |args: &[&str]| git(&project, None, args).unwrap();
+        run(&["init", "--quiet", "--initial-branch=main"]);
+        fs::write(project.join("main.rs"), "fn main() {}\n").unwrap();
+        run(&["add", "."]);
+        run(&["commit", "-qm", "init"]);
+        let snapshot = Snapshot::create(&project, "7", &root.join("snapshots"), None).unwrap();
+
+        // The session plants commands for git to run on the host during the rollback
+        let marker = root.join("ran");
+        let script = root.join("evil.sh");
+        fs::write(&script, format!("#!/bin/sh\ntouch {}\ncat\n", marker.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let hook = project.join(".git/hooks/reference-transaction");
+        fs::copy(&script, &hook).unwrap();
+        let script = script.to_str().unwrap();
+        run(&["config", "core.fsmonitor", script]);
+        run(&["config", "filter.evil.smudge", script]);
+        fs::write(project.join(".gitattributes"), "* filter=evil\n").unwrap();
+        fs::write(project.join("main.rs"), "broken").unwrap();
+
+        let err = snapshot.restore(&root.join("snapshots")).unwrap_err();
+        assert!(err.contains("filter.evil.smudge"), "{err}");
+        assert!(err.contains("core.fsmonitor"), "{err}");
+        assert_eq!(fs::read_to_string(project.join("main.rs")).unwrap(), "broken");
+
+        run(&["config", "--unset", "filter.evil.smudge"]);
+        run(&["config", "--unset", "core.fsmonitor"]);
+        snapshot.restore(&root.join("snapshots")).unwrap();
+        assert_eq!(fs::read_to_string(project.join("main.rs")).unwrap(), "fn main() {}\n");
+        assert!(!project.join(".gitattributes").exists());
+        assert!(!marker.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_git_rollback_stays_in_project() {
+        let root = scratch("worktree");
+        let project = root.join("project");
+        let elsewhere = root.join("elsewhere");
+        fs::create_dir_all(&elsewhere).unwrap();
+        fs::write(elsewhere.join("keep"), "host file\n").unwrap();
+        let run = |args: &[&str]| git(&project, None, args).unwrap();
+        run(&["init", "--quiet", "--initial-branch=main"]);
+        fs::write(project.join("main.rs"), "fn main() {}\n").unwrap();
+        run(&["add", "."]);
+        run(&["commit", "-qm", "init"]);
+        let snapshot = Snapshot::create(&project, "7", &root.join("snapshots"), None).unwrap();
+
+        // The session points git at another host directory
+        let include = root.join("included");
+        fs::write(&include, format!("[core]\n\tworktree = {}\n", elsewhere.display())).unwrap();
+        run(&["config", "include.path", include.to_str().unwrap()]);
+        fs::write(project.join("main.rs"), "broken").unwrap();
+        let err = snapshot.restore(&root.join("snapshots")).unwrap_err();
+        assert!(err.contains("include.path"), "{err}");
+        run(&["config", "--unset", "include.path"]);
+
+        run(&["config", "core.worktree", elsewhere.to_str().unwrap()]);
+        let err = snapshot.restore(&root.join("snapshots")).unwrap_err();
+        assert!(err.contains("core.worktree"), "{err}");
+        assert_eq!(fs::read_dir(&elsewhere).unwrap().count(), 1);
+
+        // Even if the setting was there all along, the project is what gets restored
+        let snapshot = Snapshot::create(&project, "8", &root.join("snapshots"), None).unwrap();
+        fs::write(project.join("main.rs"), "broken again").unwrap();
+        snapshot.restore(&root.join("snapshots")).unwrap();
+        assert_eq!(fs::read_to_string(project.join("main.rs")).unwrap(), "broken");
+        assert_eq!(fs::read_dir(&elsewhere).unwrap().count(), 1);
+        assert_eq!(fs::read_to_string(elsewhere.join("keep")).unwrap(), "host file\n");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_git_rollback_ignores_replace_refs() {
+        let root = scratch("replace");
+        let project = root.join("project");
+        let run = |args: &[&str]| git(&project, None, args).unwrap();
+        run(&["init", "--quiet", "--initial-branch=main"]);
+        fs::write(project.join("main.rs"), "fn main() {}\n").unwrap();
+        run(&["add", "."]);
+        run(&["commit", "-qm", "init"]);
+        let snapshot = Snapshot::create(&project, "7", &root.join("snapshots"), None).unwrap();
+        let state = snapshot.git.as_ref().unwrap();
+
+        // The session swaps the snapshot's commits for its own
+        fs::write(project.join("main.rs"), "evil").unwrap();
+        run(&["commit", "-qam", "evil"]);
+        let evil = run(&["rev-parse", "HEAD"]);
+        run(&["replace", "-f", &state.commit, &evil]);
+        run(&["replace", "-f", &state.index, &evil]);
+        assert_eq!(run(&["replace", "--list"]).lines().count(), 2);
+
+        snapshot.restore(&root.join("snapshots")).unwrap();
+        assert_eq!(fs::read_to_string(project.join("main.rs")).unwrap(), "fn main() {}\n");
+        assert_eq!(run(&["status", "--porcelain"]), "");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_archive_snapshot_rollback() {
+        let root = scratch("archive");
+        let project = root.join("project");
+        let dir = root.join("snapshots");
+        fs::create_dir_all(project.join("data")).unwrap();
+        fs::write(project.join("data/input.csv"), "a,b\n").unwrap();
+        fs::set_permissions(project.join("data/input.csv"), fs::Permissions::from_mode(0o600))
+            .unwrap();
+
+        let first = Snapshot::create(&project, "7", &dir, None).unwrap();
+        let second = Snapshot::create(&project, "7", &dir, None).unwrap();
+        assert_eq!((first.git.as_ref(), second.session.as_str()), (None, "7-2"));
+        assert_eq!(list(&dir).len(), 2);
+
+        fs::remove_dir_all(project.join("data")).unwrap();
+        fs::write(project.join("data"), "now a file").unwrap();
+        first.restore(&dir).unwrap();
+        assert_eq!(fs::read_to_string(project.join("data/input.csv")).unwrap(), "a,b\n");
+        let mode = fs::metadata(project.join("data/input.csv")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&project).unwrap().count(), 1);
+
+        assert!(Snapshot::create(&root, "8", &dir, None).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
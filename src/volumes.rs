@@ -0,0 +1,474 @@
+//! Parsing and validation of user-supplied volume mounts (`-v` / `volumes`).
+//!
+//! Each spec is parsed into a [`VolumeSpec`]. Host paths are expanded (`~`, `$HOME`),
+//! normalized (`//etc/` is `/etc`) and resolved through symlinks, and the resolved path is
+//! what is passed to Docker, so that what was checked is what gets mounted.
+//!
+//! Host paths are then checked against a deny tree ([`default_rules`] plus the user's
+//! `deny_mounts`). A mount is refused when its host path is a denied path or inside one,
+//! either as written or once resolved (`/proc/1/root` is inside `/proc`, a symlink to the
+//! Docker socket resolves to it), or when it contains a denied path (`$HOME` contains
+//! `~/.aws`). Entries with a `*`, such as `~/.ssh/id_*`, refuse the matching paths and the
+//! directories containing an existing match: `~/.ssh` is refused while it holds a key, but
+//! `~/.ssh/known_hosts` and `~/.ssh/config` can be mounted on their own.
+
+use crate::config::expand_home;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Reason given for the Docker socket rules
+const DOCKER_SOCKET: &str = "the Docker socket lets the agent start containers that bypass \
+                             all restrictions";
+
+/// Reason given for the other container runtime sockets
+const RUNTIME_SOCKET: &str = "a container runtime socket lets the agent start containers \
+                              that bypass all restrictions";
+
+/// File names of container runtime sockets, refused wherever they are (case-insensitive)
+const SOCKET_NAMES: [&str; 4] = ["docker.sock", "containerd.sock", "podman.sock", "crio.sock"];
+
+/// Mount options Docker accepts in a `-v` spec
+const OPTIONS: [&str; 14] = [
+    "ro",
+    "rw",
+    "z",
+    "Z",
+    "shared",
+    "rshared",
+    "slave",
+    "rslave",
+    "private",
+    "rprivate",
+    "nocopy",
+    "consistent",
+    "cached",
+    "delegated",
+];
+
+/// What is mounted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Host {
+    /// A host path, expanded and resolved
+    Path(PathBuf),
+
+    /// A named Docker volume
+    Volume(String),
+
+    /// No source: Docker creates an anonymous volume
+    Anonymous,
+}
+
+/// A parsed `host:container[:options]` volume spec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeSpec {
+    /// The spec as given
+    pub spec: String,
+
+    pub host: Host,
+
+    /// The host path as written, expanded and normalized but not resolved
+    pub written: Option<PathBuf>,
+
+    /// Absolute path inside the container
+    pub container: String,
+
+    /// Mount options, e.g. `ro`
+    pub options: Vec<String>,
+}
+
+impl fmt::Display for VolumeSpec {
+    /// The spec to pass to Docker, with the resolved host path
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Host::Path(path) => write!(f, "{}:", path.display())?,
+            Host::Volume(name) => write!(f, "{name}:")?,
+            Host::Anonymous => {}
+        }
+        write!(f, "{}", self.container)?;
+        if !self.options.is_empty() {
+            write!(f, ":{}", self.options.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl VolumeSpec {
+    /// Parse a spec, expanding and resolving its host path.
+    ///
+    /// # Arguments
+    ///
+    /// * `spec` - `host:container[:options]`, `volume-name:container[:options]` or
+    ///   `container`; the host path may start with `~`, `$HOME` or `.`
+    /// * `cwd` - Directory `.`-relative host paths are relative to
+    pub fn parse(spec: &str, cwd: &Path) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (host, container, options) = match parts.as_slice() {
+            [container] => (None, *container, ""),
+            [host, container] => (Some(*host), *container, ""),
+            [host, container, options] => (Some(*host), *container, *options),
+            _ => return Err(format!("Invalid volume '{spec}': expected host:container[:options]")),
+        };
+        if !container.starts_with('/') {
+            return Err(format!(
+                "Invalid volume '{spec}': the container path '{container}' must be absolute"
+            ));
+        }
+        let options: Vec<String> = options
+            .split(',')
+            .filter(|option| !option.is_empty())
+            .map(|option| {
+                if OPTIONS.contains(&option) {
+                    Ok(option.to_string())
+                } else {
+                    Err(format!("Invalid volume '{spec}': unknown option '{option}'"))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        let (host, written) = match host {
+            None => (Host::Anonymous, None),
+            Some("") => return Err(format!("Invalid volume '{spec}': the host path is empty")),
+            Some(host) if is_path(host) => {
+                let expanded = expand_home(host);
+                if expanded.is_relative() && !host.starts_with('.') {
+                    return Err(format!(
+                        "Invalid volume '{spec}': '{host}' could not be expanded (only ~ and $HOME are)"
+                    ));
+                }
+                let written = normalize(&cwd.join(expanded));
+                (Host::Path(resolve(&written)), Some(written))
+            }
+            Some(name) if is_volume_name(name) => (Host::Volume(name.to_string()), None),
+            Some(host) => {
+                return Err(format!(
+                    "Invalid volume '{spec}': '{host}' is neither a volume name nor a path \
+                     (paths start with /, ~, $HOME or .)"
+                ))
+            }
+        };
+        Ok(Self {
+            spec: spec.to_string(),
+            host,
+            written,
+            container: container.to_string(),
+            options,
+        })
+    }
+}
+
+/// Whether a spec's host part is a path rather than a volume name
+fn is_path(host: &str) -> bool {
+    host.starts_with(['/', '~', '.']) || host.starts_with("$HOME") || host.starts_with("${HOME}")
+}
+
+/// Whether `name` is a valid Docker volume name (`[a-zA-Z0-9][a-zA-Z0-9_.-]+`)
+fn is_volume_name(name: &str) -> bool {
+    name.len() >= 2
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Remove `.`, `..`, repeated and trailing separators without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Resolve symlinks in the longest existing prefix of a normalized absolute path; Docker
+/// creates the rest as directories.
+fn resolve(path: &Path) -> PathBuf {
+    for existing in path.ancestors() {
+        if let Ok(resolved) = existing.canonicalize() {
+            let rest = path.strip_prefix(existing).unwrap_or(Path::new(""));
+            return normalize(&resolved.join(rest));
+        }
+    }
+    path.to_path_buf()
+}
+
+/// An entry of the deny tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenyRule {
+    /// The entry as written, e.g. `~/.ssh/id_*`
+    pub entry: String,
+
+    /// The entry expanded and resolved; `*` in a component matches any characters
+    path: PathBuf,
+
+    pub reason: String,
+}
+
+impl DenyRule {
+    /// Create a rule; `~` and `$HOME` are expanded and symlinks resolved up to the first
+    /// component with a `*`.
+    pub fn new(entry: &str, reason: &str) -> Self {
+        let expanded = normalize(&expand_home(entry));
+        let glob = expanded
+            .components()
+            .position(|c| c.as_os_str().to_string_lossy().contains('*'));
+        let path = match glob {
+            Some(index) => {
+                let prefix: PathBuf = expanded.components().take(index).collect();
+                let rest: PathBuf = expanded.components().skip(index).collect();
+                resolve(&prefix).join(rest)
+            }
+            None => resolve(&expanded),
+        };
+        Self { entry: entry.to_string(), path, reason: reason.to_string() }
+    }
+
+    fn is_glob(&self) -> bool {
+        self.path.to_string_lossy().contains('*')
+    }
+
+    /// Whether `path` is the denied path or inside it
+    fn covers(&self, path: &Path) -> bool {
+        let mut components = path.components();
+        self.path.components().all(|pattern| {
+            components.next().is_some_and(|component| {
+                glob_match(
+                    &pattern.as_os_str().to_string_lossy(),
+                    &component.as_os_str().to_string_lossy(),
+                )
+            })
+        })
+    }
+
+    /// Whether mounting `path` would expose the denied path; for a glob, whether it would
+    /// expose an existing match
+    fn is_inside(&self, path: &Path) -> bool {
+        if self.is_glob() {
+            self.existing_matches().iter().any(|found| found.starts_with(path))
+        } else {
+            self.path.starts_with(path)
+        }
+    }
+
+    /// The existing paths a glob matches, e.g. the keys in `~/.ssh` for `~/.ssh/id_*`
+    fn existing_matches(&self) -> Vec<PathBuf> {
+        let mut found = vec![PathBuf::new()];
+        for component in self.path.components() {
+            let pattern = component.as_os_str().to_string_lossy();
+            if !pattern.contains('*') {
+                found = found.into_iter().map(|path| path.join(component)).collect();
+                continue;
+            }
+            found = found
+                .iter()
+                .filter_map(|dir| fs::read_dir(dir).ok())
+                .flat_map(|entries| entries.flatten().map(|entry| entry.path()))
+                .filter(|path| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    glob_match(&pattern, &name)
+                })
+                .collect();
+        }
+        found.retain(|path| path.symlink_metadata().is_ok());
+        found
+    }
+}
+
+/// Match a path component against a pattern in which `*` matches any characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|i| name.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// The built-in deny tree: system directories, credentials and container runtime sockets.
+///
+/// `/root` is left out when it is the user's own home directory, whose sensitive parts
+/// have rules of their own.
+pub fn default_rules() -> Vec<DenyRule> {
+    let mut rules: Vec<DenyRule> = [
+        ("/etc", "system configuration"),
+        ("/proc", "process information and the host filesystem through /proc/<pid>/root"),
+        ("/sys", "kernel and device configuration"),
+        ("/dev", "devices"),
+        ("/boot", "boot files"),
+        ("/root", "the root user's home directory"),
+        ("~/.aws", "AWS credentials"),
+        ("~/.ssh/id_*", "SSH private keys"),
+        ("/var/run/docker.sock", DOCKER_SOCKET),
+        ("/run/docker.sock", DOCKER_SOCKET),
+        ("~/.docker/run/docker.sock", DOCKER_SOCKET),
+        ("/run/containerd/containerd.sock", RUNTIME_SOCKET),
+        ("/run/podman/podman.sock", RUNTIME_SOCKET),
+        ("/var/run/crio/crio.sock", RUNTIME_SOCKET),
+    ]
+    .into_iter()
+    .map(|(entry, reason)| DenyRule::new(entry, reason))
+    .collect();
+
+    // Rootless Docker and Podman keep their sockets in the user's runtime directory
+    if let Some(runtime) = dirs::runtime_dir() {
+        for (socket, reason) in
+            [("docker.sock", DOCKER_SOCKET), ("podman/podman.sock", RUNTIME_SOCKET)]
+        {
+            rules.push(DenyRule::new(&runtime.join(socket).to_string_lossy(), reason));
+        }
+    }
+
+    if let Some(home) = dirs::home_dir().and_then(|home| home.canonicalize().ok()) {
+        rules.retain(|rule| rule.is_glob() || !home.starts_with(&rule.path));
+    }
+    rules
+}
+
+/// Check a parsed volume against the deny tree.
+///
+/// # Returns
+///
+/// Why the volume may not be mounted, naming the rule that matched
+pub fn check(volume: &VolumeSpec, rules: &[DenyRule]) -> Result<(), String> {
+    let (Host::Path(resolved), Some(written)) = (&volume.host, &volume.written) else {
+        return Ok(());
+    };
+    let shown = |path: &Path| {
+        if path == written {
+            path.display().to_string()
+        } else {
+            format!("{} (which resolves to {})", written.display(), path.display())
+        }
+    };
+    let refuse = |detail: String, hint: &str| {
+        Err(format!("Mounting {detail}.\nAttempted mount: {}{hint}", volume.spec))
+    };
+
+    for path in [written, resolved] {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+        if SOCKET_NAMES.contains(&name.as_str()) {
+            let reason = if name == "docker.sock" {
+                DOCKER_SOCKET
+            } else {
+                RUNTIME_SOCKET
+            };
+            return refuse(format!("{} is forbidden (security risk: {reason})", shown(path)), "");
+        }
+        for rule in rules {
+            if rule.covers(path) {
+                return refuse(
+                    format!(
+                        "{} is forbidden: it is covered by the denied path {} (security risk: {})",
+                        shown(path),
+                        rule.entry,
+                        rule.reason
+                    ),
+                    "",
+                );
+            }
+        }
+    }
+    for rule in rules {
+        if rule.is_inside(resolved) || rule.is_inside(written) {
+            return refuse(
+                format!(
+                    "{} is forbidden: it contains the denied path {} (security risk: {})",
+                    written.display(),
+                    rule.entry,
+                    rule.reason
+                ),
+                "\nIf you need specific files, mount them individually rather than the entire directory.",
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> Result<VolumeSpec, String> {
+        VolumeSpec::parse(spec, Path::new("/work/project"))
+    }
+
+    #[test]
+    fn test_parse_volume_specs() {
+        let home = dirs::home_dir().unwrap().canonicalize().unwrap();
+        let volume = parse("~/.gitconfig:/home/agent/.gitconfig:ro").unwrap();
+        assert_eq!(volume.written, Some(home.join(".gitconfig")));
+        assert_eq!(volume.options, vec!["ro"]);
+        assert_eq!(parse("$HOME:/h").unwrap().written, Some(home.clone()));
+        assert_eq!(parse("${HOME}/data:/d").unwrap().written, Some(home.join("data")));
+
+        let volume = parse("./data/../cache:/cache:ro,z").unwrap();
+        assert_eq!(volume.host, Host::Path(PathBuf::from("/work/project/cache")));
+        assert_eq!(volume.to_string(), "/work/project/cache:/cache:ro,z");
+        assert_eq!(parse("//srv/data/:/data").unwrap().to_string(), "/srv/data:/data");
+
+        assert_eq!(parse("cargo-cache:/cache").unwrap().host, Host::Volume("cargo-cache".into()));
+        assert_eq!(parse("/scratch").unwrap().host, Host::Anonymous);
+
+        assert!(parse("/a:/b:ro:extra").unwrap_err().contains("host:container"));
+        assert!(parse("/a:relative").unwrap_err().contains("must be absolute"));
+        assert!(parse("/a:/b:rw,exec").unwrap_err().contains("unknown option 'exec'"));
+        assert!(parse("data/dir:/b").unwrap_err().contains("neither a volume name nor a path"));
+    }
+
+    #[test]
+    fn test_deny_tree() {
+        let rules = default_rules();
+        let denied = |spec: &str| check(&parse(spec).unwrap(), &rules).unwrap_err();
+
+        assert!(denied("/etc/shadow:/x").contains("denied path /etc"));
+        assert!(denied("//etc:/x").contains("denied path /etc"));
+        assert!(denied("/etc/:/x").contains("denied path /etc"));
+        assert!(denied("/proc/1/root:/x").contains("denied path /proc"));
+        assert!(denied("~/.aws/credentials:/x").contains("denied path ~/.aws"));
+        assert!(denied("~/.ssh/id_ed25519:/x").contains("denied path ~/.ssh/id_*"));
+        assert!(denied("$HOME:/x").contains("contains the denied path ~/.aws"));
+        assert!(denied("/:/host").contains("contains the denied path"));
+        assert!(denied("/srv/podman.sock:/x").contains("container runtime socket"));
+        assert!(check(&parse("~/.ssh/known_hosts:/x").unwrap(), &rules).is_ok());
+
+        // A symlink to a denied path is checked where it leads
+        let dir =
+            std::env::temp_dir().join(format!("rustyolo-volumes-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("innocent");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink("/etc", &link).unwrap();
+        let error = denied(&format!("{}:/x", link.display()));
+        assert!(error.contains("resolves to") && error.contains("denied path /etc"));
+
+        // Custom entries
+        let custom =
+            [DenyRule::new(&dir.join("secrets").to_string_lossy(), "listed in deny_mounts")];
+        let volume = parse(&format!("{}/secrets/key:/x", dir.display())).unwrap();
+        assert!(check(&volume, &custom).unwrap_err().contains("listed in deny_mounts"));
+
+        // A directory is refused once it holds a match of a glob, like ~/.ssh with a key
+        let ssh = dir.join(".ssh");
+        std::fs::create_dir_all(&ssh).unwrap();
+        std::fs::write(ssh.join("known_hosts"), "").unwrap();
+        let keys = [DenyRule::new(&ssh.join("id_*").to_string_lossy(), "SSH private keys")];
+        let mount = parse(&format!("{}:/home/agent/.ssh:ro", ssh.display())).unwrap();
+        assert!(check(&mount, &keys).is_ok());
+        std::fs::write(ssh.join("id_ed25519"), "").unwrap();
+        let error = check(&mount, &keys).unwrap_err();
+        assert!(error.contains("contains the denied path") && error.contains("id_*"));
+        assert!(check(&parse(&format!("{}:/x", dir.display())).unwrap(), &keys).is_err());
+        let known_hosts = parse(&format!("{}/known_hosts:/x", ssh.display())).unwrap();
+        assert!(check(&known_hosts, &keys).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
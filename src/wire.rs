@@ -0,0 +1,46 @@
+/// Minimal bounds-checked cursor over a byte slice, for parsing network protocol messages.
+///
+/// Every accessor returns `None` instead of panicking when the input is too short, so
+/// parsers can bail out of malformed messages with `?`.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Current offset from the start of the buffer
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Move to an absolute offset (e.g., to follow a DNS compression pointer)
+    pub fn seek(&mut self, pos: usize) -> Option<()> {
+        if pos > self.buf.len() {
+            return None;
+        }
+        self.pos = pos;
+        Some(())
+    }
+
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    pub fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
@@ -0,0 +1,451 @@
+//! Copy-on-write project workspace (`--workspace overlay`).
+//!
+//! By default the project directory is bind-mounted read-write at `/app`, so every edit
+//! the agent makes lands in the real checkout immediately. In overlay mode the agent works
+//! on a scratch copy instead: the project is copied into a private temporary directory
+//! before the container starts, and the copy is mounted at `/app`. When the container
+//! exits, the copy is compared with the project as it was when it was copied, and the
+//! user picks which changes to apply to the real project; the rest are discarded.
+//!
+//! Changes under `.git`, or a nested repository's `.git`, are never applied: the copy
+//! includes the repository so that git works in the sandbox, but commits made there stay
+//! in the copy, and git on the host would obey a `.git` planted in a subdirectory. A change
+//! is not applied either if the project file was changed outside the sandbox during the
+//! session.
+
+use crate::learn::parse_selection;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// How the project directory is made available to the agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Bind-mount the project read-write
+    Bind,
+
+    /// Mount a scratch copy and review its changes afterwards
+    Overlay,
+}
+
+impl Mode {
+    /// Parse a workspace mode from its configuration string (case-insensitive).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "bind" => Some(Self::Bind),
+            "overlay" => Some(Self::Overlay),
+            _ => None,
+        }
+    }
+}
+
+/// What a path held when it was last looked at
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    /// A regular file, by content hash and permission bits
+    File { hash: u64, mode: u32 },
+
+    /// A symbolic link and its target
+    Symlink(PathBuf),
+}
+
+/// How a file was changed in the copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// A change the agent made to the copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Path relative to the project directory
+    pub path: PathBuf,
+
+    pub kind: Kind,
+
+    /// Lines added and removed, for text files
+    pub lines: Option<(usize, usize)>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let letter = match self.kind {
+            Kind::Added => 'A',
+            Kind::Modified => 'M',
+            Kind::Deleted => 'D',
+        };
+        write!(f, "{letter} {}", self.path.display())?;
+        match self.lines {
+            Some((added, removed)) => write!(f, " (+{added} -{removed})"),
+            None if self.kind == Kind::Deleted => Ok(()),
+            None => write!(f, " (binary or symlink)"),
+        }
+    }
+}
+
+/// A scratch copy of the project, removed when dropped unless it is kept for later review
+pub struct Overlay {
+    /// The real project directory
+    project: PathBuf,
+
+    /// Private temporary directory holding the copy
+    dir: PathBuf,
+
+    /// The project's files when the copy was made, by relative path
+    baseline: BTreeMap<PathBuf, Entry>,
+
+    /// Keep the copy when dropped (changes were not reviewed)
+    keep: bool,
+}
+
+impl Overlay {
+    /// Copy the project into a private temporary directory.
+    ///
+    /// # Returns
+    ///
+    /// The overlay and the number of files copied
+    pub fn create(project: &Path) -> Result<(Self, usize), String> {
+        let dir = std::env::temp_dir().join(format!("rustyolo-workspace-{}", std::process::id()));
+        if dir.starts_with(project) {
+            return Err(format!(
+                "The workspace copy cannot be made inside the project ({})",
+                dir.display()
+            ));
+        }
+        fs::create_dir(&dir)
+            .and_then(|()| fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)))
+            .map_err(|e| format!("Failed to create the workspace copy {}: {e}", dir.display()))?;
+
+        // From here on, dropping the overlay removes the copy again
+        let mut overlay =
+            Self { project: project.to_path_buf(), dir, baseline: BTreeMap::new(), keep: false };
+        let work = overlay.work_dir();
+        copy_tree(project, &work, Path::new(""), &mut overlay.baseline).map_err(|e| {
+            format!("Failed to copy {} to {}: {e}", project.display(), work.display())
+        })?;
+        let count = overlay.baseline.len();
+        Ok((overlay, count))
+    }
+
+    /// The copy, to mount at `/app`
+    pub fn work_dir(&self) -> PathBuf {
+        self.dir.join("work")
+    }
+
+    /// The changes made to the copy, outside any `.git`, sorted by path.
+    pub fn changes(&self) -> io::Result<Vec<Change>> {
+        let mut current = BTreeMap::new();
+        scan_tree(&self.work_dir(), Path::new(""), &mut current)?;
+
+        let mut changes = Vec::new();
+        for (path, entry) in &current {
+            let kind = match self.baseline.get(path) {
+                None => Kind::Added,
+                Some(baseline) if baseline != entry => Kind::Modified,
+                Some(_) => continue,
+            };
+            changes.push(Change { lines: self.line_counts(path, kind), path: path.clone(), kind });
+        }
+        for path in self.baseline.keys().filter(|path| !current.contains_key(*path)) {
+            changes.push(Change { path: path.clone(), kind: Kind::Deleted, lines: None });
+        }
+        // Git obeys a nested repository's `.git` too, such as a submodule's
+        changes.retain(|change| !change.path.components().any(|part| part.as_os_str() == ".git"));
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+
+    /// Lines added and removed between the project's file and the copy's, for text files
+    fn line_counts(&self, path: &Path, kind: Kind) -> Option<(usize, usize)> {
+        let read = |root: &Path| -> Option<String> {
+            let path = root.join(path);
+            if fs::symlink_metadata(&path).ok()?.file_type().is_symlink() {
+                return None;
+            }
+            String::from_utf8(fs::read(path).ok()?).ok()
+        };
+        let new = read(&self.work_dir())?;
+        let old = match kind {
+            Kind::Added => String::new(),
+            _ => read(&self.project)?,
+        };
+        Some(line_diff(&old, &new))
+    }
+
+    /// Apply one change to the project.
+    ///
+    /// Refuses changes to files that were changed outside the sandbox since the copy was
+    /// made, and paths that lead through a symbolic link in the project.
+    pub fn apply(&self, change: &Change) -> Result<(), String> {
+        let target = self.project.join(&change.path);
+        if leads_through_symlink(&self.project, &change.path) {
+            return Err(format!("{} leads through a symbolic link", change.path.display()));
+        }
+        let now = entry(&target).map_err(|e| format!("{}: {e}", change.path.display()))?;
+        if now.as_ref() != self.baseline.get(&change.path) {
+            return Err(format!("{} was changed outside the sandbox", change.path.display()));
+        }
+
+        let source = self.work_dir().join(&change.path);
+        let apply = || -> io::Result<()> {
+            if now.is_some() {
+                fs::remove_file(&target)?;
+            }
+            if change.kind != Kind::Deleted {
+                fs::create_dir_all(target.parent().unwrap_or(&self.project))?;
+                copy_entry(&source, &target)?;
+            }
+            Ok(())
+        };
+        apply().map_err(|e| format!("Failed to apply {}: {e}", change.path.display()))
+    }
+
+    /// Show what the agent changed and let the user apply all, some or none of it.
+    ///
+    /// Without a terminal to ask on, nothing is applied and the copy is kept.
+    pub fn finish(mut self) {
+        let changes = match self.changes() {
+            Ok(changes) => changes,
+            Err(e) => {
+                self.keep = true;
+                eprintln!(
+                    "[RustyYOLO] ❌ Failed to compare the workspace copy: {e}. It was kept at {}",
+                    self.work_dir().display()
+                );
+                return;
+            }
+        };
+        if changes.is_empty() {
+            println!("[RustyYOLO] Workspace: the agent made no changes.");
+            return;
+        }
+
+        println!("[RustyYOLO] Workspace: the agent changed {} file(s):", changes.len());
+        for (i, change) in changes.iter().enumerate() {
+            println!("  {:>2}. {change}", i + 1);
+        }
+        if !io::stdin().is_terminal() {
+            self.keep = true;
+            println!(
+                "[RustyYOLO] Nothing was applied. The changed copy is kept at {}",
+                self.work_dir().display()
+            );
+            return;
+        }
+
+        let selected = loop {
+            print!(
+                "Apply to {}? \"all\", numbers (e.g. \"1 3\") or Enter to discard: ",
+                self.project.display()
+            );
+            let _ = io::stdout().flush();
+            let mut input = String::new();
+            if io::stdin().lock().read_line(&mut input).unwrap_or(0) == 0 {
+                break Vec::new();
+            }
+            match parse_selection(&input, changes.len()) {
+                Ok(selected) => break selected,
+                Err(e) => println!("[RustyYOLO] {e}"),
+            }
+        };
+
+        let mut applied = 0;
+        for change in selected.into_iter().map(|i| &changes[i]) {
+            match self.apply(change) {
+                Ok(()) => applied += 1,
+                Err(e) => {
+                    self.keep = true;
+                    eprintln!("[RustyYOLO] ⚠️  Not applied: {e}");
+                }
+            }
+        }
+        println!(
+            "[RustyYOLO] ✅ Applied {applied} of {} change(s); the rest were discarded.",
+            changes.len()
+        );
+        if self.keep {
+            println!(
+                "[RustyYOLO] The copy is kept at {} for the changes that could not be applied",
+                self.work_dir().display()
+            );
+        }
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Count the lines added and removed between two texts, ignoring moves.
+fn line_diff(old: &str, new: &str) -> (usize, usize) {
+    let mut counts: BTreeMap<&str, isize> = BTreeMap::new();
+    for line in old.lines() {
+        *counts.entry(line).or_default() -= 1;
+    }
+    for line in new.lines() {
+        *counts.entry(line).or_default() += 1;
+    }
+    counts.values().fold((0, 0), |(added, removed), &count| {
+        (added + count.max(0).unsigned_abs(), removed + count.min(0).unsigned_abs())
+    })
+}
+
+/// What `path` holds now, or `None` if nothing (or a directory or special file)
+fn entry(path: &Path) -> io::Result<Option<Entry>> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if metadata.file_type().is_symlink() {
+        return Ok(Some(Entry::Symlink(fs::read_link(path)?)));
+    }
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&fs::read(path)?);
+    Ok(Some(Entry::File {
+        hash: hasher.finish(),
+        mode: metadata.permissions().mode() & 0o777,
+    }))
+}
+
+/// Whether a directory between `root` and `relative` is a symbolic link
+fn leads_through_symlink(root: &Path, relative: &Path) -> bool {
+    relative
+        .ancestors()
+        .skip(1)
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|ancestor| {
+            fs::symlink_metadata(root.join(ancestor)).is_ok_and(|m| m.file_type().is_symlink())
+        })
+}
+
+/// Copy a file or symbolic link, keeping the file's permissions.
+fn copy_entry(source: &Path, target: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(source)?.file_type().is_symlink() {
+        symlink(fs::read_link(source)?, target)
+    } else {
+        fs::copy(source, target).map(|_| ())
+    }
+}
+
+/// Copy the tree under `from` to `to`, recording each file and symlink in `entries`.
+fn copy_tree(
+    from: &Path,
+    to: &Path,
+    relative: &Path,
+    entries: &mut BTreeMap<PathBuf, Entry>,
+) -> io::Result<()> {
+    fs::create_dir(to)?;
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+    for item in fs::read_dir(from)? {
+        let item = item?;
+        let (source, target) = (item.path(), to.join(item.file_name()));
+        let path = relative.join(item.file_name());
+        if item.file_type()?.is_dir() {
+            copy_tree(&source, &target, &path, entries)?;
+        } else if let Some(entry) = entry(&source)? {
+            copy_entry(&source, &target)?;
+            entries.insert(path, entry);
+        }
+    }
+    Ok(())
+}
+
+/// Record each file and symlink under `dir` in `entries`.
+fn scan_tree(
+    dir: &Path,
+    relative: &Path,
+    entries: &mut BTreeMap<PathBuf, Entry>,
+) -> io::Result<()> {
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        let path = relative.join(item.file_name());
+        if item.file_type()?.is_dir() {
+            scan_tree(&item.path(), &path, entries)?;
+        } else if let Some(entry) = entry(&item.path())? {
+            entries.insert(path, entry);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(line_diff("a\nb\nc\n", "a\nB\nc\nd\n"), (2, 1));
+        assert_eq!(line_diff("", "a\nb\n"), (2, 0));
+        assert_eq!(line_diff("a\nb\n", "b\na\n"), (0, 0));
+    }
+
+    #[test]
+    fn test_overlay_changes_and_apply() {
+        let project =
+            std::env::temp_dir().join(format!("rustyolo-workspace-test-{}", std::process::id()));
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(project.join(".git")).unwrap();
+        fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(project.join("README.md"), "# Demo\n").unwrap();
+        fs::write(project.join("notes.txt"), "keep\n").unwrap();
+        fs::write(project.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+
+        let (overlay, count) = Overlay::create(&project).unwrap();
+        assert_eq!(count, 4);
+        let work = overlay.work_dir();
+        fs::write(work.join("src/main.rs"), "fn main() {\n    run();\n}\n").unwrap();
+        fs::write(work.join("src/lib.rs"), "pub fn run() {}\n").unwrap();
+        fs::remove_file(work.join("README.md")).unwrap();
+        fs::write(work.join("notes.txt"), "changed by the agent\n").unwrap();
+        fs::write(work.join(".git/HEAD"), "ref: refs/heads/agent\n").unwrap();
+        fs::create_dir_all(work.join("sub/.git")).unwrap();
+        fs::write(work.join("sub/.git/config"), "[core]\n\tfsmonitor = evil.sh\n").unwrap();
+        fs::create_dir_all(work.join("vendor/lib")).unwrap();
+        fs::write(work.join("vendor/lib/.git"), "gitdir: ../../.git/modules/lib\n").unwrap();
+        // Edited outside the sandbox in the meantime
+        fs::write(project.join("notes.txt"), "changed by the user\n").unwrap();
+
+        let changes = overlay.changes().unwrap();
+        let listed: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            listed,
+            [
+                "D README.md",
+                "M notes.txt (+1 -1)",
+                "A src/lib.rs (+1 -0)",
+                "M src/main.rs (+3 -1)"
+            ]
+        );
+
+        for change in &changes {
+            let result = overlay.apply(change);
+            assert_eq!(result.is_err(), change.path == Path::new("notes.txt"), "{change}");
+        }
+        assert!(!project.join("README.md").exists());
+        assert_eq!(fs::read_to_string(project.join("src/lib.rs")).unwrap(), "pub fn run() {}\n");
+        assert_eq!(fs::read_to_string(project.join("notes.txt")).unwrap(), "changed by the user\n");
+        assert_eq!(
+            fs::read_to_string(project.join(".git/HEAD")).unwrap(),
+            "ref: refs/heads/main\n"
+        );
+
+        let dir = overlay.dir.clone();
+        drop(overlay);
+        assert!(!dir.exists());
+        fs::remove_dir_all(&project).unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+//! A git worktree and branch for each session (`--worktree`).
+//!
+//! With the project bind-mounted, two agents working on the same repository edit the same
+//! files. With `--worktree`, each session gets a fresh `git worktree` on a new branch,
+//! `rustyolo/<session>`, created from the current `HEAD` and mounted at `/app` instead.
+//!
+//! A worktree's `.git` is a file pointing into the main repository's `.git` directory
+//! by absolute path, so that directory is mounted into the container at the same path.
+//! Its `config`, `hooks` and `modules` (the submodules' repositories) are mounted read-only
+//! on top: they can make git run commands, and git runs them on the host later.
+//!
+//! Everything else in it is writable by the agent, which needs to create objects and
+//! update its branch. That includes the refs of the other branches: the agent can move or
+//! delete any branch of the repository, not only the session branch. Run untrusted agents
+//! on a clone if that matters.
+//!
+//! The agent can also rewrite the worktree's `.git` file and the worktree's metadata in
+//! the repository, so the host never discovers the repository from the worktree: its git
+//! commands name the worktree's git directory and the common directory explicitly and
+//! turn off `core.fsmonitor` and hooks.
+//!
+//! When the session ends, the branch and what happened on it are reported, and the user
+//! may remove the worktree (the branch is kept). A session that changed nothing leaves
+//! nothing behind.
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Prefix of session branches
+pub const BRANCH_PREFIX: &str = "rustyolo/";
+
+/// A session's worktree
+pub struct Worktree {
+    /// The worktree directory, mounted at `/app`
+    path: PathBuf,
+
+    /// The session branch checked out in it
+    branch: String,
+
+    /// The commit the branch was created from
+    base: String,
+
+    /// The main repository's `.git` directory (absolute)
+    common_dir: PathBuf,
+
+    /// The worktree's own git directory, `<common_dir>/worktrees/<id>`
+    git_dir: PathBuf,
+}
+
+impl Worktree {
+    /// Create a worktree for `session` on a new branch from the `HEAD` of the repository
+    /// containing `project`.
+    ///
+    /// # Arguments
+    ///
+    /// * `project` - A directory inside the repository
+    /// * `session` - The session ID the branch is named after; `-2`, `-3`, ... is appended
+    ///   if the branch already exists
+    /// * `parent` - Directory to create the worktree in
+    pub fn create(project: &Path, session: &str, parent: &Path) -> Result<Self, String> {
+        let toplevel = git(project, &["rev-parse", "--show-toplevel"])
+            .map_err(|e| format!("--worktree needs a git repository: {e}"))?;
+        let toplevel = PathBuf::from(toplevel);
+        let common_dir = toplevel.join(git(&toplevel, &["rev-parse", "--git-common-dir"])?);
+        let common_dir = common_dir.canonicalize().map_err(|e| {
+            format!("Failed to resolve the git directory {}: {e}", common_dir.display())
+        })?;
+        let base = git(&toplevel, &["rev-parse", "--verify", "HEAD"])
+            .map_err(|e| format!("--worktree needs a commit to branch from: {e}"))?;
+
+        let mut suffix = 1;
+        let (branch, path) = loop {
+            let id = if suffix == 1 {
+                session.to_string()
+            } else {
+                format!("{session}-{suffix}")
+            };
+            let branch = format!("{BRANCH_PREFIX}{id}");
+            let name = toplevel.file_name().unwrap_or_default().to_string_lossy();
+            let path = parent.join(format!("{name}-{id}"));
+            let exists = git(&toplevel, &["rev-parse", "--verify", "--quiet", &branch]).is_ok();
+            if !exists && !path.exists() {
+                break (branch, path);
+            }
+            suffix += 1;
+        };
+
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+        let path_arg = path.to_string_lossy();
+        git(&toplevel, &["worktree", "add", "--quiet", "-b", &branch, &path_arg, &base])
+            .map_err(|e| format!("Failed to create the worktree: {e}"))?;
+        // Recorded now, while the agent has not had a chance to rewrite the `.git` file
+        let git_dir = PathBuf::from(git(&path, &["rev-parse", "--absolute-git-dir"])?);
+        Ok(Self { path, branch, base, common_dir, git_dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Docker `-v` arguments that make the worktree's repository usable in the container
+    pub fn mounts(&self) -> Vec<String> {
+        let common = self.common_dir.display();
+        let mut mounts = vec![format!("{common}:{common}")];
+        for protected in ["config", "hooks", "modules"] {
+            if self.common_dir.join(protected).exists() {
+                mounts.push(format!("{common}/{protected}:{common}/{protected}:ro"));
+            }
+        }
+        mounts
+    }
+
+    /// Report the session branch and offer to remove the worktree.
+    ///
+    /// A worktree without commits or changes is removed along with its branch. Without a
+    /// terminal to ask on, the worktree is kept.
+    pub fn finish(self) {
+        let (commits, dirty) = self.status();
+
+        if commits == 0 && !dirty {
+            println!(
+                "[RustyYOLO] Worktree: no commits or changes on {}; removing it.",
+                self.branch
+            );
+            if let Err(e) = self.remove_with_branch(false) {
+                eprintln!("[RustyYOLO] ⚠️  {e}");
+            }
+            return;
+        }
+
+        let changes = if dirty {
+            ", with uncommitted changes"
+        } else {
+            ""
+        };
+        println!(
+            "[RustyYOLO] Worktree: branch {} has {commits} new commit(s){changes}",
+            self.branch
+        );
+        println!("[RustyYOLO]   {}", self.path.display());
+        if !io::stdin().is_terminal() {
+            return;
+        }
+
+        let warning = if dirty {
+            " (uncommitted changes are lost)"
+        } else {
+            ""
+        };
+        print!("Remove the worktree? The branch is kept{warning}. [y/N] ");
+        let _ = io::stdout().flush();
+        let mut input = String::new();
+        if io::stdin().lock().read_line(&mut input).unwrap_or(0) == 0
+            || !input.trim().eq_ignore_ascii_case("y")
+        {
+            return;
+        }
+        match self.remove(dirty) {
+            Ok(()) => println!("[RustyYOLO] ✅ Worktree removed; branch {} kept.", self.branch),
+            Err(e) => eprintln!("[RustyYOLO] ❌ {e}"),
+        }
+    }
+
+    /// Remove the worktree and its branch, for a session that never started.
+    pub fn discard(self) {
+        if let Err(e) = self.remove_with_branch(true) {
+            eprintln!("[RustyYOLO] ⚠️  {e}");
+        }
+    }
+
+    fn remove_with_branch(&self, force: bool) -> Result<(), String> {
+        // The worktree's own git directory is gone with it
+        self.remove(force)?;
+        self.git_in(&self.common_dir, &["branch", "--quiet", "-D", &self.branch])
+            .map(|_| ())
+    }
+
+    /// The number of commits on the branch and whether there are uncommitted changes
+    fn status(&self) -> (usize, bool) {
+        let commits = self
+            .git(&["rev-list", "--count", &format!("{}..{}", self.base, self.branch)])
+            .ok()
+            .and_then(|count| count.parse::<usize>().ok())
+            .unwrap_or_default();
+        let dirty = self.git(&["status", "--porcelain"]).map_or(true, |s| !s.is_empty());
+        (commits, dirty)
+    }
+
+    fn remove(&self, force: bool) -> Result<(), String> {
+        let path = self.path.to_string_lossy();
+        let mut args = vec!["worktree", "remove"];
+        if force {
+            args.push("--force");
+        }
+        args.push(&path);
+        self.git(&args)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to remove the worktree {path}: {e}"))
+    }
+
+    /// Run git on the worktree without letting anything the agent wrote choose the
+    /// repository or run commands.
+    fn git(&self, args: &[&str]) -> Result<String, String> {
+        let work_tree = format!("--work-tree={}", self.path.display());
+        self.git_in(&self.git_dir, &[&[work_tree.as_str()], args].concat())
+    }
+
+    /// Run git with `git_dir` as its git directory, the repository's `.git` directory as
+    /// its common directory, and `core.fsmonitor` and hooks turned off.
+    fn git_in(&self, git_dir: &Path, args: &[&str]) -> Result<String, String> {
+        run(Command::new("git")
+            .env("GIT_COMMON_DIR", &self.common_dir)
+            .arg(format!("--git-dir={}", git_dir.display()))
+            .args(["-c", "core.fsmonitor=false", "-c", "core.hooksPath=/dev/null"])
+            .args(args))
+    }
+}
+
+/// Run git in `dir`, returning its trimmed output or its error message.
+fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    run(Command::new("git").arg("-C").arg(dir).args(args))
+}
+
+/// Run a git command, returning its trimmed output or its error message.
+///
+/// Replace refs are ignored: the session can plant them to swap objects for its own.
+fn run(command: &mut Command) -> Result<String, String> {
+    let output = command
+        .env("GIT_NO_REPLACE_OBJECTS", "1")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run git: {e}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_worktree_lifecycle() {
+        let root =
+            std::env::temp_dir().join(format!("rustyolo-worktree-test-{}", std::process::id()));
+        let repo = root.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        let run = |args: &[&str]| git(&repo, args).unwrap();
+        run(&["init", "--quiet"]);
+        fs::write(repo.join("README.md"), "# Demo\n").unwrap();
+        run(&["add", "README.md"]);
+        run(&["-c", "user.name=t", "-c", "user.email=t@example.com", "commit", "-qm", "init"]);
+
+        let first = Worktree::create(&repo, "42", &root.join("worktrees")).unwrap();
+        let second = Worktree::create(&repo, "42", &root.join("worktrees")).unwrap();
+        assert_eq!(first.branch(), "rustyolo/42");
+        assert_eq!(second.branch(), "rustyolo/42-2");
+        assert!(first.path().join("README.md").exists());
+        let common = repo.join(".git").canonicalize().unwrap();
+        assert_eq!(first.mounts()[0], format!("{0}:{0}", common.display()));
+        assert!(first.mounts().contains(&format!("{0}/config:{0}/config:ro", common.display())));
+
+        // An unused worktree is removed along with its branch
+        let path = first.path().to_path_buf();
+        first.finish();
+        assert!(!path.exists());
+        assert!(run(&["branch", "--list", "rustyolo/42"]).is_empty());
+
+        fs::write(second.path().join("notes.txt"), "work in progress\n").unwrap();
+        assert_eq!(second.status(), (0, true));
+
+        // A `.git` file pointing at a repository the agent made does not run its fsmonitor
+        let evil = second.path().join("evil");
+        git(second.path(), &["init", "--quiet", "--bare", &evil.to_string_lossy()]).unwrap();
+        let marker = root.join("fsmonitor-ran");
+        git(&evil, &["config", "core.bare", "false"]).unwrap();
+        git(&evil, &["config", "core.fsmonitor", &format!("touch {}", marker.display())]).unwrap();
+        fs::write(second.path().join(".git"), format!("gitdir: {}\n", evil.display())).unwrap();
+        assert_eq!(second.status(), (0, true));
+        assert!(!marker.exists());
+        assert!(second.remove(true).unwrap_err().contains("does not point back"));
+        fs::write(second.path().join(".git"), format!("gitdir: {}\n", second.git_dir.display()))
+            .unwrap();
+        second.remove(true).unwrap();
+        assert!(!second.path().exists());
+
+        // The worktree of a session that never started is removed with its branch
+        let unused = Worktree::create(&repo, "43", &root.join("worktrees")).unwrap();
+        let path = unused.path().to_path_buf();
+        unused.discard();
+        assert!(!path.exists());
+        assert!(run(&["branch", "--list", "rustyolo/43"]).is_empty());
+
+        assert!(Worktree::create(&root, "1", &root.join("worktrees")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}